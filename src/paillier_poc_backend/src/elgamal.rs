@@ -0,0 +1,223 @@
+//! Exponential ElGamal over secp256k1: Enc(m) = (r*G, m*G + r*Y) with Y = x*G.
+//!
+//! Additively homomorphic like Paillier, with 66-byte ciphertexts and cheaper
+//! operations. The price is decryption: it yields m*G, so recovering m takes a
+//! discrete log and only small plaintexts (scores, counts) below 2^DLOG_BITS
+//! in magnitude can be decrypted; a negative m comes back as order - |m|, the
+//! residue `SignedEncoding` decodes as negative. Zero tests need no discrete
+//! log, so comparisons work for full 256-bit tokens.
+
+use k256::elliptic_curve::group::GroupEncoding;
+use k256::elliptic_curve::{Field, PrimeField};
+use k256::{AffinePoint, FieldBytes, ProjectivePoint, Scalar};
+use num_bigint::BigUint;
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::simple_paillier::MIN_SEED_BYTES;
+
+/// Largest decryptable |plaintext| is 2^DLOG_BITS - 1
+pub const DLOG_BITS: u32 = 20;
+
+/// Two compressed points
+pub const CIPHERTEXT_BYTES: usize = 2 * POINT_BYTES;
+
+const POINT_BYTES: usize = 33;
+
+// Baby-step giant-step: BABY_STEPS table entries, as many giant steps
+const BABY_STEPS: u64 = 1 << (DLOG_BITS / 2);
+
+// Domain separator for hashing the seed to a secret key
+const SEED_DOMAIN: &[u8] = b"elgamal-k256-keygen-v1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ciphertext {
+    pub c1: ProjectivePoint, // r*G
+    pub c2: ProjectivePoint, // m*G + r*Y
+}
+
+pub struct ExpElGamal {
+    secret: Scalar,
+    public: ProjectivePoint,
+    baby_steps: HashMap<Vec<u8>, u64>, // Compressed j*G -> j, for j < BABY_STEPS
+}
+
+impl ExpElGamal {
+    /// Deterministically derive a keypair from seed material (e.g. vetKD output)
+    pub fn from_seed(seed: &[u8]) -> Result<Self, String> {
+        if seed.len() < MIN_SEED_BYTES {
+            return Err(format!("Seed too short: {} bytes (min {})", seed.len(), MIN_SEED_BYTES));
+        }
+
+        // Rejection-sample a non-zero scalar from H(domain || counter || seed)
+        for counter in 0u32.. {
+            let mut hasher = Sha256::new();
+            hasher.update(SEED_DOMAIN);
+            hasher.update(counter.to_be_bytes());
+            hasher.update(seed);
+            let candidate: Option<Scalar> = Scalar::from_repr(hasher.finalize()).into();
+
+            if let Some(secret) = candidate.filter(|s| !bool::from(s.is_zero())) {
+                return Ok(Self::from_secret(secret));
+            }
+        }
+        unreachable!("a valid scalar is found after a couple of attempts")
+    }
+
+    fn from_secret(secret: Scalar) -> Self {
+        let mut baby_steps = HashMap::with_capacity(BABY_STEPS as usize);
+        let mut point = ProjectivePoint::IDENTITY;
+        for j in 0..BABY_STEPS {
+            baby_steps.insert(compress(&point).to_vec(), j);
+            point += ProjectivePoint::GENERATOR;
+        }
+
+        ExpElGamal { secret, public: ProjectivePoint::GENERATOR * secret, baby_steps }
+    }
+
+    pub fn public_key(&self) -> &ProjectivePoint {
+        &self.public
+    }
+
+    /// Group order; plaintexts and plain factors live mod this
+    pub fn order() -> BigUint {
+        BigUint::from_bytes_be(&(-Scalar::ONE).to_bytes()) + 1u32
+    }
+
+    pub fn encrypt_with_rng<R: RngCore + CryptoRng>(&self, m: &[u8], rng: &mut R) -> Result<Ciphertext, String> {
+        let m_big = BigUint::from_bytes_be(m);
+        if m_big >= Self::order() {
+            return Err("Message too large".into());
+        }
+
+        let r = Scalar::random(&mut *rng);
+        Ok(Ciphertext {
+            c1: ProjectivePoint::GENERATOR * r,
+            c2: ProjectivePoint::GENERATOR * to_scalar(&m_big) + self.public * r,
+        })
+    }
+
+    pub fn add(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
+        Ciphertext { c1: a.c1 + b.c1, c2: a.c2 + b.c2 }
+    }
+
+    /// Enc(-m mod order)
+    pub fn neg(&self, c: &Ciphertext) -> Ciphertext {
+        Ciphertext { c1: -c.c1, c2: -c.c2 }
+    }
+
+    /// Enc(k * m)
+    pub fn mul_plain(&self, c: &Ciphertext, k: &BigUint) -> Ciphertext {
+        let k = to_scalar(k);
+        Ciphertext { c1: c.c1 * k, c2: c.c2 * k }
+    }
+
+    /// Same plaintext under fresh randomness: add Enc(0)
+    pub fn rerandomize_with_rng<R: RngCore + CryptoRng>(&self, c: &Ciphertext, rng: &mut R) -> Ciphertext {
+        let s = Scalar::random(&mut *rng);
+        Ciphertext { c1: c.c1 + ProjectivePoint::GENERATOR * s, c2: c.c2 + self.public * s }
+    }
+
+    // m*G = c2 - x*c1
+    fn plaintext_point(&self, c: &Ciphertext) -> ProjectivePoint {
+        c.c2 - c.c1 * self.secret
+    }
+
+    /// Whether `c` encrypts zero; no discrete log needed
+    pub fn is_zero(&self, c: &Ciphertext) -> bool {
+        self.plaintext_point(c) == ProjectivePoint::IDENTITY
+    }
+
+    /// Recover m by baby-step giant-step, trying -m*G when m*G is out of
+    /// range; fails for |m| >= 2^DLOG_BITS
+    pub fn decrypt(&self, c: &Ciphertext) -> Result<BigUint, String> {
+        let point = self.plaintext_point(c);
+        if let Some(m) = self.small_dlog(point) {
+            return Ok(BigUint::from(m));
+        }
+        if let Some(m) = self.small_dlog(-point) {
+            return Ok(Self::order() - m);
+        }
+        Err(format!("Plaintext too large to decrypt (ElGamal only decrypts values below 2^{} in magnitude)",
+            DLOG_BITS))
+    }
+
+    // m with m*G = point, if m < 2^DLOG_BITS
+    fn small_dlog(&self, mut point: ProjectivePoint) -> Option<u64> {
+        let giant_step = -(ProjectivePoint::GENERATOR * Scalar::from(BABY_STEPS));
+        for i in 0..BABY_STEPS {
+            if let Some(j) = self.baby_steps.get(compress(&point).as_slice()) {
+                return Some(i * BABY_STEPS + j);
+            }
+            point += giant_step;
+        }
+        None
+    }
+}
+
+impl Ciphertext {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(CIPHERTEXT_BYTES);
+        out.extend_from_slice(&compress(&self.c1));
+        out.extend_from_slice(&compress(&self.c2));
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != CIPHERTEXT_BYTES {
+            return Err(format!("ElGamal ciphertext must be {} bytes, got {}", CIPHERTEXT_BYTES, bytes.len()));
+        }
+        Ok(Ciphertext {
+            c1: decompress(&bytes[..POINT_BYTES])?,
+            c2: decompress(&bytes[POINT_BYTES..])?,
+        })
+    }
+}
+
+// BigUint -> scalar, reducing mod the group order
+fn to_scalar(x: &BigUint) -> Scalar {
+    let bytes = (x % ExpElGamal::order()).to_bytes_be();
+    let mut repr = FieldBytes::default();
+    repr[32 - bytes.len()..].copy_from_slice(&bytes);
+    Option::from(Scalar::from_repr(repr)).expect("reduced below the group order")
+}
+
+// SEC1 compressed; the identity encodes as all zeros
+fn compress(point: &ProjectivePoint) -> [u8; POINT_BYTES] {
+    let mut out = [0u8; POINT_BYTES];
+    out.copy_from_slice(&point.to_affine().to_bytes());
+    out
+}
+
+fn decompress(bytes: &[u8]) -> Result<ProjectivePoint, String> {
+    let point: Option<AffinePoint> = AffinePoint::from_bytes(bytes.into()).into();
+    point.map(ProjectivePoint::from).ok_or_else(|| "Invalid curve point".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::SignedEncoding;
+    use num_bigint::BigInt;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn negative_results_decrypt() {
+        let elgamal = ExpElGamal::from_seed(&[9u8; 32]).unwrap();
+        let encoding = SignedEncoding::new(ExpElGamal::order()).unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+
+        // 3 - 10 = -7
+        let three = elgamal.encrypt_with_rng(&[3], &mut rng).unwrap();
+        let ten = elgamal.encrypt_with_rng(&[10], &mut rng).unwrap();
+        let difference = elgamal.add(&three, &elgamal.neg(&ten));
+        assert_eq!(encoding.decode(&elgamal.decrypt(&difference).unwrap()).unwrap(), BigInt::from(-7));
+
+        let limit = (1u32 << DLOG_BITS).to_be_bytes();
+        let too_large = elgamal.encrypt_with_rng(&limit, &mut rng).unwrap();
+        assert!(elgamal.decrypt(&too_large).is_err());
+        assert!(elgamal.decrypt(&elgamal.neg(&too_large)).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Signed integers and fixed-point decimals as plaintexts.
+//!
+//! Plaintexts are residues mod the plaintext modulus (n for Paillier). Signed
+//! values use the n/2 split: x >= 0 encodes as x and x < 0 as n + x, so
+//! homomorphic addition and plaintext multiplication work unchanged and any
+//! residue above (n - 1) / 2 decodes as negative. Results are only correct
+//! while every intermediate value stays within that magnitude.
+//!
+//! Fixed-point decimals are integers scaled by 10^decimals. Sums keep the
+//! scale; multiplying by an encoded plaintext weight adds the weight's
+//! decimals, which `FixedPoint::product` tracks for decoding. Encoding from
+//! f64 is capped at MAX_DECIMALS; decoding accepts as many decimals as the
+//! modulus holds, so products of scaled values still decode.
+
+use num_bigint::{BigInt, BigUint, Sign};
+use num_traits::{FromPrimitive, Signed, ToPrimitive};
+
+/// Most decimals `FixedPoint::new` accepts; beyond this f64 inputs lose the scale
+pub const MAX_DECIMALS: u32 = 18;
+
+/// Signed encoding for one plaintext modulus
+#[derive(Clone, Debug)]
+pub struct SignedEncoding {
+    modulus: BigUint,
+    max_magnitude: BigUint, // (modulus - 1) / 2
+}
+
+impl SignedEncoding {
+    /// `modulus` must be odd (an RSA modulus or a prime group order) so the
+    /// positive and negative halves are the same size
+    pub fn new(modulus: BigUint) -> Result<Self, String> {
+        if modulus.bits() < 2 || !modulus.bit(0) {
+            return Err("Plaintext modulus must be odd and at least 3".to_string());
+        }
+        let max_magnitude = (&modulus - 1u32) >> 1;
+        Ok(SignedEncoding { modulus, max_magnitude })
+    }
+
+    /// Largest |x| that encodes
+    pub fn max_magnitude(&self) -> &BigUint {
+        &self.max_magnitude
+    }
+
+    /// Most decimals at which 1.0 (10^decimals) still encodes
+    pub fn max_decimals(&self) -> u32 {
+        self.max_magnitude.to_string().len() as u32 - 1
+    }
+
+    pub fn encode(&self, x: &BigInt) -> Result<BigUint, String> {
+        let magnitude = x.magnitude();
+        if magnitude > &self.max_magnitude {
+            return Err(format!("{} is out of range (|x| must fit {} bits)", x, self.max_magnitude.bits()));
+        }
+        Ok(match x.sign() {
+            Sign::Minus => &self.modulus - magnitude,
+            _ => magnitude.clone(),
+        })
+    }
+
+    pub fn encode_i64(&self, x: i64) -> Result<BigUint, String> {
+        self.encode(&BigInt::from(x))
+    }
+
+    /// Residues above (modulus - 1) / 2 are negative
+    pub fn decode(&self, m: &BigUint) -> Result<BigInt, String> {
+        if m >= &self.modulus {
+            return Err("Plaintext is not reduced mod the plaintext modulus".to_string());
+        }
+        Ok(if m > &self.max_magnitude {
+            BigInt::from_biguint(Sign::Minus, &self.modulus - m)
+        } else {
+            BigInt::from(m.clone())
+        })
+    }
+}
+
+/// Decimal fixed point: x is represented by round(x * 10^decimals)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedPoint {
+    decimals: u32,
+}
+
+impl FixedPoint {
+    pub fn new(decimals: u32) -> Result<Self, String> {
+        if decimals > MAX_DECIMALS {
+            return Err(format!("At most {} decimals are supported, got {}", MAX_DECIMALS, decimals));
+        }
+        Ok(FixedPoint { decimals })
+    }
+
+    /// For decoding results, e.g. products that carry the decimals of both
+    /// factors: anything up to the encoding's capacity
+    pub fn for_decoding(decimals: u32, encoding: &SignedEncoding) -> Result<Self, String> {
+        if decimals > encoding.max_decimals() {
+            return Err(format!("At most {} decimals fit the plaintext modulus, got {}",
+                encoding.max_decimals(), decimals));
+        }
+        Ok(FixedPoint { decimals })
+    }
+
+    pub fn decimals(&self) -> u32 {
+        self.decimals
+    }
+
+    /// Format of Enc(x) * y, for x in this format and a plaintext weight y in `weight`
+    pub fn product(&self, weight: FixedPoint) -> FixedPoint {
+        FixedPoint { decimals: self.decimals + weight.decimals }
+    }
+
+    fn scale(&self) -> f64 {
+        10f64.powi(self.decimals as i32)
+    }
+
+    /// round(x * 10^decimals); exact only while that stays below 2^53
+    pub fn scaled(&self, x: f64) -> Result<BigInt, String> {
+        if !x.is_finite() {
+            return Err(format!("Cannot encode {}", x));
+        }
+        BigInt::from_f64((x * self.scale()).round()).ok_or_else(|| format!("Cannot encode {}", x))
+    }
+
+    pub fn unscaled(&self, value: &BigInt) -> f64 {
+        value.to_f64().unwrap_or(f64::NAN) / self.scale()
+    }
+
+    /// Exact decimal form of a scaled value, e.g. -12345 at 3 decimals is "-12.345"
+    pub fn format(&self, value: &BigInt) -> String {
+        let sign = if value.is_negative() { "-" } else { "" };
+        let decimals = self.decimals as usize;
+        if decimals == 0 {
+            return format!("{}{}", sign, value.magnitude());
+        }
+
+        // Zero-pad so there is at least one digit before the point
+        let digits = format!("{:0>width$}", value.magnitude().to_string(), width = decimals + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - decimals);
+        format!("{}{}.{}", sign, integer, fraction)
+    }
+
+    pub fn encode(&self, encoding: &SignedEncoding, x: f64) -> Result<BigUint, String> {
+        encoding.encode(&self.scaled(x)?)
+    }
+
+    pub fn decode(&self, encoding: &SignedEncoding, m: &BigUint) -> Result<f64, String> {
+        Ok(self.unscaled(&encoding.decode(m)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoding() -> SignedEncoding {
+        // A 512-bit odd modulus, as for the smallest Paillier key
+        SignedEncoding::new((BigUint::from(1u32) << 511usize) + 1u32).unwrap()
+    }
+
+    #[test]
+    fn signed_values_round_trip() {
+        let encoding = encoding();
+        for x in [0i64, 1, -1, 123_456_789, -987_654_321] {
+            assert_eq!(encoding.decode(&encoding.encode_i64(x).unwrap()).unwrap(), BigInt::from(x));
+        }
+        let max = BigInt::from(encoding.max_magnitude().clone());
+        assert_eq!(encoding.decode(&encoding.encode(&-&max).unwrap()).unwrap(), -&max);
+        assert!(encoding.encode(&(max + 1)).is_err());
+    }
+
+    #[test]
+    fn products_decode_past_the_encoding_cap() {
+        let encoding = encoding();
+        let value = FixedPoint::new(MAX_DECIMALS).unwrap();
+        let weight = FixedPoint::new(MAX_DECIMALS).unwrap();
+
+        // -1.5 * 2.25 at 18 + 18 decimals
+        let product = value.scaled(-1.5).unwrap() * weight.scaled(2.25).unwrap();
+        let format = FixedPoint::for_decoding(value.product(weight).decimals(), &encoding).unwrap();
+        let decoded = encoding.decode(&encoding.encode(&product).unwrap()).unwrap();
+        assert_eq!(format.format(&decoded), format!("-3.375{}", "0".repeat(33)));
+
+        assert!(FixedPoint::new(MAX_DECIMALS + 1).is_err());
+        assert!(FixedPoint::for_decoding(encoding.max_decimals(), &encoding).is_ok());
+        assert!(FixedPoint::for_decoding(encoding.max_decimals() + 1, &encoding).is_err());
+    }
+}
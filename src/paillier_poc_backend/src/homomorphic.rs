@@ -0,0 +1,274 @@
+//! Common interface of the additively homomorphic schemes the canister can run
+//! on, chosen at `initialize_paillier` time:
+//!
+//! - Paillier (`SimplePaillier`): any plaintext below n decrypts, 128-512 byte
+//!   ciphertexts depending on the key size
+//! - Exponential ElGamal on secp256k1 (`ExpElGamal`): 66-byte ciphertexts and
+//!   cheaper operations, but only small plaintexts decrypt
+//!
+//! Stored documents and results hold serialized ciphertexts, so code above this
+//! layer only sees bytes. `Backend` holds whichever keypair is in use and
+//! `dispatch!` runs generic code against the concrete scheme.
+
+use num_bigint::BigUint;
+use num_traits::Zero;
+use rand::{CryptoRng, RngCore};
+
+use crate::elgamal::{self, ExpElGamal};
+use crate::rng;
+use crate::simple_paillier::SimplePaillier;
+
+pub trait AdditiveHomomorphic: Sized {
+    type Ciphertext: Clone;
+
+    /// Deterministic key generation from seed material; `bits` is the modulus
+    /// size for schemes that have one
+    fn keygen(seed: &[u8], bits: usize) -> Result<Self, String>;
+
+    fn encrypt_with_rng<R: RngCore + CryptoRng>(&self, m: &[u8], rng: &mut R) -> Result<Self::Ciphertext, String>;
+
+    /// Encrypt with randomness from the canister RNG
+    fn encrypt(&self, m: &[u8]) -> Result<Self::Ciphertext, String> {
+        rng::with_rng(|rng| self.encrypt_with_rng(m, rng))?
+    }
+
+    /// Enc(m1 + m2)
+    fn add(&self, c1: &Self::Ciphertext, c2: &Self::Ciphertext) -> Self::Ciphertext;
+
+    /// Enc(-m)
+    fn neg(&self, c: &Self::Ciphertext) -> Result<Self::Ciphertext, String>;
+
+    /// Enc(m1 - m2)
+    fn sub(&self, c1: &Self::Ciphertext, c2: &Self::Ciphertext) -> Result<Self::Ciphertext, String> {
+        Ok(self.add(c1, &self.neg(c2)?))
+    }
+
+    /// Enc(k * m)
+    fn mul_plain(&self, c: &Self::Ciphertext, k: &BigUint) -> Self::Ciphertext;
+
+    /// Same plaintext, fresh randomness
+    fn rerandomize_with_rng<R: RngCore + CryptoRng>(&self, c: &Self::Ciphertext, rng: &mut R) -> Result<Self::Ciphertext, String>;
+
+    /// Rerandomize with randomness from the canister RNG
+    fn rerandomize(&self, c: &Self::Ciphertext) -> Result<Self::Ciphertext, String> {
+        rng::with_rng(|rng| self.rerandomize_with_rng(c, rng))?
+    }
+
+    /// Plaintexts and plain factors are taken mod this
+    fn plaintext_modulus(&self) -> BigUint;
+
+    fn decrypt(&self, c: &Self::Ciphertext) -> Result<BigUint, String>;
+
+    /// Whether `c` encrypts zero (the comparison primitive)
+    fn is_zero(&self, c: &Self::Ciphertext) -> Result<bool, String> {
+        Ok(self.decrypt(c)?.is_zero())
+    }
+
+    fn serialize(&self, c: &Self::Ciphertext) -> Vec<u8>;
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Self::Ciphertext, String>;
+}
+
+impl AdditiveHomomorphic for SimplePaillier {
+    type Ciphertext = BigUint;
+
+    fn keygen(seed: &[u8], bits: usize) -> Result<Self, String> {
+        SimplePaillier::from_seed(seed, bits)
+    }
+
+    fn encrypt_with_rng<R: RngCore + CryptoRng>(&self, m: &[u8], rng: &mut R) -> Result<BigUint, String> {
+        self.public_key().encrypt_with_rng(m, rng)
+    }
+
+    // Draws on the randomizer pool (see pool.rs)
+    fn encrypt(&self, m: &[u8]) -> Result<BigUint, String> {
+        SimplePaillier::encrypt(self, m)
+    }
+
+    fn add(&self, c1: &BigUint, c2: &BigUint) -> BigUint {
+        SimplePaillier::add(self, c1, c2)
+    }
+
+    fn neg(&self, c: &BigUint) -> Result<BigUint, String> {
+        SimplePaillier::neg(self, c)
+    }
+
+    fn mul_plain(&self, c: &BigUint, k: &BigUint) -> BigUint {
+        SimplePaillier::mul_plain(self, c, k)
+    }
+
+    fn rerandomize_with_rng<R: RngCore + CryptoRng>(&self, c: &BigUint, rng: &mut R) -> Result<BigUint, String> {
+        Ok(self.public_key().rerandomize_with_rng(c, rng))
+    }
+
+    // Draws on the randomizer pool
+    fn rerandomize(&self, c: &BigUint) -> Result<BigUint, String> {
+        SimplePaillier::rerandomize(self, c)
+    }
+
+    fn plaintext_modulus(&self) -> BigUint {
+        self.public_key().n.clone()
+    }
+
+    fn decrypt(&self, c: &BigUint) -> Result<BigUint, String> {
+        SimplePaillier::decrypt(self, c)
+    }
+
+    fn serialize(&self, c: &BigUint) -> Vec<u8> {
+        c.to_bytes_be()
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<BigUint, String> {
+        Ok(BigUint::from_bytes_be(bytes))
+    }
+}
+
+impl AdditiveHomomorphic for ExpElGamal {
+    type Ciphertext = elgamal::Ciphertext;
+
+    fn keygen(seed: &[u8], _bits: usize) -> Result<Self, String> {
+        ExpElGamal::from_seed(seed)
+    }
+
+    fn encrypt_with_rng<R: RngCore + CryptoRng>(&self, m: &[u8], rng: &mut R) -> Result<elgamal::Ciphertext, String> {
+        ExpElGamal::encrypt_with_rng(self, m, rng)
+    }
+
+    fn add(&self, c1: &elgamal::Ciphertext, c2: &elgamal::Ciphertext) -> elgamal::Ciphertext {
+        ExpElGamal::add(self, c1, c2)
+    }
+
+    fn neg(&self, c: &elgamal::Ciphertext) -> Result<elgamal::Ciphertext, String> {
+        Ok(ExpElGamal::neg(self, c))
+    }
+
+    fn mul_plain(&self, c: &elgamal::Ciphertext, k: &BigUint) -> elgamal::Ciphertext {
+        ExpElGamal::mul_plain(self, c, k)
+    }
+
+    fn rerandomize_with_rng<R: RngCore + CryptoRng>(&self, c: &elgamal::Ciphertext, rng: &mut R) -> Result<elgamal::Ciphertext, String> {
+        Ok(ExpElGamal::rerandomize_with_rng(self, c, rng))
+    }
+
+    fn plaintext_modulus(&self) -> BigUint {
+        ExpElGamal::order()
+    }
+
+    fn decrypt(&self, c: &elgamal::Ciphertext) -> Result<BigUint, String> {
+        ExpElGamal::decrypt(self, c)
+    }
+
+    fn is_zero(&self, c: &elgamal::Ciphertext) -> Result<bool, String> {
+        Ok(ExpElGamal::is_zero(self, c))
+    }
+
+    fn serialize(&self, c: &elgamal::Ciphertext) -> Vec<u8> {
+        c.to_bytes()
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<elgamal::Ciphertext, String> {
+        elgamal::Ciphertext::from_bytes(bytes)
+    }
+}
+
+// ===== RUNTIME SELECTION =====
+
+/// The keypair of whichever scheme the canister was initialized with
+pub enum Backend {
+    Paillier(SimplePaillier),
+    ElGamal(ExpElGamal),
+}
+
+/// Evaluate `$body` with `$he` bound to the backend's concrete scheme
+macro_rules! dispatch {
+    ($backend:expr, $he:ident => $body:expr) => {
+        match $backend {
+            $crate::homomorphic::Backend::Paillier($he) => $body,
+            $crate::homomorphic::Backend::ElGamal($he) => $body,
+        }
+    };
+}
+pub(crate) use dispatch;
+
+impl Backend {
+    /// The Paillier keypair, for features that only exist for Paillier
+    /// (range proofs, decryption proofs, client-side encryption)
+    pub fn paillier(&self) -> Option<&SimplePaillier> {
+        match self {
+            Backend::Paillier(paillier) => Some(paillier),
+            Backend::ElGamal(_) => None,
+        }
+    }
+
+    /// Serialized Enc(m)
+    pub fn encrypt(&self, m: &[u8]) -> Result<Vec<u8>, String> {
+        dispatch!(self, he => he.encrypt(m).map(|c| he.serialize(&c)))
+    }
+
+    /// Serialized Enc(m1 + m2) of two serialized ciphertexts
+    pub fn add(&self, c1: &[u8], c2: &[u8]) -> Result<Vec<u8>, String> {
+        dispatch!(self, he => {
+            let sum = he.add(&he.deserialize(c1)?, &he.deserialize(c2)?);
+            Ok(he.serialize(&sum))
+        })
+    }
+
+    /// Serialized rerandomization of a serialized ciphertext
+    pub fn rerandomize(&self, c: &[u8]) -> Result<Vec<u8>, String> {
+        dispatch!(self, he => he.rerandomize(&he.deserialize(c)?).map(|c| he.serialize(&c)))
+    }
+
+    pub fn decrypt(&self, c: &[u8]) -> Result<BigUint, String> {
+        dispatch!(self, he => he.decrypt(&he.deserialize(c)?))
+    }
+
+    pub fn plaintext_modulus(&self) -> BigUint {
+        dispatch!(self, he => he.plaintext_modulus())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    // The contract every backend has to meet, checked through the trait only
+    fn check_backend<H: AdditiveHomomorphic>(he: &H) {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let mut enc = |m: u64| he.encrypt_with_rng(&m.to_be_bytes(), &mut rng).unwrap();
+        let (five, three, zero) = (enc(5), enc(3), enc(0));
+        let decrypt = |c: &H::Ciphertext| he.decrypt(c).unwrap();
+
+        assert_eq!(decrypt(&he.add(&five, &three)), BigUint::from(8u32));
+        assert_eq!(decrypt(&he.sub(&five, &three).unwrap()), BigUint::from(2u32));
+        assert_eq!(decrypt(&he.sub(&three, &five).unwrap()), he.plaintext_modulus() - 2u32);
+        assert_eq!(decrypt(&he.mul_plain(&three, &BigUint::from(7u32))), BigUint::from(21u32));
+        assert_eq!(decrypt(&he.mul_plain(&three, &(he.plaintext_modulus() - 1u32))), he.plaintext_modulus() - 3u32);
+
+        assert!(he.is_zero(&zero).unwrap());
+        assert!(he.is_zero(&he.sub(&five, &five).unwrap()).unwrap());
+        assert!(he.is_zero(&he.mul_plain(&five, &BigUint::zero())).unwrap());
+        assert!(!he.is_zero(&five).unwrap());
+
+        let bytes = he.serialize(&five);
+        let restored = he.deserialize(&bytes).unwrap();
+        assert_eq!(he.serialize(&restored), bytes);
+        assert_eq!(decrypt(&restored), BigUint::from(5u32));
+
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let fresh = he.rerandomize_with_rng(&five, &mut rng).unwrap();
+        assert_ne!(he.serialize(&fresh), bytes);
+        assert_eq!(decrypt(&fresh), BigUint::from(5u32));
+    }
+
+    #[test]
+    fn paillier_meets_the_contract() {
+        check_backend(&<SimplePaillier as AdditiveHomomorphic>::keygen(&[3u8; 32], 512).unwrap());
+    }
+
+    #[test]
+    fn elgamal_meets_the_contract() {
+        check_backend(&<ExpElGamal as AdditiveHomomorphic>::keygen(&[3u8; 32], 0).unwrap());
+    }
+}
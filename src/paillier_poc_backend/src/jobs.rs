@@ -0,0 +1,596 @@
+//! Background comparison jobs. Work runs from timers one message at a time and
+//! progress is checkpointed in stable memory after every message, so a job
+//! survives the per-message instruction limit and canister upgrades.
+//!
+//! Jobs run with the access rights of the principal that started them: inside
+//! a timer `caller()` is the canister itself.
+//!
+//! With keys so large that one position's zero test doesn't fit a message
+//! (3072 bits), a compare job goes one position at a time and splits it into
+//! stages of about one encryption each: scaling the difference, rerandomizing
+//! it, then the zero test. A lone position is not shuffled with others, so
+//! the key holder (the canister itself) sees each position's outcome; only
+//! the encrypted running total is kept.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{instruction_counter, time};
+use std::cell::Cell;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::homomorphic::{dispatch, AdditiveHomomorphic, Backend};
+use crate::storage::{self, Access, Permission};
+use crate::{rng, similarity, PaillierError, INSTRUCTION_LIMIT_SAFETY};
+
+const RETRY_DELAY: Duration = Duration::from_secs(60); // After vetKD/raw_rand failures
+const JOB_RETENTION_NS: u64 = 24 * 60 * 60 * 1_000_000_000; // Finished jobs are kept for a day
+pub const MAX_JOBS: usize = 100;
+const BATCHES_PER_MESSAGE: u64 = 4; // Compare jobs checkpoint after each batch
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Job {
+    pub owner: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub status: JobStatus,
+    pub work: JobWork,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum JobWork {
+    Matrix(MatrixJob),
+    Compare(CompareJob),
+}
+
+/// All-pairs comparison over a set of documents
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MatrixJob {
+    pub doc_ids: Vec<String>,
+    pub entries: Vec<MatrixEntry>, // One per finished pair, in `pairs()` order
+}
+
+/// Positional comparison of two (possibly very long) documents. The running
+/// match count is kept encrypted, so checkpoints hold no plaintext result.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CompareJob {
+    pub doc_id1: String,
+    pub doc_id2: String,
+    pub tokens: u64,     // Token count of both documents when the job started
+    pub next_index: u64, // First position not compared yet
+    pub encrypted_matches: Option<Vec<u8>>, // Enc(matches in 0..next_index)
+    pub batch: Option<u64>, // Positions per batch once halved after running out of instructions
+    pub stage: Option<PositionStage>, // Position next_index part-way, with large keys
+}
+
+/// How far a split position has got (see the module doc)
+#[derive(CandidType, Deserialize, Clone)]
+pub enum PositionStage {
+    Scaled(Vec<u8>),  // Enc(r * (a - b)), not rerandomized yet
+    Blinded(Vec<u8>), // Waiting for the zero test
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MatrixEntry {
+    pub doc_id1: String,
+    pub doc_id2: String,
+    pub encrypted_score: Option<Vec<u8>>, // Enc(number of matching token positions)
+    pub tokens_compared: usize,
+    pub error: Option<String>, // e.g. document deleted or access revoked meanwhile
+}
+
+impl Job {
+    pub fn new(owner: Principal, work: JobWork) -> Self {
+        let now = time();
+        Job { owner, created_at: now, updated_at: now, status: JobStatus::Queued, work }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Completed | JobStatus::Failed(_))
+    }
+
+    /// (units done, units total)
+    pub fn progress(&self) -> (u64, u64) {
+        match &self.work {
+            JobWork::Matrix(matrix) => (matrix.entries.len() as u64, matrix.pairs().len() as u64),
+            JobWork::Compare(compare) => (compare.next_index, compare.tokens),
+        }
+    }
+}
+
+impl MatrixJob {
+    pub fn new(doc_ids: Vec<String>) -> Self {
+        MatrixJob { doc_ids, entries: Vec::new() }
+    }
+
+    /// Index pairs (i, j) with i < j, in processing order
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        let n = self.doc_ids.len();
+        (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))).collect()
+    }
+}
+
+impl CompareJob {
+    pub fn new(doc_id1: String, doc_id2: String, tokens: u64) -> Self {
+        CompareJob { doc_id1, doc_id2, tokens, next_index: 0, encrypted_matches: None, batch: None, stage: None }
+    }
+}
+
+// ===== RUNNER =====
+
+thread_local! {
+    static SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Queue a runner tick unless one is already pending. Called when a job is
+/// started and from post_upgrade, since timers do not survive upgrades.
+pub fn schedule() {
+    schedule_after(Duration::ZERO);
+}
+
+fn schedule_after(delay: Duration) {
+    if SCHEDULED.with(|s| s.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(run()));
+}
+
+async fn run() {
+    SCHEDULED.with(|s| s.set(false));
+
+    if next_unfinished_job().is_none() {
+        return;
+    }
+
+    // After an upgrade the RNG and keypair may need inter-canister calls
+    let ready = match crate::rng::ensure_seeded().await {
+        Ok(()) => crate::ensure_keypair().await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = ready {
+        ic_cdk::println!("Jobs: not ready ({}), retrying in {:?}", e, RETRY_DELAY);
+        schedule_after(RETRY_DELAY);
+        return;
+    }
+
+    // Each tick (or the callback after an await) is a fresh message with a full budget
+    if let Some(job_id) = next_unfinished_job() {
+        step(job_id);
+    }
+
+    if next_unfinished_job().is_some() {
+        schedule();
+    }
+}
+
+/// Oldest job still queued or running
+pub fn next_unfinished_job() -> Option<u64> {
+    let mut next = None;
+    storage::for_each_job(|job_id, job| {
+        if next.is_none() && !job.is_finished() {
+            next = Some(job_id);
+        }
+    });
+    next
+}
+
+/// Drop finished jobs older than the retention period
+pub fn prune_finished_jobs() {
+    let cutoff = time().saturating_sub(JOB_RETENTION_NS);
+    let mut expired = Vec::new();
+    storage::for_each_job(|job_id, job| {
+        if job.is_finished() && job.updated_at < cutoff {
+            expired.push(job_id);
+        }
+    });
+    for job_id in expired {
+        storage::remove_job(job_id);
+    }
+}
+
+// Whether the message still had nearly its whole budget when a step began. Work
+// that runs out of instructions in such a message will never fit one.
+fn started_fresh(start_instructions: u64) -> bool {
+    start_instructions < INSTRUCTION_LIMIT_SAFETY / 10
+}
+
+// Run one message worth of work on a job and checkpoint it
+fn step(job_id: u64) {
+    let Some(mut job) = storage::get_job(job_id) else { return };
+    job.status = JobStatus::Running;
+
+    // Jobs zero-test under the canister key, which threshold mode rules out
+    let owner = job.owner;
+    let result = match &mut job.work {
+        _ if storage::config().threshold_key.is_some() => Err(PaillierError::InvalidInput(
+            "Jobs are not available with threshold decryption".to_string())),
+        JobWork::Matrix(matrix) => step_matrix(matrix, owner),
+        JobWork::Compare(compare) => step_compare(compare, owner),
+    };
+
+    match result {
+        Ok(true) => job.status = JobStatus::Completed,
+        Ok(false) => {}
+        Err(e) => job.status = JobStatus::Failed(e.to_string()),
+    }
+    job.updated_at = time();
+
+    let (done, total) = job.progress();
+    ic_cdk::println!("Job {}: {}/{} ({:?})", job_id, done, total, job.status);
+
+    storage::insert_job(job_id, job);
+}
+
+// Compare pairs until the next one might not fit in this message; true when done
+fn step_matrix(matrix: &mut MatrixJob, owner: Principal) -> Result<bool, PaillierError> {
+    let pairs = matrix.pairs();
+    let start_instructions = instruction_counter();
+    let mut compared = 0;
+
+    crate::STATE.with(|state| {
+        let state = state.borrow();
+        let backend = state.backend.as_ref().ok_or(PaillierError::NotInitialized)?;
+        let key_config = state.key_config;
+        let per_position = key_config.instructions_per_comparison();
+
+        while let Some(&(i, j)) = pairs.get(matrix.entries.len()) {
+            let (doc_id1, doc_id2) = (&matrix.doc_ids[i], &matrix.doc_ids[j]);
+            let doc1 = storage::get_document(doc_id1);
+            let doc2 = storage::get_document(doc_id2);
+
+            let mut entry = MatrixEntry {
+                doc_id1: doc_id1.clone(),
+                doc_id2: doc_id2.clone(),
+                encrypted_score: None,
+                tokens_compared: 0,
+                error: None,
+            };
+
+            match (doc1, doc2) {
+                (Some(doc1), Some(doc2)) => {
+                    if !doc1.allows(owner, Permission::Compare)
+                        || !doc2.allows(owner, Permission::Compare)
+                    {
+                        entry.error = Some("Compare access was revoked".to_string());
+                    } else if doc1.packing.is_some() || doc2.packing.is_some() {
+                        entry.error = Some("Packed documents can only be used with compare_documents".to_string());
+                    } else if !key_config.comparison_fits() {
+                        entry.error = Some(format!(
+                            "A position does not fit in one message with {}-bit keys; use start_compare_job",
+                            key_config.key_bits));
+                    } else {
+                        let positions = doc1.tokens.len().min(doc2.tokens.len());
+
+                        // Leave the pair for the next message if it might not fit;
+                        // always make progress on the first one
+                        let estimate = positions as u64 * per_position;
+                        if compared > 0 && instruction_counter() + estimate > INSTRUCTION_LIMIT_SAFETY {
+                            break;
+                        }
+
+                        let score = crate::count_matching_tokens(
+                            backend,
+                            &doc1.tokens[..positions],
+                            &doc2.tokens[..positions],
+                            key_config,
+                        )
+                        .and_then(|matches| {
+                            backend.encrypt(&matches.to_be_bytes()).map_err(PaillierError::EncryptionFailed)
+                        });
+
+                        match score {
+                            Ok(score) => {
+                                entry.encrypted_score = Some(score);
+                                entry.tokens_compared = positions;
+                            }
+                            // The estimate was too low: redo the pair in the next message
+                            Err(PaillierError::InstructionLimitExceeded { .. })
+                                if compared > 0 || !started_fresh(start_instructions) => break,
+                            Err(PaillierError::InstructionLimitExceeded { .. }) => {
+                                entry.error = Some("The pair does not fit in one message; use start_compare_job".to_string());
+                            }
+                            Err(e) => entry.error = Some(e.to_string()),
+                        }
+                    }
+                }
+                (None, _) => entry.error = Some(format!("Document '{}' not found", doc_id1)),
+                (_, None) => entry.error = Some(format!("Document '{}' not found", doc_id2)),
+            }
+
+            matrix.entries.push(entry);
+            compared += 1;
+        }
+
+        let instructions_used = instruction_counter() - start_instructions;
+        storage::update_metrics(|m| {
+            m.comparison_operations += compared;
+            m.total_instructions_used += instructions_used;
+        });
+
+        Ok(matrix.entries.len() == pairs.len())
+    })
+}
+
+// Compare the positions that fit in this message and fold them into the
+// encrypted accumulator; true when the last position is done
+fn step_compare(compare: &mut CompareJob, owner: Principal) -> Result<bool, PaillierError> {
+    let start_instructions = instruction_counter();
+
+    let doc1 = storage::get_document(&compare.doc_id1)
+        .ok_or_else(|| PaillierError::DocumentNotFound(compare.doc_id1.clone()))?;
+    let doc2 = storage::get_document(&compare.doc_id2)
+        .ok_or_else(|| PaillierError::DocumentNotFound(compare.doc_id2.clone()))?;
+
+    if !doc1.allows(owner, Permission::Compare)
+        || !doc2.allows(owner, Permission::Compare)
+    {
+        return Err(PaillierError::Unauthorized("Compare access was revoked".to_string()));
+    }
+
+    // A replaced document would mix old and new positions in the count
+    if doc1.tokens.len() as u64 != compare.tokens || doc2.tokens.len() as u64 != compare.tokens
+        || doc1.packing.is_some() || doc2.packing.is_some()
+    {
+        return Err(PaillierError::InvalidInput("A document changed while the job was running".to_string()));
+    }
+
+    crate::STATE.with(|state| {
+        let state = state.borrow();
+        let backend = state.backend.as_ref().ok_or(PaillierError::NotInitialized)?;
+        let key_config = state.key_config;
+        let per_position = key_config.instructions_per_comparison();
+
+        if key_config.comparison_fits() {
+            let start = compare.next_index as usize;
+            let (matches, next) = run_batches(compare, start_instructions, per_position, instruction_counter, |range| {
+                crate::count_matching_tokens(backend, &doc1.tokens[range.clone()], &doc2.tokens[range], key_config)
+            })?;
+
+            if next > start {
+                add_matches(backend, compare, matches)?;
+                compare.next_index = next as u64;
+            }
+        } else {
+            step_position(backend, compare, &doc1.tokens, &doc2.tokens)?;
+        }
+
+        let instructions_used = instruction_counter() - start_instructions;
+        storage::update_metrics(|m| m.total_instructions_used += instructions_used);
+
+        let done = compare.next_index == compare.tokens;
+        if done {
+            storage::update_metrics(|m| m.comparison_operations += 1);
+        }
+        Ok(done)
+    })
+}
+
+// Fold a count into the job's encrypted running total
+fn add_matches(backend: &Backend, compare: &mut CompareJob, matches: u64) -> Result<(), PaillierError> {
+    let batch_matches = backend.encrypt(&matches.to_be_bytes())
+        .map_err(PaillierError::EncryptionFailed)?;
+    let total = match &compare.encrypted_matches {
+        Some(acc) => backend.add(acc, &batch_matches).map_err(PaillierError::ComparisonFailed)?,
+        None => batch_matches,
+    };
+    compare.encrypted_matches = Some(total);
+    Ok(())
+}
+
+// One stage of position `next_index`, for keys where a whole position doesn't
+// fit a message. The last stage adds the outcome to the total and moves on.
+fn step_position(
+    backend: &Backend,
+    compare: &mut CompareJob,
+    tokens1: &[Vec<u8>],
+    tokens2: &[Vec<u8>],
+) -> Result<(), PaillierError> {
+    let i = compare.next_index as usize;
+    let outcome = dispatch!(backend, he => advance_position(he, &mut compare.stage, &tokens1[i], &tokens2[i]))
+        .map_err(|e| match e {
+            PaillierError::ComparisonFailed(msg) => PaillierError::ComparisonFailed(format!("token {}: {}", i, msg)),
+            e => e,
+        })?;
+
+    if let Some(matched) = outcome {
+        add_matches(backend, compare, matched as u64)?;
+        compare.next_index += 1;
+    }
+    Ok(())
+}
+
+// Run the next stage; Some(outcome) once the zero test is done
+fn advance_position<H: AdditiveHomomorphic>(
+    he: &H,
+    stage: &mut Option<PositionStage>,
+    token1: &[u8],
+    token2: &[u8],
+) -> Result<Option<bool>, PaillierError> {
+    let failed = PaillierError::ComparisonFailed;
+    match stage.take() {
+        None => {
+            let c1 = he.deserialize(token1).map_err(failed)?;
+            let c2 = he.deserialize(token2).map_err(failed)?;
+            let scaled = rng::with_rng(|rng| similarity::scale_difference(he, &c1, &c2, rng))
+                .map_err(PaillierError::RandomnessUnavailable)?
+                .map_err(failed)?;
+            *stage = Some(PositionStage::Scaled(he.serialize(&scaled)));
+            Ok(None)
+        }
+        Some(PositionStage::Scaled(bytes)) => {
+            let blinded = he.deserialize(&bytes)
+                .and_then(|scaled| he.rerandomize(&scaled))
+                .map_err(failed)?;
+            *stage = Some(PositionStage::Blinded(he.serialize(&blinded)));
+            Ok(None)
+        }
+        Some(PositionStage::Blinded(bytes)) => he.deserialize(&bytes)
+            .and_then(|blinded| similarity::is_match(he, &blinded))
+            .map(Some)
+            .map_err(failed),
+    }
+}
+
+// One message worth of a compare job: `count` runs over batches of positions
+// from `next_index` until the next batch might not fit, at least one per
+// message. Returns the matches found and the first position not compared.
+// A batch that overran a whole message is halved for the next attempt.
+// `counter` is the instruction counter (a stand-in in tests).
+fn run_batches(
+    compare: &mut CompareJob,
+    start_instructions: u64,
+    per_position: u64,
+    counter: impl Fn() -> u64,
+    mut count: impl FnMut(Range<usize>) -> Result<u64, PaillierError>,
+) -> Result<(u64, usize), PaillierError> {
+    // Positions are only shuffled within a batch, so batches are a fair
+    // share of a message rather than single positions
+    let batch = compare.batch
+        .unwrap_or_else(|| (INSTRUCTION_LIMIT_SAFETY / per_position / BATCHES_PER_MESSAGE).max(1));
+    let start = compare.next_index as usize;
+    let total = compare.tokens as usize;
+    let mut next = start;
+    let mut matches = 0;
+
+    while next < total {
+        if next > start && counter() + batch * per_position > INSTRUCTION_LIMIT_SAFETY {
+            break;
+        }
+        let end = (next + batch as usize).min(total);
+        match count(next..end) {
+            Ok(batch_matches) => {
+                matches += batch_matches;
+                next = end;
+            }
+            // Keep the finished batches and resume in the next message
+            Err(PaillierError::InstructionLimitExceeded { .. }) => {
+                if next == start && started_fresh(start_instructions) {
+                    if batch == 1 {
+                        return Err(PaillierError::InvalidInput(
+                            "A single position does not fit in one message".to_string()));
+                    }
+                    compare.batch = Some(batch / 2);
+                }
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((matches, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PER_POSITION: u64 = INSTRUCTION_LIMIT_SAFETY / 45; // 45 positions per message
+    const BATCH: usize = 11; // Default: 45 / BATCHES_PER_MESSAGE
+
+    // A message: the counter starts at `used` and each position costs
+    // PER_POSITION times `overrun`, failing like count_matching_tokens does
+    // once past the limit. Position i matches when i is even.
+    fn message(compare: &mut CompareJob, used: u64, overrun: u64) -> Result<(u64, usize), PaillierError> {
+        let counter = Cell::new(used);
+        run_batches(compare, used, PER_POSITION, || counter.get(), |range| {
+            let cost = counter.get() + range.len() as u64 * PER_POSITION * overrun;
+            if cost > INSTRUCTION_LIMIT_SAFETY {
+                counter.set(INSTRUCTION_LIMIT_SAFETY);
+                return Err(PaillierError::InstructionLimitExceeded { used: cost, limit: INSTRUCTION_LIMIT_SAFETY });
+            }
+            counter.set(cost);
+            Ok(range.filter(|i| i % 2 == 0).count() as u64)
+        })
+    }
+
+    // Drive a job to the end, checkpointing like `step_compare`
+    fn run_to_end(compare: &mut CompareJob, overrun: u64) -> (u64, usize) {
+        let (mut matches, mut messages) = (0, 0);
+        while compare.next_index < compare.tokens {
+            let (found, next) = message(compare, 0, overrun).unwrap();
+            matches += found;
+            compare.next_index = next as u64;
+            messages += 1;
+        }
+        (matches, messages)
+    }
+
+    fn job(tokens: u64) -> CompareJob {
+        CompareJob::new("a".to_string(), "b".to_string(), tokens)
+    }
+
+    #[test]
+    fn fresh_messages() {
+        assert!(started_fresh(0));
+        assert!(started_fresh(INSTRUCTION_LIMIT_SAFETY / 10 - 1));
+        assert!(!started_fresh(INSTRUCTION_LIMIT_SAFETY / 10));
+    }
+
+    #[test]
+    fn resumes_from_the_checkpoint() {
+        let mut compare = job(100);
+        assert_eq!(run_to_end(&mut compare, 1), (50, 3));
+        assert_eq!(compare.batch, None);
+
+        // A message that starts half spent stops after whole batches
+        let mut compare = job(100);
+        let (matches, next) = message(&mut compare, INSTRUCTION_LIMIT_SAFETY / 2, 1).unwrap();
+        assert!(next > 0 && next < 100);
+        assert_eq!(next % BATCH, 0);
+        assert_eq!(matches, (0..next).filter(|i| i % 2 == 0).count() as u64);
+        compare.next_index = next as u64;
+        assert_eq!(matches + run_to_end(&mut compare, 1).0, 50);
+    }
+
+    #[test]
+    fn overrun_in_a_fresh_message_halves_the_batch() {
+        // Positions cost 8x the estimate, so a default batch never fits
+        let mut compare = job(30);
+        assert_eq!(message(&mut compare, 0, 8).unwrap(), (0, 0));
+        assert_eq!(compare.batch, Some(5));
+        assert_eq!(run_to_end(&mut compare, 8).0, 15);
+        assert_eq!(compare.batch, Some(5));
+
+        // Not halved when the message was already partly spent
+        let mut compare = job(30);
+        assert_eq!(message(&mut compare, INSTRUCTION_LIMIT_SAFETY / 2, 8).unwrap(), (0, 0));
+        assert_eq!(compare.batch, None);
+    }
+
+    #[test]
+    fn split_positions_count_like_whole_ones() {
+        use crate::simple_paillier::SimplePaillier;
+
+        rng::seed([5; 32]);
+        let backend = Backend::Paillier(SimplePaillier::from_seed(&[6; 32], 512).unwrap());
+        let encrypt = |tokens: &[u8]| -> Vec<Vec<u8>> {
+            tokens.iter().map(|&t| backend.encrypt(&[t]).unwrap()).collect()
+        };
+        let (tokens1, tokens2) = (encrypt(&[1, 2, 3, 4, 5]), encrypt(&[1, 9, 3, 9, 5]));
+
+        let mut compare = job(5);
+        let mut messages = 0;
+        while compare.next_index < compare.tokens {
+            step_position(&backend, &mut compare, &tokens1, &tokens2).unwrap();
+            messages += 1;
+        }
+        assert_eq!(messages, 15); // Three stages per position
+        assert!(compare.stage.is_none());
+
+        let total = compare.encrypted_matches.unwrap();
+        assert_eq!(backend.decrypt(&total).unwrap(), 3u32.into());
+    }
+
+    #[test]
+    fn a_position_that_never_fits_fails() {
+        let mut compare = job(4);
+        compare.batch = Some(1);
+        assert!(matches!(message(&mut compare, 0, 100), Err(PaillierError::InvalidInput(_))));
+    }
+}
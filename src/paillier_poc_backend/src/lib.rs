@@ -1,19 +1,81 @@
 use ic_cdk_macros::*;
 use ic_cdk::api::{time, instruction_counter, caller};
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
 use num_bigint::BigUint;
+use num_traits::Zero;
 use std::cell::RefCell;
+use std::num::NonZeroU32;
+use std::time::Duration;
 use serde::Serialize;
 
-mod simple_paillier;
+pub mod elgamal;
+pub mod encoding;
+pub mod homomorphic;
+mod jobs;
+pub mod packing;
+pub mod pool;
+pub mod primes;
+pub mod proofs;
+pub mod rng;
+pub mod similarity;
+pub mod simple_paillier;
+mod storage;
+pub mod threshold;
+pub mod vetkd_check;
+pub mod vetkd_utils;
+use elgamal::ExpElGamal;
+use encoding::{FixedPoint, SignedEncoding};
+use homomorphic::{dispatch, AdditiveHomomorphic, Backend};
+use jobs::{CompareJob, Job, JobStatus, JobWork, MatrixEntry, MatrixJob};
+use packing::SlotLayout;
 use simple_paillier::SimplePaillier;
+use storage::{
+    Access, AccessGrant, DecryptionRequest, HeScheme, KeyDerivation, PackedLayout, PartialShare, PendingUpload,
+    Permission, StoredDocument, ThresholdKeyConfig,
+};
+use threshold::ThresholdKey;
+use vetkd_utils::{
+    log_security_event, CacheStats, SecurityEvent, SecurityEventType, VetKeyManager,
+    VetKeyMetrics,
+};
 
 // ===== CONSTANTS FROM SPEC =====
 const TOKEN_SIZE: usize = 32;
-const KEY_SIZE: usize = 512; // For POC
-const MAX_TOKENS: usize = 50; // Reduced for ICP safety
-const MAX_DOCUMENTS: usize = 100; // Memory limit
-const INSTRUCTION_LIMIT_SAFETY: u64 = 4_500_000_000; // 90% of query limit (improved from 80%)
+const DEFAULT_KEY_SIZE: usize = 512; // For POC
+// Estimated instructions per Paillier encryption in wasm, from 2048/3072-bit
+// measurements scaled by the cube of the size. `initialize_paillier` replaces
+// them with a measurement. Every size fits an encryption and a decryption in
+// one message; at 3072 bits a zero test (about two encryptions) doesn't, so
+// compare jobs split each position over several messages (see jobs.rs).
+const PAILLIER_TOKEN_COSTS: [(usize, u64); 4] = [
+    (512, 90_000_000),
+    (1024, 725_000_000),
+    (2048, 5_800_000_000),
+    (3072, 19_000_000_000),
+];
+const ELGAMAL_TOKEN_COST: u64 = 45_000_000; // About half of 512-bit Paillier
+const ELGAMAL_KEY_BITS: usize = 256; // secp256k1, the only ElGamal group
+const PACKED_VALUE_BITS: usize = 16; // Token fingerprints in packed documents
+const PACKED_GUARD_BITS: usize = 1; // Room for the one addition a packed comparison needs
+const MAX_DOCUMENTS: usize = 10_000; // Documents live in stable memory
+const MAX_SEARCH_RESULTS: usize = 20; // top_k cap for search_similar
+const MAX_MATRIX_DOCUMENTS: usize = 20; // 190 pairs per matrix job
+const MAX_UPLOAD_TOKENS: usize = 10_000; // Chunked uploads; compare them with start_compare_job
+const MAX_INGRESS_BYTES: usize = 2 * 1024 * 1024; // upload_ciphertexts sends everything in one message
+const INGRESS_OVERHEAD_BYTES: usize = 4 * 1024; // doc_id and Candid framing
+const MAX_PENDING_UPLOADS: usize = 100;
+const MAX_RERANDOMIZE: usize = 1_000; // Ciphertexts per call; under 1 MB of reply at 3072 bits
+const UPLOAD_TIMEOUT_NS: u64 = 60 * 60 * 1_000_000_000; // Partial uploads expire after an idle hour
+const UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MAX_THRESHOLD_PARTIES: usize = 16;
+const MAX_DECRYPTION_REQUESTS: usize = 100; // Each holds a document's worth of ciphertexts per party
+const DECRYPTION_RETENTION_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // Requests are kept for a week
+// 90% of the 40B limit of an update message (timers run as updates too). The
+// heavy work only runs in updates; queries just read stable memory.
+const INSTRUCTION_LIMIT_SAFETY: u64 = 36_000_000_000;
+const POOL_REFILL_INTERVAL: Duration = Duration::from_secs(10);
+const POOL_REFILL_BUDGET: u64 = 1_000_000_000; // Keep refills short so calls aren't held up
+const VETKD_FALLBACK_ENABLED: bool = true; // Local keys when the subnet has no vetKD (testing)
 
 // ===== ERROR TYPES =====
 #[derive(CandidType, Deserialize, Debug)]
@@ -21,38 +83,91 @@ pub enum PaillierError {
     NotInitialized,
     AlreadyInitialized,
     KeyGenerationFailed(String),
+    KeyDerivationFailed(String),
+    RandomnessUnavailable(String),
     EncryptionFailed(String),
+    ComparisonFailed(String),
     InvalidTokenSize { expected: usize, got: usize },
     TooManyTokens { provided: usize, max: usize },
+    TokenCountMismatch { doc1: usize, doc2: usize },
     DocumentNotFound(String),
     DocumentCountMismatch,
+    DocumentLimitReached { max: usize },
     InstructionLimitExceeded { used: u64, limit: u64 },
     MemoryLimitExceeded,
+    Unauthorized(String),
+    CallFailed(String),
+    JobNotFound(u64),
+    JobNotFinished { done: u64, total: u64 },
+    DecryptionNotFound(u64),
     InvalidInput(String),
 }
 
+// Text form used by the v1 endpoints' `error` fields
+impl std::fmt::Display for PaillierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaillierError::NotInitialized => write!(f, "Paillier not initialized"),
+            PaillierError::AlreadyInitialized => write!(f, "Already initialized"),
+            PaillierError::KeyGenerationFailed(e) => write!(f, "Key generation failed: {}", e),
+            PaillierError::KeyDerivationFailed(e) => write!(f, "Key derivation failed: {}", e),
+            PaillierError::RandomnessUnavailable(e) => write!(f, "Randomness unavailable: {}", e),
+            PaillierError::EncryptionFailed(e) => write!(f, "Encryption failed: {}", e),
+            PaillierError::ComparisonFailed(e) => write!(f, "Homomorphic comparison failed: {}", e),
+            PaillierError::InvalidTokenSize { expected, got } =>
+                write!(f, "Token has wrong size: {} bytes (expected {})", got, expected),
+            PaillierError::TooManyTokens { provided, max } =>
+                write!(f, "Too many tokens: {} > {}", provided, max),
+            PaillierError::TokenCountMismatch { doc1, doc2 } =>
+                write!(f, "Documents have different token counts: {} vs {}", doc1, doc2),
+            PaillierError::DocumentNotFound(doc_id) => write!(f, "Document '{}' not found", doc_id),
+            PaillierError::DocumentCountMismatch => write!(f, "Document count mismatch"),
+            PaillierError::DocumentLimitReached { max } => write!(f, "Document limit reached: {}", max),
+            PaillierError::InstructionLimitExceeded { used, limit } =>
+                write!(f, "Instruction limit exceeded: {} of {}", used, limit),
+            PaillierError::MemoryLimitExceeded => write!(f, "Memory limit exceeded"),
+            PaillierError::Unauthorized(e) => write!(f, "Unauthorized: {}", e),
+            PaillierError::CallFailed(e) => write!(f, "Inter-canister call failed: {}", e),
+            PaillierError::JobNotFound(job_id) => write!(f, "Job {} not found", job_id),
+            PaillierError::JobNotFinished { done, total } =>
+                write!(f, "Job not finished: {} of {} done", done, total),
+            PaillierError::DecryptionNotFound(request_id) =>
+                write!(f, "Decryption request {} not found", request_id),
+            PaillierError::InvalidInput(e) => write!(f, "Invalid input: {}", e),
+        }
+    }
+}
+
 // ===== STATE MANAGEMENT =====
+// Documents, owner, key configuration and metrics are persisted in stable
+// memory (see storage.rs); the heap only caches what is derived from them.
 thread_local! {
     static STATE: RefCell<CanisterState> = RefCell::new(CanisterState::new());
-    
-    // Performance metrics
-    static METRICS: RefCell<PerformanceMetrics> = RefCell::new(PerformanceMetrics::default());
 }
 
 #[derive(Default)]
 struct CanisterState {
-    paillier: Option<SimplePaillier>,
-    encrypted_docs: Vec<(String, Vec<Vec<u8>>)>, // (doc_id, encrypted_tokens)
-    owner: Option<Principal>, // For access control
+    backend: Option<Backend>, // Re-derived from the key seed on demand, never stored
+    key_config: KeyConfig,
 }
 
-#[derive(Default)]
+// Limits derived from the deployment's scheme and key size
+#[derive(Clone, Copy)]
+struct KeyConfig {
+    scheme: HeScheme,
+    key_bits: usize,
+    token_cost: u64,      // Instructions per encryption
+    comparison_cost: u64, // Instructions per blinded zero test
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
 struct PerformanceMetrics {
     total_operations: u64,
     total_instructions_used: u64,
     encryption_operations: u64,
     comparison_operations: u64,
     failed_operations: u64,
+    stored_tokens: u64, // Running total for memory accounting
 }
 
 impl CanisterState {
@@ -61,6 +176,110 @@ impl CanisterState {
     }
 }
 
+impl Default for KeyConfig {
+    fn default() -> Self {
+        Self::new(HeScheme::Paillier, DEFAULT_KEY_SIZE).expect("default key size is supported")
+    }
+}
+
+impl KeyConfig {
+    // Starts from the estimated costs; see `with_costs`
+    fn new(scheme: HeScheme, key_bits: usize) -> Result<Self, PaillierError> {
+        let token_cost = match scheme {
+            HeScheme::Paillier => PAILLIER_TOKEN_COSTS.iter()
+                .find(|&&(bits, _)| bits == key_bits)
+                .map(|&(_, cost)| cost),
+            HeScheme::ElGamal => (key_bits == ELGAMAL_KEY_BITS).then_some(ELGAMAL_TOKEN_COST),
+        };
+        let supported: Vec<usize> = PAILLIER_TOKEN_COSTS.iter()
+            .filter(|&&(_, cost)| Self::step_fits(cost))
+            .map(|&(bits, _)| bits)
+            .collect();
+        
+        match token_cost {
+            Some(cost) if Self::step_fits(cost) => Ok(Self {
+                scheme,
+                key_bits,
+                token_cost: cost,
+                comparison_cost: 2 * cost,
+            }),
+            Some(cost) => Err(PaillierError::InvalidInput(format!(
+                "{}-bit keys need about {} instructions per token, more than one message allows \
+                 (supported: {:?} for Paillier, {} for ElGamal)",
+                key_bits, cost, supported, ELGAMAL_KEY_BITS))),
+            None => Err(PaillierError::InvalidInput(format!(
+                "Unsupported key size {} for {:?} (supported: {:?} for Paillier, {} for ElGamal)",
+                key_bits, scheme, supported, ELGAMAL_KEY_BITS))),
+        }
+    }
+    
+    // The smallest unit of work (a stage of a split position: an encryption
+    // plus a decryption, at most 1.5 encryptions) must fit one message
+    fn step_fits(token_cost: u64) -> bool {
+        token_cost.saturating_mul(3) / 2 <= INSTRUCTION_LIMIT_SAFETY
+    }
+    
+    // Measured costs (see `measure_costs`)
+    fn with_costs(self, token_cost: u64, comparison_cost: u64) -> Self {
+        Self { token_cost: token_cost.max(1), comparison_cost: comparison_cost.max(1), ..self }
+    }
+    
+    fn default_key_bits(scheme: HeScheme) -> usize {
+        match scheme {
+            HeScheme::Paillier => DEFAULT_KEY_SIZE,
+            HeScheme::ElGamal => ELGAMAL_KEY_BITS,
+        }
+    }
+    
+    // Paillier ciphertexts live in Z_{n^2}, so they are twice the key size
+    fn ciphertext_bytes(&self) -> usize {
+        match self.scheme {
+            HeScheme::Paillier => self.key_bits / 4,
+            HeScheme::ElGamal => elgamal::CIPHERTEXT_BYTES,
+        }
+    }
+    
+    fn instructions_per_token(&self) -> u64 {
+        self.token_cost
+    }
+    
+    fn max_tokens(&self) -> usize {
+        ((INSTRUCTION_LIMIT_SAFETY / self.token_cost) as usize).max(1)
+    }
+    
+    // Tokens between instruction counter checks; `base` is the 512-bit interval
+    fn check_interval(&self, base: usize) -> usize {
+        let baseline = PAILLIER_TOKEN_COSTS[0].1;
+        ((base as u64 * baseline / self.token_cost) as usize).max(1)
+    }
+    
+    fn instructions_per_comparison(&self) -> u64 {
+        self.comparison_cost
+    }
+    
+    // Whether one position's zero test fits a message; if not, positions can
+    // only be compared by a job that splits them (see jobs.rs)
+    fn comparison_fits(&self) -> bool {
+        self.comparison_cost <= INSTRUCTION_LIMIT_SAFETY
+    }
+    
+    // Set overlap tests every token pair in both directions; each test costs
+    // about one encryption. Zero when not even one pair fits.
+    fn max_overlap_pairs(&self) -> usize {
+        (INSTRUCTION_LIMIT_SAFETY / self.instructions_per_token() / 2) as usize
+    }
+    
+    // Fingerprint slots per packed ciphertext; plaintexts stay below n
+    fn packed_layout(&self) -> Result<SlotLayout, PaillierError> {
+        if self.scheme != HeScheme::Paillier {
+            return Err(PaillierError::InvalidInput(
+                "Packed documents are only available with the Paillier scheme".to_string()));
+        }
+        SlotLayout::new(self.key_bits - 1, PACKED_VALUE_BITS, PACKED_GUARD_BITS)
+            .map_err(PaillierError::InvalidInput)
+    }
+}
+
 // ===== API TYPES =====
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct InitResult {
@@ -82,14 +301,133 @@ pub struct EncryptResult {
     pub error: Option<String>,
 }
 
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct PublicKeyInfo {
+    pub n: Vec<u8>, // big-endian
+    pub g: Vec<u8>, // always n + 1
+    pub key_bits: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DecryptionStatus {
+    pub request_id: u64,
+    pub ciphertexts: Vec<Vec<u8>>, // Under the threshold key; what the parties decrypt
+    pub threshold: u32,
+    pub partials_received: u32,
+    pub plaintext: Option<Nat>, // Match count, once enough parties have contributed
+}
+
+// What to rerandomize: part of a stored document, or ciphertexts the caller
+// already holds (e.g. scores from compare_documents)
+#[derive(CandidType, Deserialize)]
+pub enum RerandomizeSource {
+    Document { doc_id: String, start: u64 }, // Up to MAX_RERANDOMIZE ciphertexts from `start`
+    Ciphertexts(Vec<Vec<u8>>),
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct RerandomizeResult {
+    pub ciphertexts: Vec<Vec<u8>>, // Same plaintexts and order, fresh randomness
+    pub next_start: Option<u64>,   // Where the rest of the document starts
+    pub messages_used: u32,
+    pub time_ms: u64,
+    pub instructions_used: u64, // Summed over all messages
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ProvenDecryption {
+    pub value: Nat,
+    pub proof: Vec<u8>, // Check with verify_decryption
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct UploadInfo {
+    pub doc_id: String,
+    pub tokens_received: usize,
+    pub max_chunk_tokens: usize, // Per append_tokens call, derived from the key size
+    pub expires_at: u64, // IC time in nanoseconds; every chunk extends it
+}
+
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct CompareResult {
     pub success: bool,
-    pub similarity_score: Option<Vec<u8>>, // Encrypted result
+    pub similarity_score: Option<Vec<u8>>, // Enc(number of matching token positions); None in threshold mode
+    pub tokens_compared: Option<usize>, // Score is "k of tokens_compared match"
     pub time_ms: u64,
     pub instructions_used: u64,
     pub instruction_percentage: f32, // % of limit used
     pub error: Option<String>,
+    pub decryption_request: Option<u64>, // Threshold mode: where the parties release the count
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct OverlapResult {
+    pub encrypted_intersection: Vec<u8>, // Enc(|A ∩ B|)
+    pub set_size1: usize,
+    pub set_size2: usize,
+    pub time_ms: u64,
+    pub instructions_used: u64,
+    pub instruction_percentage: f32, // % of limit used
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct OverlapScore {
+    pub intersection: u64,
+    pub jaccard: f64, // |A ∩ B| / |A ∪ B|
+}
+
+// A decrypted plaintext read as a signed fixed-point number (see encoding.rs)
+#[derive(CandidType, Deserialize)]
+pub struct DecodedValue {
+    pub value: Int, // Signed, scaled by 10^decimals
+    pub decimals: u32,
+    pub decimal: String, // Exact decimal form, e.g. "-12.345"
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct SearchHit {
+    pub doc_id: String,
+    pub encrypted_score: Vec<u8>, // Enc(number of matching token positions)
+    pub tokens_compared: usize,
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub hits: Vec<SearchHit>, // Best first
+    pub candidates_compared: usize,
+    pub messages_used: u32, // The search yields between candidates to stay under the limit
+    pub time_ms: u64,
+    pub instructions_used: u64, // Summed over all messages
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct JobInfo {
+    pub job_id: u64,
+    pub status: JobStatus,
+    pub done: u64,
+    pub total: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MatrixResult {
+    pub doc_ids: Vec<String>,
+    pub entries: Vec<MatrixEntry>, // Upper triangle, row by row
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CompareJobResult {
+    pub doc_id1: String,
+    pub doc_id2: String,
+    pub encrypted_score: Vec<u8>, // Enc(number of matching token positions)
+    pub tokens_compared: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum JobResult {
+    Matrix(MatrixResult),
+    Compare(CompareJobResult),
 }
 
 #[derive(CandidType, Deserialize, Serialize)]
@@ -102,53 +440,227 @@ pub struct CanisterStats {
     pub comparison_operations: u64,
     pub failed_operations: u64,
     pub owner: Option<String>,
+    pub key_size_bits: u32,
+    pub max_tokens_per_document: usize,
+    pub randomizers_ready: usize,
+}
+
+// v1 failure shapes: `success: false` with the error rendered as text
+impl InitResult {
+    fn failed(error: &PaillierError) -> Self {
+        InitResult {
+            success: false,
+            message: error.to_string(),
+            key_generation_ms: 0,
+            instructions_used: 0,
+            memory_used_kb: get_memory_usage_kb(),
+        }
+    }
+}
+
+impl EncryptResult {
+    fn failed(doc_id: String, error: &PaillierError) -> Self {
+        EncryptResult {
+            success: false,
+            doc_id,
+            tokens_encrypted: 0,
+            time_ms: 0,
+            instructions_used: 0,
+            memory_used_kb: get_memory_usage_kb(),
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+impl CompareResult {
+    fn failed(error: &PaillierError) -> Self {
+        CompareResult {
+            success: false,
+            similarity_score: None,
+            tokens_compared: None,
+            time_ms: 0,
+            instructions_used: 0,
+            instruction_percentage: 0.0,
+            error: Some(error.to_string()),
+            decryption_request: None,
+        }
+    }
 }
 
 // ===== CUSTOM GETRANDOM FOR ICP =====
-// ICP doesn't have system randomness, must implement deterministic version
+// ICP has no system randomness; serve getrandom from the raw_rand-seeded RNG
 fn custom_getrandom(dest: &mut [u8]) -> Result<(), getrandom::Error> {
-    // Use IC time and instruction counter for deterministic randomness
-    // WARNING: This is NOT cryptographically secure - POC only
-    let time_bytes = time().to_be_bytes();
-    let counter_bytes = instruction_counter().to_be_bytes();
-    let caller_bytes = caller().as_slice();
+    // Fails until the first raw_rand seed arrives rather than returning weak bytes
+    rng::fill_bytes(dest).map_err(|_| {
+        getrandom::Error::from(NonZeroU32::new(getrandom::Error::CUSTOM_START).unwrap())
+    })
+}
+
+// Register custom RNG on canister init
+getrandom::register_custom_getrandom!(custom_getrandom);
+
+// ===== HELPER FUNCTIONS =====
+fn get_memory_usage_kb() -> u64 {
+    STATE.with(|state| memory_usage_kb(&state.borrow()))
+}
+
+// Takes the state directly so it can be used while STATE is already borrowed
+fn memory_usage_kb(state: &CanisterState) -> u64 {
+    // Summed from a running counter: iterating stable memory would cost too much.
+    // Uploads in progress count too (their headers carry the byte totals)
+    let stored_tokens = storage::metrics().stored_tokens;
+    (stored_tokens * state.key_config.ciphertext_bytes() as u64 + storage::pending_upload_bytes()) / 1024
+}
+
+fn is_owner(principal: Principal) -> bool {
+    storage::config().owner == Some(principal)
+}
+
+// Check the caller's access to a document, logging denied attempts
+fn check_document_access(doc_id: &str, doc: &StoredDocument, permission: Permission) -> Result<(), PaillierError> {
+    if doc.allows(caller(), permission) {
+        return Ok(());
+    }
+    log_security_event(SecurityEventType::InvalidAccess, format!("{:?} on document '{}'", permission, doc_id));
+    Err(PaillierError::Unauthorized(format!("no {:?} access to document '{}'", permission, doc_id)))
+}
+
+// Only the owner may replace an existing document; returns the current version
+fn check_can_write(doc_id: &str) -> Result<Option<StoredDocument>, PaillierError> {
+    match storage::get_document(doc_id) {
+        Some(doc) if doc.owner() != caller() => {
+            log_security_event(SecurityEventType::InvalidAccess, format!("Overwrite of document '{}'", doc_id));
+            Err(PaillierError::Unauthorized(format!("document '{}' belongs to another principal", doc_id)))
+        }
+        previous => Ok(previous),
+    }
+}
+
+// Packed documents only support compare_documents; everything else works token by token
+fn check_unpacked(doc_id: &str, doc: &StoredDocument) -> Result<(), PaillierError> {
+    if doc.packing.is_some() {
+        return Err(PaillierError::InvalidInput(format!(
+            "Document '{}' is packed and can only be used with compare_documents", doc_id)));
+    }
+    Ok(())
+}
+
+// Fetch a document for an ACL change; only its owner may change the ACL
+fn owned_document(doc_id: &str) -> Result<StoredDocument, PaillierError> {
+    validate_doc_id(doc_id)?;
+    
+    let doc = storage::get_document(doc_id)
+        .ok_or_else(|| PaillierError::DocumentNotFound(doc_id.to_string()))?;
     
-    // Safety check for empty caller (improvement from review)
-    if caller_bytes.is_empty() {
-        return Err(getrandom::Error::CUSTOM_START);
+    if doc.owner() != caller() {
+        log_security_event(SecurityEventType::InvalidAccess, format!("ACL change on document '{}'", doc_id));
+        return Err(PaillierError::Unauthorized(format!("only the owner of '{}' can manage access", doc_id)));
     }
+    Ok(doc)
+}
+
+// Rebuild the heap key config from stable memory (after upgrades). The keypair
+// itself needs an inter-canister call, so it is re-derived lazily by `ensure_keypair`.
+fn restore_key_config() -> Result<(), String> {
+    let config = storage::config();
+    let key_config = match config.key_bits {
+        Some(bits) => KeyConfig::new(config.scheme.unwrap_or_default(), bits as usize)
+            .map_err(|e| format!("{:?}", e))?,
+        None => KeyConfig::default(),
+    };
+    // Deployments initialized before costs were measured keep the estimates
+    let key_config = match (config.token_cost, config.comparison_cost) {
+        (Some(token_cost), Some(comparison_cost)) => key_config.with_costs(token_cost, comparison_cost),
+        _ => key_config,
+    };
     
-    for (i, byte) in dest.iter_mut().enumerate() {
-        *byte = time_bytes[i % 8] 
-            ^ counter_bytes[i % 8] 
-            ^ caller_bytes[i % caller_bytes.len()]
-            ^ (i as u8);
+    STATE.with(|state| state.borrow_mut().key_config = key_config);
+    Ok(())
+}
+
+// Fetch the seed the keypair is derived from. The key size is part of the
+// derivation path, so ElGamal (256) and Paillier seeds never coincide.
+async fn paillier_seed(key_bits: usize, derivation: &KeyDerivation) -> Result<Vec<u8>, String> {
+    match derivation {
+        KeyDerivation::VetKd => {
+            let manager = VetKeyManager::new(VETKD_FALLBACK_ENABLED).await?;
+            manager.derive_paillier_seed(key_bits).await
+        }
+        KeyDerivation::Fallback { seed } => Ok(seed.clone()),
+    }
+}
+
+// Make sure the keypair is in the heap cache, re-deriving it if needed.
+// Note: may await, which resets instruction_counter().
+async fn ensure_keypair() -> Result<(), PaillierError> {
+    if STATE.with(|s| s.borrow().backend.is_some()) {
+        return Ok(());
     }
     
+    let config = storage::config();
+    let (key_bits, derivation) = match (config.key_bits, config.key_derivation) {
+        (Some(bits), Some(derivation)) => (bits as usize, derivation),
+        _ => return Err(PaillierError::NotInitialized),
+    };
+    let key_config = KeyConfig::new(config.scheme.unwrap_or_default(), key_bits)?;
+    
+    let seed = paillier_seed(key_bits, &derivation).await
+        .map_err(PaillierError::KeyDerivationFailed)?;
+    let backend = derive_backend(key_config, &seed)
+        .map_err(PaillierError::KeyGenerationFailed)?;
+    ic_cdk::println!("Re-derived {}-bit {:?} keypair", key_bits, key_config.scheme);
+    
+    STATE.with(|s| {
+        s.borrow_mut().backend.get_or_insert(backend);
+    });
     Ok(())
 }
 
-// Register custom RNG on canister init
-getrandom::register_custom_getrandom!(custom_getrandom);
+fn derive_backend(key_config: KeyConfig, seed: &[u8]) -> Result<Backend, String> {
+    match key_config.scheme {
+        HeScheme::Paillier => SimplePaillier::keygen(seed, key_config.key_bits).map(Backend::Paillier),
+        HeScheme::ElGamal => ExpElGamal::keygen(seed, key_config.key_bits).map(Backend::ElGamal),
+    }
+}
 
-// ===== HELPER FUNCTIONS =====
-fn get_memory_usage_kb() -> u64 {
-    // Improved memory estimation including BigUint allocations
-    STATE.with(|state| {
-        let state = state.borrow();
-        let doc_memory: usize = state.encrypted_docs.iter()
-            .map(|(id, tokens)| {
-                // Each encrypted token is ~256 bytes for 512-bit keys
-                id.len() + tokens.len() * 256
-            })
-            .sum();
-        (doc_memory / 1024) as u64
-    })
+// Instructions for one encryption and one blinded zero test with a freshly
+// derived keypair. Its randomizer pool is still empty, so the encryption
+// includes the r^n modpow.
+fn measure_costs(backend: &Backend, key_config: KeyConfig) -> Result<(u64, u64), PaillierError> {
+    let start = instruction_counter();
+    let a = backend.encrypt(&[1]).map_err(PaillierError::EncryptionFailed)?;
+    let token_cost = instruction_counter() - start;
+    
+    // Another encryption and a zero test (about two more) may not fit what
+    // is left of this message with large keys; estimate from the encryption
+    if instruction_counter() + 3 * token_cost > INSTRUCTION_LIMIT_SAFETY {
+        return Ok((token_cost, 2 * token_cost));
+    }
+    
+    let b = backend.encrypt(&[2]).map_err(PaillierError::EncryptionFailed)?;
+    let start = instruction_counter();
+    count_matching_tokens(backend, &[a], &[b], key_config.with_costs(token_cost, 2 * token_cost))?;
+    let comparison_cost = instruction_counter() - start;
+    
+    Ok((token_cost, comparison_cost))
+}
+
+// The keypair, for code that works with either scheme
+fn backend(state: &CanisterState) -> Result<&Backend, PaillierError> {
+    state.backend.as_ref().ok_or(PaillierError::NotInitialized)
 }
 
-fn check_instruction_limit() -> Result<(), PaillierError> {
+// Range proofs, decryption proofs and client-side encryption exist for Paillier only
+fn paillier_backend(state: &CanisterState) -> Result<&SimplePaillier, PaillierError> {
+    backend(state)?.paillier().ok_or_else(|| PaillierError::InvalidInput(
+        "Only available when initialized with the Paillier scheme".to_string()))
+}
+
+// Fails unless `next` more instructions (the work until the next check) fit.
+// With large keys one step is more than the headroom above the safety limit.
+fn check_instruction_limit(next: u64) -> Result<(), PaillierError> {
     let used = instruction_counter();
-    if used > INSTRUCTION_LIMIT_SAFETY {
+    if used + next > INSTRUCTION_LIMIT_SAFETY {
         Err(PaillierError::InstructionLimitExceeded { 
             used, 
             limit: INSTRUCTION_LIMIT_SAFETY 
@@ -159,6 +671,32 @@ fn check_instruction_limit() -> Result<(), PaillierError> {
 }
 
 // Input validation for document IDs (improvement from review)
+// End the current message by calling ourselves, so long operations can
+// continue with a fresh instruction_counter(). State is committed at this point
+// and other messages may run before we resume.
+async fn yield_message() -> Result<(), PaillierError> {
+    ic_cdk::call::<_, ()>(ic_cdk::id(), "yield_noop", ())
+        .await
+        .map_err(|(code, msg)| PaillierError::CallFailed(format!("{:?} - {}", code, msg)))
+}
+
+fn caller_is_self() -> Result<(), String> {
+    if caller() == ic_cdk::id() {
+        Ok(())
+    } else {
+        Err("Only the canister itself can call this".to_string())
+    }
+}
+
+// Count failed operations the same way for both API versions
+fn track_failure<T>(result: Result<T, PaillierError>) -> Result<T, PaillierError> {
+    if let Err(e) = &result {
+        ic_cdk::println!("Error: {}", e);
+        storage::update_metrics(|m| m.failed_operations += 1);
+    }
+    result
+}
+
 fn validate_doc_id(doc_id: &str) -> Result<(), PaillierError> {
     if doc_id.is_empty() {
         return Err(PaillierError::InvalidInput("Doc ID cannot be empty".into()));
@@ -177,471 +715,2147 @@ fn validate_doc_id(doc_id: &str) -> Result<(), PaillierError> {
 fn init() {
     ic_cdk::println!("Paillier POC Canister initialized");
     ic_cdk::println!("Version: 0.1.0");
-    ic_cdk::println!("Max tokens per document: {}", MAX_TOKENS);
+    ic_cdk::println!("Max tokens per document: {} (512-bit keys)", KeyConfig::default().max_tokens());
     
     // Set owner to deployer
-    STATE.with(|state| {
-        state.borrow_mut().owner = Some(caller());
-    });
+    storage::update_config(|config| config.owner = Some(caller()));
+    
+    // Seed the RNG from raw_rand as soon as possible, then periodically
+    rng::schedule_seeding();
+    schedule_pool_refill();
+    schedule_upload_sweep();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    // In production, would serialize state to stable memory
-    ic_cdk::println!("Pre-upgrade: Would save state to stable memory");
+    // Stable structures are written through, nothing to serialize
+    ic_cdk::println!("Pre-upgrade: {} documents already in stable memory", storage::document_count());
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    // In production, would deserialize state from stable memory
-    ic_cdk::println!("Post-upgrade: Would restore state from stable memory");
-    init();
+    // Owner, documents and metrics are read from stable memory as-is; the
+    // keypair is re-derived on first use. Timers do not survive upgrades.
+    if let Err(e) = restore_key_config() {
+        ic_cdk::println!("Error: failed to restore key config: {}", e);
+    }
+    rng::schedule_seeding();
+    schedule_pool_refill();
+    schedule_upload_sweep();
+    jobs::schedule();
+    
+    ic_cdk::println!("Post-upgrade: restored {} documents", storage::document_count());
 }
 
-// ===== UPDATE METHODS =====
-#[update]
-fn initialize_paillier() -> InitResult {
-    let start_time = time() / 1_000_000; // Convert to ms
-    let start_instructions = instruction_counter();
-    
+// ===== RANDOMIZER POOL =====
+// Precompute r^n between calls so encryptions only multiply (see pool.rs)
+fn schedule_pool_refill() {
+    ic_cdk_timers::set_timer_interval(POOL_REFILL_INTERVAL, refill_pool);
+}
+
+fn refill_pool() {
     STATE.with(|state| {
-        let mut state = state.borrow_mut();
+        let state = state.borrow();
         
-        // Check if already initialized
-        if state.paillier.is_some() {
-            return InitResult {
-                success: false,
-                message: "Already initialized".to_string(),
-                key_generation_ms: 0,
-                instructions_used: 0,
-                memory_used_kb: get_memory_usage_kb(),
-            };
+        // Nothing to do until the keypair is derived, or with ElGamal. The
+        // randomizers are for whichever key documents are encrypted under.
+        if state.backend.as_ref().and_then(Backend::paillier).is_none() {
+            return;
         }
+        let Ok(pk) = document_key(&state) else { return };
         
-        // Generate keypair with error handling
-        ic_cdk::println!("Generating {}-bit keypair...", KEY_SIZE);
-        
-        match std::panic::catch_unwind(|| SimplePaillier::new(KEY_SIZE)) {
-            Ok(paillier) => {
-                state.paillier = Some(paillier);
-                
-                let end_time = time() / 1_000_000;
-                let instructions_used = instruction_counter() - start_instructions;
-                
-                METRICS.with(|metrics| {
-                    let mut m = metrics.borrow_mut();
-                    m.total_operations += 1;
-                    m.total_instructions_used += instructions_used;
-                });
-                
-                ic_cdk::println!("Key generation successful");
-                
-                InitResult {
-                    success: true,
-                    message: format!("Paillier initialized with {}-bit keys", KEY_SIZE),
-                    key_generation_ms: end_time - start_time,
-                    instructions_used,
-                    memory_used_kb: get_memory_usage_kb(),
-                }
-            }
-            Err(e) => {
-                let error_msg = format!("Key generation panic: {:?}", e);
-                ic_cdk::println!("Error: {}", error_msg);
-                
-                METRICS.with(|metrics| {
-                    metrics.borrow_mut().failed_operations += 1;
-                });
-                
-                InitResult {
-                    success: false,
-                    message: error_msg,
-                    key_generation_ms: 0,
-                    instructions_used: instruction_counter() - start_instructions,
-                    memory_used_kb: get_memory_usage_kb(),
-                }
-            }
+        // Only while a randomizer is cheap enough for a short refill (not at 2048+ bits)
+        let per_randomizer = state.key_config.instructions_per_token();
+        match pool::refill(&pk, || instruction_counter() + per_randomizer <= POOL_REFILL_BUDGET) {
+            Ok(0) => {}
+            Ok(added) => ic_cdk::println!("Randomizer pool: {} added, {} ready", added, pool::available(&pk)),
+            Err(e) => ic_cdk::println!("Error: randomizer pool refill failed: {}", e),
         }
-    })
+    });
 }
 
+// ===== UPDATE METHODS =====
+// v1 endpoints report failures as `success: false` plus an error string; the
+// v2 endpoints return the same results with a typed `PaillierError`.
+
+// `scheme` defaults to Paillier, so existing callers can omit it
 #[update]
-fn encrypt_document(doc_id: String, tokens: Vec<Vec<u8>>) -> EncryptResult {
-    let start_time = time() / 1_000_000;
-    let start_instructions = instruction_counter();
+async fn initialize_paillier(key_size: Option<u32>, scheme: Option<HeScheme>) -> InitResult {
+    track_failure(initialize(key_size, scheme).await).unwrap_or_else(|e| InitResult::failed(&e))
+}
+
+#[update]
+async fn initialize_paillier_v2(key_size: Option<u32>, scheme: Option<HeScheme>) -> Result<InitResult, PaillierError> {
+    track_failure(initialize(key_size, scheme).await)
+}
+
+#[update]
+async fn encrypt_document(doc_id: String, tokens: Vec<Vec<u8>>) -> EncryptResult {
+    track_failure(encrypt(doc_id.clone(), tokens, false).await)
+        .unwrap_or_else(|e| EncryptResult::failed(doc_id, &e))
+}
+
+#[update]
+async fn encrypt_document_v2(doc_id: String, tokens: Vec<Vec<u8>>) -> Result<EncryptResult, PaillierError> {
+    track_failure(encrypt(doc_id, tokens, false).await)
+}
+
+// Packs 16-bit token fingerprints many to a ciphertext (30 at 512 bits), so
+// documents are that much cheaper to store and compare. Packed documents only
+// work with compare_documents, and a comparison shows the key holder each
+// position's fingerprint difference instead of a shuffled zero test.
+#[update]
+async fn encrypt_document_packed(doc_id: String, tokens: Vec<Vec<u8>>) -> Result<EncryptResult, PaillierError> {
+    track_failure(encrypt(doc_id, tokens, true).await)
+}
+
+#[update]
+async fn compare_documents(doc_id1: String, doc_id2: String) -> CompareResult {
+    track_failure(compare(&doc_id1, &doc_id2).await).unwrap_or_else(|e| CompareResult::failed(&e))
+}
+
+#[update]
+async fn compare_documents_v2(doc_id1: String, doc_id2: String) -> Result<CompareResult, PaillierError> {
+    track_failure(compare(&doc_id1, &doc_id2).await)
+}
+
+async fn initialize(key_size: Option<u32>, scheme: Option<HeScheme>) -> Result<InitResult, PaillierError> {
+    // Key generation needs the CSPRNG; seed it now if the init timer hasn't yet
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
     
-    // Input validation with improved function
-    if let Err(e) = validate_doc_id(&doc_id) {
-        return EncryptResult {
-            success: false,
-            doc_id,
-            tokens_encrypted: 0,
-            time_ms: 0,
-            instructions_used: instruction_counter() - start_instructions,
-            memory_used_kb: get_memory_usage_kb(),
-            error: Some(format!("Invalid doc ID: {:?}", e)),
-        };
-    }
+    let start_time = time() / 1_000_000; // Convert to ms
     
-    if tokens.len() > MAX_TOKENS {
-        return EncryptResult {
-            success: false,
-            doc_id,
-            tokens_encrypted: 0,
-            time_ms: 0,
-            instructions_used: instruction_counter() - start_instructions,
-            memory_used_kb: get_memory_usage_kb(),
-            error: Some(format!("Too many tokens: {} > {}", tokens.len(), MAX_TOKENS)),
-        };
+    let scheme = scheme.unwrap_or_default();
+    let key_bits = key_size.map(|k| k as usize).unwrap_or(KeyConfig::default_key_bits(scheme));
+    let key_config = KeyConfig::new(scheme, key_bits)?;
+    
+    // Check if already initialized
+    if storage::config().key_bits.is_some() {
+        return Err(PaillierError::AlreadyInitialized);
     }
     
-    // Validate token sizes
-    for (i, token) in tokens.iter().enumerate() {
-        if token.len() != TOKEN_SIZE {
-            return EncryptResult {
-                success: false,
-                doc_id,
-                tokens_encrypted: 0,
-                time_ms: 0,
-                instructions_used: instruction_counter() - start_instructions,
-                memory_used_kb: get_memory_usage_kb(),
-                error: Some(format!("Token {} has wrong size: {} bytes (expected {})", 
-                    i, token.len(), TOKEN_SIZE)),
-            };
+    // Seed from vetKD so the key can be re-derived instead of stored; without
+    // vetKD (testing) a random seed is generated and kept in stable memory
+    let (derivation, seed) = match paillier_seed(key_config.key_bits, &KeyDerivation::VetKd).await {
+        Ok(seed) => (KeyDerivation::VetKd, seed),
+        Err(e) if VETKD_FALLBACK_ENABLED => {
+            ic_cdk::println!("Warning: vetKD unavailable ({}), using a stored random seed", e);
+            log_security_event(SecurityEventType::FallbackUsed, "Key seed".to_string());
+            
+            let mut seed = vec![0u8; simple_paillier::MIN_SEED_BYTES];
+            rng::fill_bytes(&mut seed).map_err(PaillierError::RandomnessUnavailable)?;
+            (KeyDerivation::Fallback { seed: seed.clone() }, seed)
         }
+        Err(e) => return Err(PaillierError::KeyDerivationFailed(e)),
+    };
+    
+    // Counted from here: the inter-canister call reset instruction_counter()
+    let start_instructions = instruction_counter();
+    
+    // A concurrent call may have finished while we were awaiting
+    if storage::config().key_bits.is_some() {
+        return Err(PaillierError::AlreadyInitialized);
     }
     
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        
-        // Check document limit
-        if state.encrypted_docs.len() >= MAX_DOCUMENTS {
-            return EncryptResult {
-                success: false,
-                doc_id,
-                tokens_encrypted: 0,
-                time_ms: 0,
-                instructions_used: instruction_counter() - start_instructions,
-                memory_used_kb: get_memory_usage_kb(),
-                error: Some(format!("Document limit reached: {}", MAX_DOCUMENTS)),
-            };
-        }
-        
-        // Check if initialized
-        let paillier = match &state.paillier {
-            Some(p) => p,
-            None => {
-                METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                return EncryptResult {
-                    success: false,
-                    doc_id,
-                    tokens_encrypted: 0,
-                    time_ms: 0,
-                    instructions_used: instruction_counter() - start_instructions,
-                    memory_used_kb: get_memory_usage_kb(),
-                    error: Some("Paillier not initialized".to_string()),
-                }
-            }
-        };
+    // Generate keypair with error handling
+    ic_cdk::println!("Deriving {}-bit {:?} keypair...", key_config.key_bits, key_config.scheme);
+    
+    let backend = std::panic::catch_unwind(|| derive_backend(key_config, &seed))
+        .unwrap_or_else(|e| Err(format!("Key generation panic: {:?}", e)))
+        .map_err(PaillierError::KeyGenerationFailed)?;
+    
+    // Size token limits from what this replica actually charges
+    let (token_cost, comparison_cost) = measure_costs(&backend, key_config)?;
+    if !KeyConfig::step_fits(token_cost) {
+        return Err(PaillierError::InvalidInput(format!(
+            "{}-bit keys need {} instructions per encryption, more than one message allows",
+            key_config.key_bits, token_cost)));
+    }
+    let key_config = key_config.with_costs(token_cost, comparison_cost);
+    ic_cdk::println!("Measured {} instructions per encryption, {} per comparison", token_cost, comparison_cost);
+    
+    storage::update_config(|config| {
+        config.key_bits = Some(key_config.key_bits as u32);
+        config.scheme = Some(key_config.scheme);
+        config.key_derivation = Some(derivation);
+        config.token_cost = Some(token_cost);
+        config.comparison_cost = Some(comparison_cost);
+    });
+    
+    let memory_used_kb = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.backend = Some(backend);
+        state.key_config = key_config;
+        memory_usage_kb(&state)
+    });
+    
+    let end_time = time() / 1_000_000;
+    let instructions_used = instruction_counter() - start_instructions;
+    
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.total_instructions_used += instructions_used;
+    });
+    
+    ic_cdk::println!("Key generation successful");
+    
+    Ok(InitResult {
+        success: true,
+        message: format!("{:?} initialized with {}-bit keys (max {} tokens per document)",
+            key_config.scheme, key_config.key_bits, key_config.max_tokens()),
+        key_generation_ms: end_time - start_time,
+        instructions_used,
+        memory_used_kb,
+    })
+}
+
+async fn encrypt(doc_id: String, tokens: Vec<Vec<u8>>, packed: bool) -> Result<EncryptResult, PaillierError> {
+    // Encryption randomness comes from the CSPRNG
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
+    
+    let start_time = time() / 1_000_000;
+    
+    // Input validation with improved function
+    validate_doc_id(&doc_id)?;
+    
+    let key_config = STATE.with(|s| s.borrow().key_config);
+    if packed {
+        check_not_threshold("Packed documents")?;
+    }
+    let layout = if packed { Some(key_config.packed_layout()?) } else { None };
+    // A packed document costs one encryption per ciphertext
+    let max_tokens = key_config.max_tokens() * layout.map_or(1, |l| l.slots);
+    
+    validate_tokens(&tokens, max_tokens)?;
+    
+    // Reject overwrites of other principals' documents before any key work
+    check_can_write(&doc_id)?;
+    
+    // Re-derive the keypair if it isn't cached (first call after an upgrade)
+    ensure_keypair().await?;
+    
+    // ensure_keypair may have awaited, which reset instruction_counter()
+    let start_instructions = instruction_counter();
+    
+    STATE.with(|state| {
+        let state = state.borrow();
         
-        // Encrypt tokens with instruction monitoring
-        let mut encrypted_tokens = Vec::with_capacity(tokens.len());
-        
-        for (i, token) in tokens.iter().enumerate() {
-            // Check instruction limit every 5 tokens
-            if i % 5 == 0 {
-                if let Err(e) = check_instruction_limit() {
-                    METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                    return EncryptResult {
-                        success: false,
-                        doc_id,
-                        tokens_encrypted: i,
-                        time_ms: (time() / 1_000_000) - start_time,
-                        instructions_used: instruction_counter() - start_instructions,
-                        memory_used_kb: get_memory_usage_kb(),
-                        error: Some(format!("Instruction limit exceeded at token {}: {:?}", i, e)),
-                    };
-                }
-            }
-            
-            match paillier.encrypt(token) {
-                Ok(encrypted) => {
-                    encrypted_tokens.push(encrypted.to_bytes_be());
-                }
-                Err(e) => {
-                    METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                    return EncryptResult {
-                        success: false,
-                        doc_id,
-                        tokens_encrypted: i,
-                        time_ms: (time() / 1_000_000) - start_time,
-                        instructions_used: instruction_counter() - start_instructions,
-                        memory_used_kb: get_memory_usage_kb(),
-                        error: Some(format!("Encryption failed at token {}: {}", i, e)),
-                    }
-                }
-            }
+        // Check ownership again: the doc_id may have been claimed while we awaited
+        let previous = check_can_write(&doc_id)?;
+        
+        // Check document limit
+        if previous.is_none() && storage::document_count() >= MAX_DOCUMENTS {
+            return Err(PaillierError::DocumentLimitReached { max: MAX_DOCUMENTS });
         }
         
-        // Store encrypted document (replace if exists)
-        state.encrypted_docs.retain(|(id, _)| id != &doc_id);
-        state.encrypted_docs.push((doc_id.clone(), encrypted_tokens));
+        // Check if initialized
+        let backend = backend(&state)?;
+        
+        let encrypted_tokens = match layout {
+            Some(layout) => encrypt_packed(paillier_backend(&state)?, &tokens, layout, key_config)?,
+            None => encrypt_tokens(backend, &tokens, key_config)?,
+        };
+        let packing = layout.map(|l| PackedLayout {
+            tokens: tokens.len() as u64,
+            value_bits: l.value_bits as u32,
+            guard_bits: (l.slot_bits - l.value_bits) as u32,
+        });
+        
+        // Store encrypted document (replace if exists, keeping its grants);
+        // stored_tokens counts ciphertexts, packed or not
+        let new_tokens = encrypted_tokens.len() as u64;
+        let replaced_tokens = previous.as_ref().map_or(0, |doc| doc.tokens.len() as u64);
+        storage::insert_document(doc_id.clone(), StoredDocument {
+            tokens: encrypted_tokens,
+            owner: caller(),
+            acl: previous.and_then(|doc| doc.acl),
+            packing,
+        });
         
         let end_time = time() / 1_000_000;
         let total_instructions = instruction_counter() - start_instructions;
         
-        METRICS.with(|metrics| {
-            let mut m = metrics.borrow_mut();
+        storage::update_metrics(|m| {
             m.total_operations += 1;
             m.encryption_operations += 1;
             m.total_instructions_used += total_instructions;
+            m.stored_tokens = m.stored_tokens + new_tokens - replaced_tokens;
         });
         
         ic_cdk::println!("Encrypted {} tokens for document '{}'", tokens.len(), doc_id);
         
-        EncryptResult {
+        Ok(EncryptResult {
             success: true,
             doc_id,
             tokens_encrypted: tokens.len(),
             time_ms: end_time - start_time,
             instructions_used: total_instructions,
-            memory_used_kb: get_memory_usage_kb(),
+            memory_used_kb: memory_usage_kb(&state),
             error: None,
-        }
+        })
     })
 }
 
-#[update]
-fn compare_documents(doc_id1: String, doc_id2: String) -> CompareResult {
-    let start_time = time() / 1_000_000;
-    let start_instructions = instruction_counter();
+fn validate_tokens(tokens: &[Vec<u8>], max_tokens: usize) -> Result<(), PaillierError> {
+    if tokens.len() > max_tokens {
+        return Err(PaillierError::TooManyTokens { provided: tokens.len(), max: max_tokens });
+    }
     
-    // Validate both document IDs
-    if let Err(e) = validate_doc_id(&doc_id1) {
-        return CompareResult {
-            success: false,
-            similarity_score: None,
-            time_ms: 0,
-            instructions_used: instruction_counter() - start_instructions,
-            instruction_percentage: 0.0,
-            error: Some(format!("Invalid doc_id1: {:?}", e)),
-        };
+    // Validate token sizes
+    if let Some(token) = tokens.iter().find(|token| token.len() != TOKEN_SIZE) {
+        return Err(PaillierError::InvalidTokenSize { expected: TOKEN_SIZE, got: token.len() });
+    }
+    Ok(())
+}
+
+// Encrypt document tokens: under the threshold key once one is configured,
+// so that not even the canister can decrypt them, else under the canister key
+fn encrypt_tokens(backend: &Backend, tokens: &[Vec<u8>], key_config: KeyConfig) -> Result<Vec<Vec<u8>>, PaillierError> {
+    match threshold_key()? {
+        Some(key) => encrypt_each(&key, tokens, key_config),
+        None => dispatch!(backend, he => encrypt_each(he, tokens, key_config)),
     }
+}
+
+// Encrypt tokens with instruction monitoring
+fn encrypt_each<H: AdditiveHomomorphic>(he: &H, tokens: &[Vec<u8>], key_config: KeyConfig) -> Result<Vec<Vec<u8>>, PaillierError> {
+    let mut encrypted_tokens = Vec::with_capacity(tokens.len());
+    let check_interval = key_config.check_interval(5);
     
-    if let Err(e) = validate_doc_id(&doc_id2) {
-        return CompareResult {
-            success: false,
-            similarity_score: None,
-            time_ms: 0,
-            instructions_used: instruction_counter() - start_instructions,
-            instruction_percentage: 0.0,
-            error: Some(format!("Invalid doc_id2: {:?}", e)),
-        };
+    for (i, token) in tokens.iter().enumerate() {
+        // Check instruction limit every 5 tokens (more often for larger keys)
+        if i % check_interval == 0 {
+            check_instruction_limit(check_interval as u64 * key_config.instructions_per_token())?;
+        }
+        
+        let encrypted = he.encrypt(token)
+            .map_err(|e| PaillierError::EncryptionFailed(format!("token {}: {}", i, e)))?;
+        encrypted_tokens.push(he.serialize(&encrypted));
     }
+    Ok(encrypted_tokens)
+}
+
+// Fingerprint the tokens and encrypt them `layout.slots` to a ciphertext
+fn encrypt_packed(
+    paillier: &SimplePaillier,
+    tokens: &[Vec<u8>],
+    layout: SlotLayout,
+    key_config: KeyConfig,
+) -> Result<Vec<Vec<u8>>, PaillierError> {
+    let fingerprints: Vec<u64> = tokens.iter()
+        .map(|token| packing::fingerprint(token, layout.value_bits))
+        .collect();
+    
+    fingerprints.chunks(layout.slots)
+        .enumerate()
+        .map(|(i, chunk)| {
+            // Each ciphertext costs one encryption
+            check_instruction_limit(key_config.instructions_per_token())?;
+            layout.encrypt(paillier.public_key(), chunk)
+                .map(|packed| packed.c.to_bytes_be())
+                .map_err(|e| PaillierError::EncryptionFailed(format!("ciphertext {}: {}", i, e)))
+        })
+        .collect()
+}
+
+async fn compare(doc_id1: &str, doc_id2: &str) -> Result<CompareResult, PaillierError> {
+    let start_time = time() / 1_000_000;
+    
+    // Validate both document IDs
+    validate_doc_id(doc_id1)?;
+    validate_doc_id(doc_id2)?;
+    
+    // Blinding draws from the CSPRNG; seed it now if the init timer hasn't yet
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
+    ensure_keypair().await?;
+    
+    // Read after the await: re-deriving the keypair may have reset instruction_counter()
+    let start_instructions = instruction_counter();
     
     STATE.with(|state| {
         let state = state.borrow();
         
         // Check if initialized
-        let paillier = match &state.paillier {
-            Some(p) => p,
-            None => {
-                METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                return CompareResult {
-                    success: false,
-                    similarity_score: None,
-                    time_ms: 0,
-                    instructions_used: instruction_counter() - start_instructions,
-                    instruction_percentage: 0.0,
-                    error: Some("Paillier not initialized".to_string()),
-                }
-            }
-        };
+        let backend = backend(&state)?;
         
         // Find documents
-        let doc1 = state.encrypted_docs.iter()
-            .find(|(id, _)| id == &doc_id1)
-            .map(|(_, tokens)| tokens);
-        let doc2 = state.encrypted_docs.iter()
-            .find(|(id, _)| id == &doc_id2)
-            .map(|(_, tokens)| tokens);
-        
-        match (doc1, doc2) {
-            (Some(tokens1), Some(tokens2)) => {
-                if tokens1.len() != tokens2.len() {
-                    METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                    return CompareResult {
-                        success: false,
-                        similarity_score: None,
-                        time_ms: 0,
-                        instructions_used: instruction_counter() - start_instructions,
-                        instruction_percentage: 0.0,
-                        error: Some(format!("Documents have different token counts: {} vs {}", 
-                            tokens1.len(), tokens2.len())),
-                    };
-                }
-                
-                ic_cdk::println!("Comparing {} tokens between '{}' and '{}'", 
-                    tokens1.len(), doc_id1, doc_id2);
-                
-                // Perform homomorphic comparison
-                let mut accumulated_diff = None;
-                
-                for (i, (enc1_bytes, enc2_bytes)) in tokens1.iter().zip(tokens2.iter()).enumerate() {
-                    // Check instructions every 3 tokens
-                    if i % 3 == 0 {
-                        if let Err(e) = check_instruction_limit() {
-                            METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                            let used = instruction_counter() - start_instructions;
-                            return CompareResult {
-                                success: false,
-                                similarity_score: None,
-                                time_ms: (time() / 1_000_000) - start_time,
-                                instructions_used: used,
-                                instruction_percentage: (used as f32 / INSTRUCTION_LIMIT_SAFETY as f32) * 100.0,
-                                error: Some(format!("Instruction limit exceeded at token {}: {:?}", i, e)),
-                            };
-                        }
-                    }
-                    
-                    // Convert back to BigUint
-                    let enc1 = BigUint::from_bytes_be(enc1_bytes);
-                    let enc2 = BigUint::from_bytes_be(enc2_bytes);
-                    
-                    // Add encrypted values (represents difference in our simplified scheme)
-                    let diff = paillier.add(&enc1, &enc2);
-                    
-                    // Accumulate differences
-                    accumulated_diff = Some(match accumulated_diff {
-                        None => diff,
-                        Some(acc) => paillier.add(&acc, &diff),
-                    });
-                }
-                
-                let end_time = time() / 1_000_000;
-                let total_instructions = instruction_counter() - start_instructions;
-                let instruction_percentage = (total_instructions as f32 / INSTRUCTION_LIMIT_SAFETY as f32) * 100.0;
-                
-                METRICS.with(|metrics| {
-                    let mut m = metrics.borrow_mut();
-                    m.total_operations += 1;
-                    m.comparison_operations += 1;
-                    m.total_instructions_used += total_instructions;
-                });
-                
-                ic_cdk::println!("Comparison completed in {}ms using {}% of instruction limit", 
-                    end_time - start_time, instruction_percentage);
-                
-                CompareResult {
-                    success: true,
-                    similarity_score: accumulated_diff.map(|d| d.to_bytes_be()),
-                    time_ms: end_time - start_time,
-                    instructions_used: total_instructions,
-                    instruction_percentage,
-                    error: None,
-                }
-            }
-            (None, _) => {
-                METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                CompareResult {
-                    success: false,
-                    similarity_score: None,
-                    time_ms: 0,
-                    instructions_used: instruction_counter() - start_instructions,
-                    instruction_percentage: 0.0,
-                    error: Some(format!("Document '{}' not found", doc_id1)),
-                }
+        let doc1 = storage::get_document(doc_id1)
+            .ok_or_else(|| PaillierError::DocumentNotFound(doc_id1.to_string()))?;
+        let doc2 = storage::get_document(doc_id2)
+            .ok_or_else(|| PaillierError::DocumentNotFound(doc_id2.to_string()))?;
+        
+        // The caller needs Compare access to both documents
+        check_document_access(doc_id1, &doc1, Permission::Compare)?;
+        check_document_access(doc_id2, &doc2, Permission::Compare)?;
+        let tokens = doc1.token_count();
+        
+        if tokens != doc2.token_count() {
+            return Err(PaillierError::TokenCountMismatch { doc1: tokens, doc2: doc2.token_count() });
+        }
+        if doc1.packing != doc2.packing {
+            return Err(PaillierError::InvalidInput(format!(
+                "'{}' and '{}' must both be packed the same way or both unpacked", doc_id1, doc_id2)));
+        }
+        
+        // Fail fast rather than run out of instructions halfway. A packed
+        // ciphertext costs about one encryption (a decryption, no blinding)
+        let per_ciphertext = match doc1.packing {
+            Some(_) => state.key_config.instructions_per_token(),
+            None => state.key_config.instructions_per_comparison(),
+        };
+        let threshold = threshold_key()?;
+        if doc1.tokens.len() as u64 * per_ciphertext > INSTRUCTION_LIMIT_SAFETY {
+            // Jobs are unavailable in threshold mode
+            let hint = if threshold.is_some() { "" } else { ", use start_compare_job" };
+            return Err(PaillierError::InvalidInput(format!(
+                "Documents too long to compare in one call ({} tokens){}", tokens, hint)));
+        }
+        
+        ic_cdk::println!("Comparing {} tokens between '{}' and '{}'", 
+            tokens, doc_id1, doc_id2);
+        
+        // With a threshold key the canister can't count the matches itself: the
+        // blinded differences go to the parties (see THRESHOLD DECRYPTION)
+        let (encrypted_matches, decryption_request) = match threshold {
+            Some(key) => {
+                let differences = threshold_differences(&key, &doc1.tokens, &doc2.tokens, state.key_config)?;
+                (None, Some(open_decryption(differences)?))
             }
-            (_, None) => {
-                METRICS.with(|m| m.borrow_mut().failed_operations += 1);
-                CompareResult {
-                    success: false,
-                    similarity_score: None,
-                    time_ms: 0,
-                    instructions_used: instruction_counter() - start_instructions,
-                    instruction_percentage: 0.0,
-                    error: Some(format!("Document '{}' not found", doc_id2)),
-                }
+            None => {
+                let matches = match doc1.packing {
+                    Some(packing) => count_packed_matches(
+                        paillier_backend(&state)?, packing, &doc1.tokens, &doc2.tokens, state.key_config)?,
+                    None => count_matching_tokens(backend, &doc1.tokens, &doc2.tokens, state.key_config)?,
+                };
+                let encrypted_matches = backend.encrypt(&matches.to_be_bytes())
+                    .map_err(PaillierError::EncryptionFailed)?;
+                (Some(encrypted_matches), None)
             }
-        }
+        };
+        
+        let end_time = time() / 1_000_000;
+        let total_instructions = instruction_counter() - start_instructions;
+        let instruction_percentage = (total_instructions as f32 / INSTRUCTION_LIMIT_SAFETY as f32) * 100.0;
+        
+        storage::update_metrics(|m| {
+            m.total_operations += 1;
+            m.comparison_operations += 1;
+            m.total_instructions_used += total_instructions;
+        });
+        
+        ic_cdk::println!("Comparison completed in {}ms using {}% of instruction limit", 
+            end_time - start_time, instruction_percentage);
+        
+        Ok(CompareResult {
+            success: true,
+            similarity_score: encrypted_matches,
+            tokens_compared: Some(tokens),
+            time_ms: end_time - start_time,
+            instructions_used: total_instructions,
+            instruction_percentage,
+            error: None,
+            decryption_request,
+        })
     })
 }
 
-// ===== QUERY METHODS =====
-#[query]
-fn get_stats() -> CanisterStats {
-    STATE.with(|state| {
-        let state = state.borrow();
+// Blinded Hamming protocol over the common positions of two documents. Both
+// roles run in-canister and only the match count leaves this function.
+fn count_matching_tokens(
+    backend: &Backend,
+    tokens1: &[Vec<u8>],
+    tokens2: &[Vec<u8>],
+    key_config: KeyConfig,
+) -> Result<u64, PaillierError> {
+    dispatch!(backend, he => blinded_match_count(he, tokens1, tokens2, key_config))
+}
+
+fn blinded_match_count<H: AdditiveHomomorphic>(
+    he: &H,
+    tokens1: &[Vec<u8>],
+    tokens2: &[Vec<u8>],
+    key_config: KeyConfig,
+) -> Result<u64, PaillierError> {
+    let positions = tokens1.len().min(tokens2.len());
+    let cs1 = deserialize_tokens(he, &tokens1[..positions])?;
+    let cs2 = deserialize_tokens(he, &tokens2[..positions])?;
+    
+    // Check instructions every few tokens (each costs about two encryptions)
+    let check_interval = key_config.check_interval(1);
+    let per_check = check_interval as u64 * key_config.instructions_per_comparison();
+    let mut check = |i: usize| if i.is_multiple_of(check_interval) { check_instruction_limit(per_check) } else { Ok(()) };
+    
+    // Enc(r_i * (a_i - b_i)) is Enc(0) iff tokens match; the key holder counts the zeros
+    let blinded = rng::with_rng(|rng| similarity::blinded_differences(he, &cs1, &cs2, rng, &mut check))
+        .map_err(PaillierError::RandomnessUnavailable)??;
+    similarity::count_matches(he, &blinded, &mut check)
+}
+
+// Evaluator half of `blinded_match_count` under the threshold key: the
+// shuffled blinded differences, for the parties to decrypt
+fn threshold_differences(
+    key: &ThresholdKey,
+    tokens1: &[Vec<u8>],
+    tokens2: &[Vec<u8>],
+    key_config: KeyConfig,
+) -> Result<Vec<Vec<u8>>, PaillierError> {
+    let cs1 = deserialize_tokens(key, tokens1)?;
+    let cs2 = deserialize_tokens(key, tokens2)?;
+    
+    // Each position costs about two encryptions, like a zero test
+    let check_interval = key_config.check_interval(1);
+    let per_check = check_interval as u64 * key_config.instructions_per_comparison();
+    let mut check = |i: usize| if i.is_multiple_of(check_interval) { check_instruction_limit(per_check) } else { Ok(()) };
+    
+    let blinded = rng::with_rng(|rng| similarity::blinded_differences(key, &cs1, &cs2, rng, &mut check))
+        .map_err(PaillierError::RandomnessUnavailable)??;
+    Ok(blinded.iter().map(|c| key.serialize(c)).collect())
+}
+
+fn deserialize_tokens<H: AdditiveHomomorphic>(he: &H, tokens: &[Vec<u8>]) -> Result<Vec<H::Ciphertext>, PaillierError> {
+    tokens.iter()
+        .enumerate()
+        .map(|(i, bytes)| he.deserialize(bytes)
+            .map_err(|e| PaillierError::ComparisonFailed(format!("token {}: {}", i, e))))
+        .collect()
+}
+
+// Slot-wise equality of two packed documents: one decryption per ciphertext
+// instead of two encryptions per token. There is no blinding or shuffling,
+// so the key holder sees each position's fingerprint difference.
+fn count_packed_matches(
+    paillier: &SimplePaillier,
+    packing: PackedLayout,
+    tokens1: &[Vec<u8>],
+    tokens2: &[Vec<u8>],
+    key_config: KeyConfig,
+) -> Result<u64, PaillierError> {
+    let pk = paillier.public_key();
+    let check_interval = key_config.check_interval(1);
+    let layout = SlotLayout::for_key(pk, packing.value_bits as usize, packing.guard_bits as usize)
+        .map_err(PaillierError::ComparisonFailed)?;
+    
+    let mut matches: u64 = 0;
+    for (i, (enc1_bytes, enc2_bytes)) in tokens1.iter().zip(tokens2.iter()).enumerate() {
+        if i % check_interval == 0 {
+            check_instruction_limit(check_interval as u64 * key_config.instructions_per_token())?;
+        }
         
-        METRICS.with(|metrics| {
-            let m = metrics.borrow();
-            
-            // Calculate memory usage with improved estimation
-            let encrypted_token_size = 256; // bytes for 512-bit keys
-            let total_tokens: usize = state.encrypted_docs.iter()
-                .map(|(_, tokens)| tokens.len())
-                .sum();
-            let memory_used_mb = (total_tokens * encrypted_token_size) as f64 / 1_048_576.0;
-            
-            CanisterStats {
-                total_operations: m.total_operations,
-                total_instructions: m.total_instructions_used,
-                documents_stored: state.encrypted_docs.len(),
-                memory_used_mb,
-                encryption_operations: m.encryption_operations,
-                comparison_operations: m.comparison_operations,
-                failed_operations: m.failed_operations,
-                owner: state.owner.map(|p| p.to_string()),
-            }
-        })
-    })
+        // The last ciphertext may be partly filled; its empty slots must not count
+        let len = layout.len_of(i, packing.tokens as usize);
+        let enc1 = layout.wrap(BigUint::from_bytes_be(enc1_bytes), len);
+        let enc2 = layout.wrap(BigUint::from_bytes_be(enc2_bytes), len);
+        
+        matches += layout.slot_differences(pk, &enc1, &enc2)
+            .and_then(|differences| layout.count_equal(paillier.private_key(), &differences))
+            .map_err(|e| PaillierError::ComparisonFailed(format!("ciphertext {}: {}", i, e)))?;
+    }
+    Ok(matches)
 }
 
-#[query]
-fn list_documents() -> Vec<(String, usize)> {
+#[update]
+async fn compare_overlap(doc_id1: String, doc_id2: String) -> Result<OverlapResult, PaillierError> {
+    track_failure(overlap(&doc_id1, &doc_id2).await)
+}
+
+async fn overlap(doc_id1: &str, doc_id2: &str) -> Result<OverlapResult, PaillierError> {
+    let start_time = time() / 1_000_000;
+    
+    validate_doc_id(doc_id1)?;
+    validate_doc_id(doc_id2)?;
+    check_not_threshold("Set overlaps")?;
+    
+    // Blinding draws from the CSPRNG; seed it now if the init timer hasn't yet
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
+    ensure_keypair().await?;
+    
+    // Read after the await: re-deriving the keypair may have reset instruction_counter()
+    let start_instructions = instruction_counter();
+    
     STATE.with(|state| {
-        state.borrow()
-            .encrypted_docs
-            .iter()
-            .map(|(id, tokens)| (id.clone(), tokens.len()))
-            .collect()
+        let state = state.borrow();
+        
+        let backend = backend(&state)?;
+        
+        let doc1 = storage::get_document(doc_id1)
+            .ok_or_else(|| PaillierError::DocumentNotFound(doc_id1.to_string()))?;
+        let doc2 = storage::get_document(doc_id2)
+            .ok_or_else(|| PaillierError::DocumentNotFound(doc_id2.to_string()))?;
+        
+        check_document_access(doc_id1, &doc1, Permission::Compare)?;
+        check_document_access(doc_id2, &doc2, Permission::Compare)?;
+        check_unpacked(doc_id1, &doc1)?;
+        check_unpacked(doc_id2, &doc2)?;
+        
+        // Quadratic work: refuse up front rather than fail halfway
+        let pairs = doc1.tokens.len() * doc2.tokens.len();
+        let max_pairs = state.key_config.max_overlap_pairs();
+        if max_pairs == 0 {
+            return Err(PaillierError::InvalidInput(format!(
+                "Set overlap is not available with {}-bit keys: one token pair doesn't fit a message",
+                state.key_config.key_bits)));
+        }
+        if pairs > max_pairs {
+            return Err(PaillierError::InvalidInput(format!(
+                "Too many token pairs: {} x {} > {} ({}-bit keys)",
+                doc1.tokens.len(), doc2.tokens.len(), max_pairs, state.key_config.key_bits)));
+        }
+        
+        ic_cdk::println!("Set overlap of '{}' ({} tokens) and '{}' ({} tokens)",
+            doc_id1, doc1.tokens.len(), doc_id2, doc2.tokens.len());
+        
+        let intersection = dispatch!(backend, he =>
+            blinded_intersection_size(he, &doc1.tokens, &doc2.tokens, state.key_config))?;
+        
+        // Publish only Enc(count)
+        let encrypted_intersection = backend.encrypt(&intersection.to_be_bytes())
+            .map_err(PaillierError::EncryptionFailed)?;
+        
+        let end_time = time() / 1_000_000;
+        let total_instructions = instruction_counter() - start_instructions;
+        let instruction_percentage = (total_instructions as f32 / INSTRUCTION_LIMIT_SAFETY as f32) * 100.0;
+        
+        storage::update_metrics(|m| {
+            m.total_operations += 1;
+            m.comparison_operations += 1;
+            m.total_instructions_used += total_instructions;
+        });
+        
+        Ok(OverlapResult {
+            encrypted_intersection,
+            set_size1: doc1.tokens.len(),
+            set_size2: doc2.tokens.len(),
+            time_ms: end_time - start_time,
+            instructions_used: total_instructions,
+            instruction_percentage,
+        })
     })
 }
 
-#[query]
-fn health_check() -> String {
-    let initialized = STATE.with(|s| s.borrow().paillier.is_some());
-    let doc_count = STATE.with(|s| s.borrow().encrypted_docs.len());
-    let memory_kb = get_memory_usage_kb();
+// Blinded set-overlap protocol; like `count_matching_tokens` only the count
+// leaves this function
+fn blinded_intersection_size<H: AdditiveHomomorphic>(
+    he: &H,
+    tokens1: &[Vec<u8>],
+    tokens2: &[Vec<u8>],
+    key_config: KeyConfig,
+) -> Result<u64, PaillierError> {
+    let cs1 = deserialize_tokens(he, tokens1)?;
+    let cs2 = deserialize_tokens(he, tokens2)?;
     
-    format!(
-        "Status: {}, Documents: {}, Memory: {}KB", 
-        if initialized { "Ready" } else { "Not initialized" },
-        doc_count,
-        memory_kb
-    )
+    // Every token is a whole row of pairs, so check each time
+    let per_row = tokens1.len().max(tokens2.len()) as u64 * key_config.instructions_per_token();
+    let mut check = |_| check_instruction_limit(per_row);
+    rng::with_rng(|rng| similarity::blinded_intersection_size(he, &cs1, &cs2, rng, &mut check))
+        .map_err(PaillierError::RandomnessUnavailable)?
 }
 
-// ===== ADMIN METHODS =====
 #[update]
-fn clear_all_documents() -> String {
+async fn search_similar(doc_id: String, top_k: u32) -> Result<SearchResult, PaillierError> {
+    track_failure(search(doc_id, top_k as usize).await)
+}
+
+async fn search(doc_id: String, top_k: usize) -> Result<SearchResult, PaillierError> {
+    let start_time = time() / 1_000_000;
+    
+    validate_doc_id(&doc_id)?;
+    check_not_threshold("Searches")?;
+    if top_k == 0 || top_k > MAX_SEARCH_RESULTS {
+        return Err(PaillierError::InvalidInput(format!(
+            "top_k must be between 1 and {}", MAX_SEARCH_RESULTS)));
+    }
+    
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
+    ensure_keypair().await?;
+    
     let caller = caller();
+    let key_config = STATE.with(|s| s.borrow().key_config);
+    let mut query = search_query(&doc_id, &key_config)?;
     
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        
-        // Check owner
-        if state.owner != Some(caller) {
-            return format!("Unauthorized: only owner can clear documents");
+    // Snapshot the candidates; each is re-read (and re-checked) when compared.
+    // Packed documents can't be compared token by token, so they are refused.
+    let mut candidates = Vec::new();
+    let mut packed = None;
+    storage::for_each_document_meta(|id, meta| {
+        if id != doc_id && meta.ciphertexts > 0 && meta.allows(caller, Permission::Compare) {
+            match meta.packing {
+                Some(_) => { packed.get_or_insert_with(|| id.to_string()); }
+                None => candidates.push(id.to_string()),
+            }
+        }
+    });
+    if let Some(id) = packed {
+        return Err(PaillierError::InvalidInput(format!(
+            "Document '{}' is packed and can only be used with compare_documents", id)));
+    }
+    
+    ic_cdk::println!("Searching {} candidates for '{}'", candidates.len(), doc_id);
+    
+    let mut scored = Vec::with_capacity(candidates.len());
+    let mut messages_used = 1;
+    let mut instructions_used = 0;
+    
+    for candidate_id in candidates {
+        // Move to a fresh message before a candidate that might not fit
+        if instruction_counter() + candidate_cost(&query, &key_config) > INSTRUCTION_LIMIT_SAFETY {
+            instructions_used += instruction_counter();
+            yield_message().await?;
+            messages_used += 1;
+            
+            // The query may have been deleted, replaced or its grants revoked meanwhile
+            query = search_query(&doc_id, &key_config)?;
         }
         
-        let count = state.encrypted_docs.len();
-        state.encrypted_docs.clear();
+        // Likewise for the candidate; a candidate repacked since the snapshot is an error
+        let doc = match storage::get_document(&candidate_id) {
+            Some(doc) if doc.allows(caller, Permission::Compare) => doc,
+            _ => continue,
+        };
+        check_unpacked(&candidate_id, &doc)?;
         
-        METRICS.with(|m| m.borrow_mut().total_operations += 1);
+        let positions = query.tokens.len().min(doc.tokens.len());
+        let matches = STATE.with(|state| {
+            let state = state.borrow();
+            count_matching_tokens(backend(&state)?, &query.tokens[..positions], &doc.tokens[..positions], key_config)
+        })?;
+        scored.push((candidate_id, matches, positions));
+    }
+    
+    let candidates_compared = scored.len();
+    
+    // Rank by match count (ties by doc_id) and encrypt only the scores returned
+    scored.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    scored.truncate(top_k);
+    
+    if instruction_counter() + scored.len() as u64 * key_config.instructions_per_token() > INSTRUCTION_LIMIT_SAFETY {
+        instructions_used += instruction_counter();
+        yield_message().await?;
+        messages_used += 1;
+    }
+    
+    let hits = STATE.with(|state| {
+        let state = state.borrow();
+        let backend = backend(&state)?;
         
-        format!("Cleared {} documents", count)
+        scored.into_iter()
+            .map(|(doc_id, matches, positions)| {
+                let score = backend.encrypt(&matches.to_be_bytes())
+                    .map_err(PaillierError::EncryptionFailed)?;
+                Ok(SearchHit {
+                    doc_id,
+                    encrypted_score: score,
+                    tokens_compared: positions,
+                })
+            })
+            .collect::<Result<Vec<_>, PaillierError>>()
+    })?;
+    
+    instructions_used += instruction_counter();
+    
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.comparison_operations += candidates_compared as u64;
+        m.total_instructions_used += instructions_used;
+    });
+    
+    ic_cdk::println!("Search for '{}' compared {} candidates over {} messages",
+        doc_id, candidates_compared, messages_used);
+    
+    Ok(SearchResult {
+        hits,
+        candidates_compared,
+        messages_used,
+        time_ms: (time() / 1_000_000) - start_time,
+        instructions_used,
     })
 }
 
-// Export Candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+// Read and check the query document; one candidate must fit in a message
+fn search_query(doc_id: &str, key_config: &KeyConfig) -> Result<StoredDocument, PaillierError> {
+    let query = storage::get_document(doc_id)
+        .ok_or_else(|| PaillierError::DocumentNotFound(doc_id.to_string()))?;
+    check_document_access(doc_id, &query, Permission::Compare)?;
+    check_unpacked(doc_id, &query)?;
+    
+    if !key_config.comparison_fits() {
+        return Err(PaillierError::InvalidInput(format!(
+            "Search is not available with {}-bit keys, compare pairs with start_compare_job",
+            key_config.key_bits)));
+    }
+    if candidate_cost(&query, key_config) > INSTRUCTION_LIMIT_SAFETY {
+        return Err(PaillierError::InvalidInput(format!(
+            "Document '{}' is too long to search ({} tokens, at most {} at {} bits)",
+            doc_id, query.tokens.len(),
+            INSTRUCTION_LIMIT_SAFETY / key_config.instructions_per_comparison(), key_config.key_bits)));
+    }
+    Ok(query)
+}
+
+// Upper bound for one candidate: every position of the query
+fn candidate_cost(query: &StoredDocument, key_config: &KeyConfig) -> u64 {
+    query.tokens.len() as u64 * key_config.instructions_per_comparison()
+}
+
+// Target of `yield_message`; only the canister itself may call it
+#[update(guard = "caller_is_self")]
+fn yield_noop() {}
+
+// ===== CLIENT-SIDE ENCRYPTION =====
+// Clients that encrypt under the published key keep their plaintext tokens
+// off-chain; the canister only ever sees ciphertexts. Each ciphertext comes
+// with a range proof (see proofs.rs) so it can't encrypt an out-of-range token.
+
+// An update call: the keypair may need to be re-derived after an upgrade
+#[update]
+async fn get_public_key() -> Result<PublicKeyInfo, PaillierError> {
+    ensure_keypair().await?;
+    
+    STATE.with(|state| {
+        let state = state.borrow();
+        let pk = document_key(&state)?;
+        Ok(PublicKeyInfo {
+            n: pk.n.to_bytes_be(),
+            g: pk.g.to_bytes_be(),
+            key_bits: pk.n.bits() as u32,
+        })
+    })
+}
+
+#[update]
+async fn upload_ciphertexts(
+    doc_id: String,
+    ciphertexts: Vec<Vec<u8>>,
+    range_proofs: Vec<Vec<u8>>,
+) -> Result<EncryptResult, PaillierError> {
+    track_failure(upload(doc_id, ciphertexts, range_proofs).await)
+}
+
+// Ciphertexts with range proofs that fit one ingress message (about 28 at 512 bits)
+fn max_proven_tokens(pk: &simple_paillier::PublicKey) -> usize {
+    let per_token = proofs::widths(pk).1 + proofs::range_proof_bytes(pk) + 8; // + blob length prefixes
+    (MAX_INGRESS_BYTES - INGRESS_OVERHEAD_BYTES) / per_token
+}
+
+async fn upload(doc_id: String, ciphertexts: Vec<Vec<u8>>, range_proofs: Vec<Vec<u8>>) -> Result<EncryptResult, PaillierError> {
+    let start_time = time() / 1_000_000;
+    
+    validate_doc_id(&doc_id)?;
+    
+    if range_proofs.len() != ciphertexts.len() {
+        return Err(PaillierError::InvalidInput(format!(
+            "{} ciphertexts but {} range proofs", ciphertexts.len(), range_proofs.len())));
+    }
+    
+    check_can_write(&doc_id)?;
+    ensure_keypair().await?;
+    
+    let (pk, key_config) = STATE.with(|state| {
+        let state = state.borrow();
+        Ok::<_, PaillierError>((document_key(&state)?, state.key_config))
+    })?;
+    
+    // Range proofs dominate the size, so the ingress limit caps the count
+    let max_tokens = max_proven_tokens(&pk);
+    if ciphertexts.len() > max_tokens {
+        return Err(PaillierError::TooManyTokens { provided: ciphertexts.len(), max: max_tokens });
+    }
+    
+    let (check, instructions_used) = verify_proven_tokens(&pk, key_config, &ciphertexts, &range_proofs).await?;
+    let tokens = check.into_tokens().map_err(PaillierError::InvalidInput)?;
+    
+    STATE.with(|state| {
+        let state = state.borrow();
+        
+        // Threshold decryption may have been enabled while we yielded
+        if document_key(&state)?.n != pk.n {
+            return Err(PaillierError::InvalidInput("The document key changed during the upload".to_string()));
+        }
+        
+        // Check ownership again: the doc_id may have been claimed while we yielded
+        let previous = check_can_write(&doc_id)?;
+        if previous.is_none() && storage::document_count() >= MAX_DOCUMENTS {
+            return Err(PaillierError::DocumentLimitReached { max: MAX_DOCUMENTS });
+        }
+        
+        // Replaces an existing document like encrypt_document, keeping its grants
+        let new_tokens = tokens.len() as u64;
+        let replaced_tokens = previous.as_ref().map_or(0, |doc| doc.tokens.len() as u64);
+        storage::insert_document(doc_id.clone(), StoredDocument {
+            tokens,
+            owner: caller(),
+            acl: previous.and_then(|doc| doc.acl),
+            packing: None,
+        });
+        
+        let total_instructions = instructions_used + instruction_counter();
+        storage::update_metrics(|m| {
+            m.total_operations += 1;
+            m.total_instructions_used += total_instructions;
+            m.stored_tokens = m.stored_tokens + new_tokens - replaced_tokens;
+        });
+        
+        ic_cdk::println!("Stored {} client-encrypted tokens for document '{}'", new_tokens, doc_id);
+        
+        Ok(EncryptResult {
+            success: true,
+            doc_id,
+            tokens_encrypted: new_tokens as usize,
+            time_ms: (time() / 1_000_000) - start_time,
+            instructions_used: total_instructions,
+            memory_used_kb: memory_usage_kb(&state),
+            error: None,
+        })
+    })
+}
+
+// Check each ciphertext's range proof, returning the finished check and the
+// instructions spent in the messages before the current one
+async fn verify_proven_tokens(
+    pk: &simple_paillier::PublicKey,
+    key_config: KeyConfig,
+    ciphertexts: &[Vec<u8>],
+    range_proofs: &[Vec<u8>],
+) -> Result<(proofs::RangeCheck, u64), PaillierError> {
+    let mut check = proofs::RangeCheck::new(pk, ciphertexts, range_proofs)
+        .map_err(PaillierError::InvalidInput)?;
+    
+    let bits = check.remaining_bits();
+    let (instructions_used, messages) = run_in_messages(bits, key_config.instructions_per_comparison(), "bit proof", |batch| {
+        check.verify_bits(pk, batch).map_err(PaillierError::InvalidInput)
+    })
+    .await?;
+    
+    ic_cdk::println!("Verified {} range proofs over {} messages", ciphertexts.len(), messages);
+    Ok((check, instructions_used))
+}
+
+// Do `total` units of work, `run(batch)` doing the next `batch` of them. Each
+// message runs as many as its budget covers: the first batch is sized from
+// `unit_cost`, later ones from the measured cost. Returns the instructions
+// spent in the messages before the current one, and the messages used.
+async fn run_in_messages(
+    total: usize,
+    mut unit_cost: u64,
+    unit: &str,
+    mut run: impl FnMut(usize) -> Result<(), PaillierError>,
+) -> Result<(u64, u32), PaillierError> {
+    let mut done = 0;
+    let mut instructions_used = 0;
+    let mut messages = 1;
+    
+    while done < total {
+        let mut units = units_that_fit(instruction_counter(), unit_cost);
+        if units == 0 {
+            instructions_used += instruction_counter();
+            yield_message().await?;
+            messages += 1;
+            
+            units = units_that_fit(instruction_counter(), unit_cost);
+            if units == 0 {
+                return Err(PaillierError::InvalidInput(format!(
+                    "One {} needs about {} instructions, more than a message allows", unit, unit_cost)));
+            }
+        }
+        
+        let before = instruction_counter();
+        let batch = units.min(total - done);
+        run(batch)?;
+        unit_cost = ((instruction_counter() - before) / batch as u64).max(1);
+        done += batch;
+    }
+    Ok((instructions_used, messages))
+}
+
+// Units that still fit this message at `unit_cost` instructions each
+fn units_that_fit(used: u64, unit_cost: u64) -> usize {
+    (INSTRUCTION_LIMIT_SAFETY.saturating_sub(used) / unit_cost.max(1)) as usize
+}
+
+// Append a verified chunk to an upload; an unfinished check stores nothing
+fn store_proven_chunk(doc_id: &str, check: proofs::RangeCheck, now: u64) -> Result<PendingUpload, PaillierError> {
+    let tokens = check.into_tokens().map_err(PaillierError::InvalidInput)?;
+    storage::append_upload_chunk(doc_id, tokens, now)
+        .ok_or_else(|| PaillierError::InvalidInput(format!("No upload in progress for '{}'", doc_id)))
+}
+
+// ===== CHUNKED UPLOAD =====
+// Documents larger than one message's encryption budget are built from
+// several chunks. The upload stays invisible to comparisons, searches and
+// list_documents until finalize_upload stores it as a regular document.
+
+#[update]
+fn begin_upload(doc_id: String) -> Result<UploadInfo, PaillierError> {
+    track_failure(begin(doc_id))
+}
+
+#[update]
+async fn append_tokens(doc_id: String, chunk: Vec<Vec<u8>>) -> Result<UploadInfo, PaillierError> {
+    track_failure(append(doc_id, chunk).await)
+}
+
+#[update]
+fn finalize_upload(doc_id: String) -> Result<EncryptResult, PaillierError> {
+    track_failure(finalize(doc_id))
+}
+
+// Client-encrypted chunks: like upload_ciphertexts, but appended to an upload
+// so a document isn't capped at what one ingress message can carry
+#[update]
+async fn append_ciphertexts(
+    doc_id: String,
+    ciphertexts: Vec<Vec<u8>>,
+    range_proofs: Vec<Vec<u8>>,
+) -> Result<UploadInfo, PaillierError> {
+    track_failure(append_proven(doc_id, ciphertexts, range_proofs).await)
+}
+
+// Drop the caller's upload and the chunks sent so far
+#[update]
+fn abort_upload(doc_id: String) -> Result<(), PaillierError> {
+    validate_doc_id(&doc_id)?;
+    own_upload(&doc_id)?;
+    
+    storage::remove_upload(&doc_id);
+    ic_cdk::println!("Aborted upload of '{}'", doc_id);
+    Ok(())
+}
+
+fn begin(doc_id: String) -> Result<UploadInfo, PaillierError> {
+    validate_doc_id(&doc_id)?;
+    if storage::config().key_bits.is_none() {
+        return Err(PaillierError::NotInitialized);
+    }
+    
+    check_can_start_upload(&doc_id)?;
+    
+    let now = time();
+    sweep_expired_uploads();
+    
+    // Restarting one's own upload discards what was sent so far
+    if storage::get_upload(&doc_id).is_none() && storage::upload_count() >= MAX_PENDING_UPLOADS {
+        return Err(PaillierError::InvalidInput(format!(
+            "Too many uploads in progress (max {}), try again later", MAX_PENDING_UPLOADS)));
+    }
+    
+    let upload = storage::begin_upload(doc_id.clone(), caller(), now);
+    
+    ic_cdk::println!("Started upload of '{}'", doc_id);
+    Ok(upload_info(&doc_id, &upload))
+}
+
+async fn append(doc_id: String, chunk: Vec<Vec<u8>>) -> Result<UploadInfo, PaillierError> {
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
+    
+    validate_doc_id(&doc_id)?;
+    
+    let key_config = STATE.with(|s| s.borrow().key_config);
+    validate_tokens(&chunk, key_config.max_tokens())?;
+    
+    own_upload(&doc_id)?;
+    ensure_keypair().await?;
+    
+    // Read after the awaits, which reset instruction_counter()
+    let start_instructions = instruction_counter();
+    
+    STATE.with(|state| {
+        let state = state.borrow();
+        let backend = backend(&state)?;
+        
+        // Re-read: the upload may have expired or been restarted while we awaited
+        let upload = own_upload(&doc_id)?;
+        check_upload_room(&upload, chunk.len())?;
+        
+        // Stored as a chunk of its own; earlier chunks are not rewritten
+        let tokens = encrypt_tokens(backend, &chunk, key_config)?;
+        let upload = storage::append_upload_chunk(&doc_id, tokens, time())
+            .ok_or_else(|| PaillierError::InvalidInput(format!("No upload in progress for '{}'", doc_id)))?;
+        let info = upload_info(&doc_id, &upload);
+        
+        let instructions_used = instruction_counter() - start_instructions;
+        storage::update_metrics(|m| {
+            m.total_operations += 1;
+            m.total_instructions_used += instructions_used;
+        });
+        
+        Ok(info)
+    })
+}
+
+async fn append_proven(doc_id: String, ciphertexts: Vec<Vec<u8>>, range_proofs: Vec<Vec<u8>>) -> Result<UploadInfo, PaillierError> {
+    validate_doc_id(&doc_id)?;
+    
+    if range_proofs.len() != ciphertexts.len() {
+        return Err(PaillierError::InvalidInput(format!(
+            "{} ciphertexts but {} range proofs", ciphertexts.len(), range_proofs.len())));
+    }
+    
+    let upload = own_upload(&doc_id)?;
+    check_upload_room(&upload, ciphertexts.len())?;
+    ensure_keypair().await?;
+    
+    let (pk, key_config) = STATE.with(|state| {
+        let state = state.borrow();
+        Ok::<_, PaillierError>((document_key(&state)?, state.key_config))
+    })?;
+    
+    let max_tokens = max_proven_tokens(&pk);
+    if ciphertexts.len() > max_tokens {
+        return Err(PaillierError::TooManyTokens { provided: ciphertexts.len(), max: max_tokens });
+    }
+    
+    let (check, instructions_used) = verify_proven_tokens(&pk, key_config, &ciphertexts, &range_proofs).await?;
+    
+    // Re-read: the upload may have expired, been aborted or grown while we yielded
+    let upload = own_upload(&doc_id)?;
+    check_upload_room(&upload, ciphertexts.len())?;
+    let upload = store_proven_chunk(&doc_id, check, time())?;
+    
+    let total_instructions = instructions_used + instruction_counter();
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.total_instructions_used += total_instructions;
+    });
+    
+    Ok(upload_info(&doc_id, &upload))
+}
+
+fn finalize(doc_id: String) -> Result<EncryptResult, PaillierError> {
+    let start_time = time() / 1_000_000;
+    let start_instructions = instruction_counter();
+    
+    validate_doc_id(&doc_id)?;
+    own_upload(&doc_id)?;
+    
+    let previous = check_can_write(&doc_id)?;
+    if previous.is_none() && storage::document_count() >= MAX_DOCUMENTS {
+        return Err(PaillierError::DocumentLimitReached { max: MAX_DOCUMENTS });
+    }
+    
+    // Replaces an existing document like encrypt_document, keeping its grants
+    let (upload, tokens) = storage::take_upload(&doc_id)
+        .ok_or_else(|| PaillierError::InvalidInput(format!("No upload in progress for '{}'", doc_id)))?;
+    let new_tokens = tokens.len() as u64;
+    let replaced_tokens = previous.as_ref().map_or(0, |doc| doc.tokens.len() as u64);
+    storage::insert_document(doc_id.clone(), StoredDocument {
+        tokens,
+        owner: upload.owner,
+        acl: previous.and_then(|doc| doc.acl),
+        packing: None,
+    });
+    
+    let total_instructions = instruction_counter() - start_instructions;
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.encryption_operations += 1;
+        m.total_instructions_used += total_instructions;
+        m.stored_tokens = m.stored_tokens + new_tokens - replaced_tokens;
+    });
+    
+    ic_cdk::println!("Finalized upload of '{}' ({} tokens)", doc_id, new_tokens);
+    
+    Ok(EncryptResult {
+        success: true,
+        doc_id,
+        tokens_encrypted: new_tokens as usize,
+        time_ms: (time() / 1_000_000) - start_time,
+        instructions_used: total_instructions,
+        memory_used_kb: get_memory_usage_kb(),
+        error: None,
+    })
+}
+
+// A new upload may replace the caller's own document, or an upload of the same
+// doc_id by someone else once that one has expired
+fn check_can_start_upload(doc_id: &str) -> Result<(), PaillierError> {
+    check_can_write(doc_id)?;
+    match storage::get_upload(doc_id) {
+        Some(upload) if upload.owner != caller() && !is_expired(&upload) => {
+            Err(PaillierError::Unauthorized(format!("document '{}' is being uploaded by another principal", doc_id)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The caller's unexpired upload of doc_id
+fn own_upload(doc_id: &str) -> Result<PendingUpload, PaillierError> {
+    match storage::get_upload(doc_id) {
+        Some(upload) if upload.owner == caller() && !is_expired(&upload) => Ok(upload),
+        Some(upload) if upload.owner == caller() => {
+            storage::remove_upload(doc_id);
+            Err(PaillierError::InvalidInput(format!("Upload of '{}' expired, call begin_upload again", doc_id)))
+        }
+        _ => Err(PaillierError::InvalidInput(format!("No upload in progress for '{}'", doc_id))),
+    }
+}
+
+// Abandoned uploads would otherwise hold their tokens until the next begin_upload
+fn schedule_upload_sweep() {
+    ic_cdk_timers::set_timer_interval(UPLOAD_SWEEP_INTERVAL, sweep_expired_uploads);
+}
+
+fn sweep_expired_uploads() {
+    let expired = storage::remove_uploads_before(time().saturating_sub(UPLOAD_TIMEOUT_NS));
+    if expired > 0 {
+        ic_cdk::println!("Dropped {} expired uploads", expired);
+    }
+}
+
+fn check_upload_room(upload: &PendingUpload, chunk_tokens: usize) -> Result<(), PaillierError> {
+    let provided = upload.tokens as usize + chunk_tokens;
+    if provided > MAX_UPLOAD_TOKENS {
+        return Err(PaillierError::TooManyTokens { provided, max: MAX_UPLOAD_TOKENS });
+    }
+    Ok(())
+}
+
+fn is_expired(upload: &PendingUpload) -> bool {
+    time() > upload.updated_at + UPLOAD_TIMEOUT_NS
+}
+
+fn upload_info(doc_id: &str, upload: &PendingUpload) -> UploadInfo {
+    UploadInfo {
+        doc_id: doc_id.to_string(),
+        tokens_received: upload.tokens as usize,
+        max_chunk_tokens: STATE.with(|s| s.borrow().key_config.max_tokens()),
+        expires_at: upload.updated_at + UPLOAD_TIMEOUT_NS,
+    }
+}
+
+// ===== RERANDOMIZATION =====
+// Stored ciphertexts and returned results are fixed byte strings, so the same
+// value handed out twice is linkable. Rerandomized copies decrypt to the same
+// plaintexts but can't be matched with each other or with the stored originals.
+
+#[update]
+async fn rerandomize_ciphertexts(source: RerandomizeSource) -> Result<RerandomizeResult, PaillierError> {
+    track_failure(rerandomize(source).await)
+}
+
+async fn rerandomize(source: RerandomizeSource) -> Result<RerandomizeResult, PaillierError> {
+    let start_time = time() / 1_000_000;
+    
+    if let RerandomizeSource::Document { doc_id, .. } = &source {
+        validate_doc_id(doc_id)?;
+    }
+    
+    // Fresh r^n comes from the pool, or from the RNG once it runs dry
+    rng::ensure_seeded().await.map_err(PaillierError::RandomnessUnavailable)?;
+    ensure_keypair().await?;
+    
+    let (ciphertexts, next_start) = match source {
+        RerandomizeSource::Document { doc_id, start } => {
+            let doc = storage::get_document(&doc_id)
+                .ok_or_else(|| PaillierError::DocumentNotFound(doc_id.clone()))?;
+            check_document_access(&doc_id, &doc, Permission::Read)?;
+            
+            // Positions count ciphertexts, also for packed documents
+            let len = doc.tokens.len();
+            let start = usize::try_from(start).ok().filter(|&start| start <= len).ok_or_else(|| {
+                PaillierError::InvalidInput(format!("start {} is past the end of '{}' ({} ciphertexts)", start, doc_id, len))
+            })?;
+            let end = (start + MAX_RERANDOMIZE).min(len);
+            (doc.tokens[start..end].to_vec(), (end < len).then_some(end as u64))
+        }
+        RerandomizeSource::Ciphertexts(ciphertexts) => {
+            if ciphertexts.len() > MAX_RERANDOMIZE {
+                return Err(PaillierError::TooManyTokens { provided: ciphertexts.len(), max: MAX_RERANDOMIZE });
+            }
+            (ciphertexts, None)
+        }
+    };
+    
+    // Budget a full encryption each, as the pool may be empty
+    let per_ciphertext = STATE.with(|s| s.borrow().key_config.instructions_per_token());
+    let threshold = threshold_key()?;
+    let mut rerandomized = Vec::with_capacity(ciphertexts.len());
+    let mut messages_used = 1;
+    let mut instructions_used = 0;
+    
+    for (i, ciphertext) in ciphertexts.iter().enumerate() {
+        if instruction_counter() + per_ciphertext > INSTRUCTION_LIMIT_SAFETY {
+            instructions_used += instruction_counter();
+            yield_message().await?;
+            messages_used += 1;
+        }
+        
+        // Documents are under the threshold key once one is configured
+        let copy = match &threshold {
+            Some(key) => key.deserialize(ciphertext)
+                .and_then(|c| key.rerandomize(&c))
+                .map(|c| key.serialize(&c))
+                .map_err(|e| PaillierError::InvalidInput(format!("ciphertext {}: {}", i, e)))?,
+            None => STATE.with(|state| {
+                let state = state.borrow();
+                let backend = backend(&state)?;
+                
+                if let Some(paillier) = backend.paillier() {
+                    if !paillier.public_key().is_valid_ciphertext(&BigUint::from_bytes_be(ciphertext)) {
+                        return Err(PaillierError::InvalidInput(format!("ciphertext {} is not in Z*_{{n^2}}", i)));
+                    }
+                }
+                backend.rerandomize(ciphertext)
+                    .map_err(|e| PaillierError::InvalidInput(format!("ciphertext {}: {}", i, e)))
+            })?,
+        };
+        rerandomized.push(copy);
+    }
+    
+    instructions_used += instruction_counter();
+    
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.total_instructions_used += instructions_used;
+    });
+    
+    Ok(RerandomizeResult {
+        ciphertexts: rerandomized,
+        next_start,
+        messages_used,
+        time_ms: (time() / 1_000_000) - start_time,
+        instructions_used,
+    })
+}
+
+// ===== JOBS =====
+#[update]
+fn start_matrix_job(doc_ids: Vec<String>) -> Result<u64, PaillierError> {
+    track_failure(start_matrix(doc_ids))
+}
+
+fn start_matrix(doc_ids: Vec<String>) -> Result<u64, PaillierError> {
+    if storage::config().key_bits.is_none() {
+        return Err(PaillierError::NotInitialized);
+    }
+    check_not_threshold("Jobs")?;
+    if doc_ids.len() < 2 || doc_ids.len() > MAX_MATRIX_DOCUMENTS {
+        return Err(PaillierError::InvalidInput(format!(
+            "A matrix job needs 2 to {} documents", MAX_MATRIX_DOCUMENTS)));
+    }
+    
+    // Pairs are compared whole; only compare jobs can split positions
+    let key_config = STATE.with(|s| s.borrow().key_config);
+    if !key_config.comparison_fits() {
+        return Err(PaillierError::InvalidInput(format!(
+            "Matrix jobs are not available with {}-bit keys, use start_compare_job per pair",
+            key_config.key_bits)));
+    }
+    
+    // The job runs with the caller's rights, so check them all up front
+    for (i, doc_id) in doc_ids.iter().enumerate() {
+        validate_doc_id(doc_id)?;
+        if doc_ids[..i].contains(doc_id) {
+            return Err(PaillierError::InvalidInput(format!("Duplicate document '{}'", doc_id)));
+        }
+        let doc = storage::get_document(doc_id)
+            .ok_or_else(|| PaillierError::DocumentNotFound(doc_id.clone()))?;
+        check_document_access(doc_id, &doc, Permission::Compare)?;
+        check_unpacked(doc_id, &doc)?;
+    }
+    
+    let job_id = enqueue_job(JobWork::Matrix(MatrixJob::new(doc_ids)))?;
+    ic_cdk::println!("Started matrix job {}", job_id);
+    
+    Ok(job_id)
+}
+
+#[update]
+fn start_compare_job(doc_id1: String, doc_id2: String) -> Result<u64, PaillierError> {
+    track_failure(start_compare(doc_id1, doc_id2))
+}
+
+fn start_compare(doc_id1: String, doc_id2: String) -> Result<u64, PaillierError> {
+    if storage::config().key_bits.is_none() {
+        return Err(PaillierError::NotInitialized);
+    }
+    check_not_threshold("Jobs")?;
+    validate_doc_id(&doc_id1)?;
+    validate_doc_id(&doc_id2)?;
+    
+    let doc1 = storage::get_document(&doc_id1)
+        .ok_or_else(|| PaillierError::DocumentNotFound(doc_id1.clone()))?;
+    let doc2 = storage::get_document(&doc_id2)
+        .ok_or_else(|| PaillierError::DocumentNotFound(doc_id2.clone()))?;
+    
+    check_document_access(&doc_id1, &doc1, Permission::Compare)?;
+    check_document_access(&doc_id2, &doc2, Permission::Compare)?;
+    check_unpacked(&doc_id1, &doc1)?;
+    check_unpacked(&doc_id2, &doc2)?;
+    
+    if doc1.tokens.len() != doc2.tokens.len() {
+        return Err(PaillierError::TokenCountMismatch { doc1: doc1.tokens.len(), doc2: doc2.tokens.len() });
+    }
+    
+    if doc1.tokens.is_empty() {
+        return Err(PaillierError::InvalidInput("Both documents are empty".to_string()));
+    }
+    
+    let tokens = doc1.tokens.len() as u64;
+    let job_id = enqueue_job(JobWork::Compare(CompareJob::new(doc_id1, doc_id2, tokens)))?;
+    ic_cdk::println!("Started compare job {} ({} tokens)", job_id, tokens);
+    
+    Ok(job_id)
+}
+
+// Store a new job for the caller and make sure the runner is scheduled
+fn enqueue_job(work: JobWork) -> Result<u64, PaillierError> {
+    jobs::prune_finished_jobs();
+    if storage::job_count() >= jobs::MAX_JOBS {
+        return Err(PaillierError::InvalidInput(format!(
+            "Too many jobs (max {}), try again later", jobs::MAX_JOBS)));
+    }
+    
+    let job_id = storage::allocate_job_id();
+    storage::insert_job(job_id, Job::new(caller(), work));
+    jobs::schedule();
+    
+    storage::update_metrics(|m| m.total_operations += 1);
+    Ok(job_id)
+}
+
+// Jobs are visible to the principal that started them
+fn owned_job(job_id: u64) -> Result<Job, PaillierError> {
+    let job = storage::get_job(job_id).ok_or(PaillierError::JobNotFound(job_id))?;
+    if job.owner != caller() {
+        return Err(PaillierError::Unauthorized(format!("job {} belongs to another principal", job_id)));
+    }
+    Ok(job)
+}
+
+#[query]
+fn get_job_status(job_id: u64) -> Result<JobInfo, PaillierError> {
+    let job = owned_job(job_id)?;
+    let (done, total) = job.progress();
+    
+    Ok(JobInfo {
+        job_id,
+        status: job.status,
+        done,
+        total,
+        created_at: job.created_at,
+        updated_at: job.updated_at,
+    })
+}
+
+#[query]
+fn get_job_result(job_id: u64) -> Result<JobResult, PaillierError> {
+    let job = owned_job(job_id)?;
+    
+    match job.status {
+        JobStatus::Completed => {}
+        JobStatus::Failed(e) => return Err(PaillierError::ComparisonFailed(e)),
+        _ => {
+            let (done, total) = job.progress();
+            return Err(PaillierError::JobNotFinished { done, total });
+        }
+    }
+    
+    match job.work {
+        JobWork::Matrix(matrix) => Ok(JobResult::Matrix(MatrixResult {
+            doc_ids: matrix.doc_ids,
+            entries: matrix.entries,
+        })),
+        JobWork::Compare(compare) => Ok(JobResult::Compare(CompareJobResult {
+            encrypted_score: compare.encrypted_matches.ok_or_else(|| PaillierError::ComparisonFailed(
+                format!("job {} finished without a score", job_id)))?,
+            doc_id1: compare.doc_id1,
+            doc_id2: compare.doc_id2,
+            tokens_compared: compare.next_index,
+        })),
+    }
+}
+
+// ===== QUERY METHODS =====
+#[query]
+fn get_stats() -> CanisterStats {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let m = storage::metrics();
+        
+        // Calculate memory usage from the configured ciphertext size, plus uploads in progress
+        let encrypted_token_size = state.key_config.ciphertext_bytes() as u64;
+        let stored_bytes = m.stored_tokens * encrypted_token_size + storage::pending_upload_bytes();
+        let memory_used_mb = stored_bytes as f64 / 1_048_576.0;
+        
+        CanisterStats {
+            total_operations: m.total_operations,
+            total_instructions: m.total_instructions_used,
+            documents_stored: storage::document_count(),
+            memory_used_mb,
+            encryption_operations: m.encryption_operations,
+            comparison_operations: m.comparison_operations,
+            failed_operations: m.failed_operations,
+            owner: storage::config().owner.map(|p| p.to_string()),
+            key_size_bits: state.key_config.key_bits as u32,
+            max_tokens_per_document: state.key_config.max_tokens(),
+            randomizers_ready: state.backend.as_ref()
+                .and_then(Backend::paillier)
+                .map_or(0, |paillier| pool::available(paillier.public_key())),
+        }
+    })
+}
+
+#[query]
+fn list_documents() -> Vec<(String, usize)> {
+    // Only documents the caller owns or has Read access to
+    let caller = caller();
+    let mut documents = Vec::new();
+    storage::for_each_document_meta(|doc_id, meta| {
+        if meta.allows(caller, Permission::Read) {
+            documents.push((doc_id.to_string(), meta.token_count()));
+        }
+    });
+    documents
+}
+
+#[query]
+fn get_document_acl(doc_id: String) -> Result<Vec<AccessGrant>, String> {
+    let doc = owned_document(&doc_id).map_err(|e| e.to_string())?;
+    Ok(doc.grants().to_vec())
+}
+
+#[query]
+fn health_check() -> String {
+    // The keypair may not be cached yet after an upgrade; the config is authoritative
+    let initialized = storage::config().key_bits.is_some();
+    let doc_count = storage::document_count();
+    let memory_kb = get_memory_usage_kb();
+    
+    format!(
+        "Status: {}, Documents: {}, Memory: {}KB", 
+        if initialized { "Ready" } else { "Not initialized" },
+        doc_count,
+        memory_kb
+    )
+}
+
+// ===== DOCUMENT ACCESS =====
+#[update]
+fn delete_document(doc_id: String) -> Result<(), String> {
+    validate_doc_id(&doc_id).map_err(|e| e.to_string())?;
+    
+    let doc = storage::get_document(&doc_id)
+        .ok_or_else(|| format!("Document '{}' not found", doc_id))?;
+    check_document_access(&doc_id, &doc, Permission::Delete).map_err(|e| e.to_string())?;
+    
+    storage::remove_document(&doc_id);
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.stored_tokens = m.stored_tokens.saturating_sub(doc.tokens.len() as u64);
+    });
+    
+    ic_cdk::println!("Deleted document '{}'", doc_id);
+    Ok(())
+}
+
+#[update]
+fn grant_access(doc_id: String, principal: Principal, permission: Permission) -> Result<(), String> {
+    let mut doc = owned_document(&doc_id).map_err(|e| e.to_string())?;
+    
+    if doc.grant(AccessGrant { principal, permission }) {
+        storage::insert_document(doc_id, doc);
+    }
+    Ok(())
+}
+
+#[update]
+fn revoke_access(doc_id: String, principal: Principal, permission: Permission) -> Result<(), String> {
+    let mut doc = owned_document(&doc_id).map_err(|e| e.to_string())?;
+    
+    if doc.revoke(&AccessGrant { principal, permission }) {
+        storage::insert_document(doc_id, doc);
+    }
+    Ok(())
+}
+
+// ===== ADMIN METHODS =====
+#[update]
+fn clear_all_documents() -> String {
+    let caller = caller();
+    
+    // Check owner
+    if !is_owner(caller) {
+        log_security_event(SecurityEventType::InvalidAccess, "clear_all_documents".to_string());
+        return "Unauthorized: only owner can clear documents".to_string();
+    }
+    
+    let count = storage::clear_documents();
+    
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.stored_tokens = 0;
+    });
+    
+    format!("Cleared {} documents", count)
+}
+
+#[update]
+async fn decrypt_score(ciphertext: Vec<u8>) -> Result<Nat, String> {
+    let plaintext = decrypt_as_owner(&ciphertext, "decrypt_score").await?;
+    Ok(Nat(plaintext))
+}
+
+#[update]
+async fn decrypt_overlap(ciphertext: Vec<u8>, set_size1: u64, set_size2: u64) -> Result<OverlapScore, String> {
+    let plaintext = decrypt_as_owner(&ciphertext, "decrypt_overlap").await?;
+    let intersection = u64::try_from(&plaintext)
+        .map_err(|_| "Decrypted intersection does not fit in 64 bits".to_string())?;
+    
+    Ok(OverlapScore {
+        intersection,
+        jaccard: similarity::jaccard(intersection, set_size1, set_size2),
+    })
+}
+
+// For results computed over signed or fixed-point plaintexts, such as
+// weighted scores and differences: residues above n/2 are negative
+#[update]
+async fn decrypt_signed(ciphertext: Vec<u8>, decimals: Option<u32>) -> Result<DecodedValue, String> {
+    let plaintext = decrypt_as_owner(&ciphertext, "decrypt_signed").await?;
+    
+    let modulus = STATE.with(|s| s.borrow().backend.as_ref().map(Backend::plaintext_modulus))
+        .ok_or_else(|| "Paillier not initialized".to_string())?;
+    let encoding = SignedEncoding::new(modulus)?;
+    let fixed_point = FixedPoint::for_decoding(decimals.unwrap_or(0), &encoding)?;
+    let value = encoding.decode(&plaintext)?;
+    
+    Ok(DecodedValue {
+        decimal: fixed_point.format(&value),
+        value: Int(value),
+        decimals: fixed_point.decimals(),
+    })
+}
+
+// Only the key holder may decrypt, and only while no threshold key is set
+async fn decrypt_as_owner(ciphertext: &[u8], endpoint: &str) -> Result<BigUint, String> {
+    check_direct_decryption(endpoint)?;
+    
+    ensure_keypair().await.map_err(|e| e.to_string())?;
+    
+    STATE.with(|state| {
+        let state = state.borrow();
+        
+        let backend = state.backend.as_ref()
+            .ok_or_else(|| "Paillier not initialized".to_string())?;
+        
+        let plaintext = backend.decrypt(ciphertext)?;
+        
+        storage::update_metrics(|m| m.total_operations += 1);
+        
+        Ok(plaintext)
+    })
+}
+
+fn check_direct_decryption(endpoint: &str) -> Result<(), String> {
+    if !is_owner(caller()) {
+        log_security_event(SecurityEventType::InvalidAccess, endpoint.to_string());
+        return Err("Unauthorized: only owner can decrypt".to_string());
+    }
+    if storage::config().threshold_key.is_some() {
+        return Err("Match counts are released by threshold decryption only (see submit_partial_decryption)".to_string());
+    }
+    Ok(())
+}
+
+// ===== DECRYPTION PROOFS =====
+// A decrypted score can be checked by anyone: the key holder proves that the
+// ciphertext encrypts the value it reports, without revealing the key.
+
+#[update]
+async fn decrypt_score_with_proof(ciphertext: Vec<u8>) -> Result<ProvenDecryption, String> {
+    check_direct_decryption("decrypt_score_with_proof")?;
+    
+    // The proof needs fresh randomness
+    rng::ensure_seeded().await?;
+    ensure_keypair().await.map_err(|e| e.to_string())?;
+    
+    STATE.with(|state| {
+        let state = state.borrow();
+        
+        let paillier = paillier_backend(&state).map_err(|e| e.to_string())?;
+        let pk = paillier.public_key();
+        
+        let c = BigUint::from_bytes_be(&ciphertext);
+        let (value, proof) = rng::with_rng(|rng| proofs::prove_decryption(pk, paillier.private_key(), &c, rng))
+            .and_then(|proven| proven)?;
+        
+        storage::update_metrics(|m| m.total_operations += 1);
+        
+        Ok(ProvenDecryption { value: Nat(value), proof: proof.to_bytes(pk) })
+    })
+}
+
+// An update call so the answer goes through consensus (and the keypair can
+// be re-derived after an upgrade); anyone may call it
+#[update]
+async fn verify_decryption(ciphertext: Vec<u8>, claimed_value: Nat, proof: Vec<u8>) -> Result<bool, PaillierError> {
+    ensure_keypair().await?;
+    
+    STATE.with(|state| {
+        let state = state.borrow();
+        let pk = paillier_backend(&state)?.public_key();
+        
+        let proof = proofs::DecryptionProof::from_bytes(pk, &proof).map_err(PaillierError::InvalidInput)?;
+        let c = BigUint::from_bytes_be(&ciphertext);
+        
+        match proofs::verify_decryption(pk, &c, &claimed_value.0, &proof) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                ic_cdk::println!("Decryption proof rejected: {}", e);
+                Ok(false)
+            }
+        }
+    })
+}
+
+// ===== THRESHOLD DECRYPTION =====
+// Once a threshold key is configured, documents are encrypted under it instead
+// of the canister key, so the canister can't decrypt them either.
+// compare_documents blinds and shuffles the differences under that key and
+// opens a decryption request; the parties submit partial decryptions of every
+// difference with proofs, and once `threshold` of them have verified the
+// canister combines them and publishes how many are zero. Whoever combines
+// the shares sees the blinded values, which give the count but not the
+// positions. Endpoints that zero-test under the canister key are unavailable.
+
+#[update]
+fn configure_threshold_key(config: ThresholdKeyConfig) -> Result<(), PaillierError> {
+    track_failure(configure_threshold(config))
+}
+
+fn configure_threshold(config: ThresholdKeyConfig) -> Result<(), PaillierError> {
+    if !is_owner(caller()) {
+        log_security_event(SecurityEventType::InvalidAccess, "configure_threshold_key".to_string());
+        return Err(PaillierError::Unauthorized("only owner can configure threshold decryption".to_string()));
+    }
+    // One-way: switching back would let the owner decrypt on their own again
+    if storage::config().threshold_key.is_some() {
+        return Err(PaillierError::InvalidInput("A threshold key is already configured".to_string()));
+    }
+    if storage::config().key_bits.is_none() {
+        return Err(PaillierError::NotInitialized);
+    }
+    
+    let parties = config.parties.len();
+    if parties == 0 || parties > MAX_THRESHOLD_PARTIES {
+        return Err(PaillierError::InvalidInput(format!(
+            "Threshold decryption needs 1 to {} parties", MAX_THRESHOLD_PARTIES)));
+    }
+    if config.verification_keys.len() != parties {
+        return Err(PaillierError::InvalidInput(format!(
+            "{} parties but {} verification keys", parties, config.verification_keys.len())));
+    }
+    if (1..parties).any(|i| config.parties[..i].contains(&config.parties[i])) {
+        return Err(PaillierError::InvalidInput("Parties must be distinct".to_string()));
+    }
+    let key = parse_threshold_key(&config)?;
+    let key_config = STATE.with(|s| s.borrow().key_config);
+    check_threshold_key_fits(&key, &key_config)?;
+    
+    // Documents under the canister key could still be compared by the canister alone
+    if storage::document_count() > 0 || storage::upload_count() > 0 {
+        return Err(PaillierError::InvalidInput(
+            "Delete all documents and pending uploads before enabling threshold decryption".to_string()));
+    }
+    
+    storage::update_config(|c| c.threshold_key = Some(config.clone()));
+    storage::update_metrics(|m| m.total_operations += 1);
+    
+    ic_cdk::println!("Threshold decryption enabled: {} of {} parties", config.threshold, parties);
+    Ok(())
+}
+
+// Costs are measured with the canister key, so the threshold key may not be
+// larger, and a whole comparison has to fit one message (no jobs)
+fn check_threshold_key_fits(key: &ThresholdKey, key_config: &KeyConfig) -> Result<(), PaillierError> {
+    if key_config.scheme != HeScheme::Paillier {
+        return Err(PaillierError::InvalidInput(
+            "Threshold decryption needs the canister initialized with Paillier".to_string()));
+    }
+    if key.pk.n.bits() > key_config.key_bits as u64 {
+        return Err(PaillierError::InvalidInput(format!(
+            "The threshold key may have at most {} bits, the canister key size", key_config.key_bits)));
+    }
+    if !key_config.comparison_fits() {
+        return Err(PaillierError::InvalidInput(format!(
+            "Threshold decryption is not available with {}-bit keys", key_config.key_bits)));
+    }
+    Ok(())
+}
+
+#[query]
+fn get_threshold_key() -> Option<ThresholdKeyConfig> {
+    storage::config().threshold_key
+}
+
+// A party decrypts every ciphertext of the request with its share; `values`
+// and `proofs` follow the order of the request's ciphertexts
+#[update]
+async fn submit_partial_decryption(
+    request_id: u64,
+    values: Vec<Vec<u8>>,
+    proofs: Vec<Vec<u8>>,
+) -> Result<DecryptionStatus, PaillierError> {
+    track_failure(submit_partial(request_id, values, proofs).await)
+}
+
+async fn submit_partial(request_id: u64, values: Vec<Vec<u8>>, proofs: Vec<Vec<u8>>) -> Result<DecryptionStatus, PaillierError> {
+    let config = storage::config().threshold_key
+        .ok_or_else(|| PaillierError::InvalidInput("No threshold key configured".to_string()))?;
+    let key = parse_threshold_key(&config)?;
+    
+    let index = config.parties.iter().position(|p| *p == caller())
+        .map(|i| i + 1)
+        .ok_or_else(|| PaillierError::Unauthorized("caller is not a threshold party".to_string()))?;
+    
+    let request = storage::get_decryption(request_id)
+        .ok_or(PaillierError::DecryptionNotFound(request_id))?;
+    if request.plaintext.is_some() {
+        return Ok(decryption_status(request_id, &request, &config));
+    }
+    check_new_partial(&request, index)?;
+    if values.len() != request.ciphertexts.len() || proofs.len() != request.ciphertexts.len() {
+        return Err(PaillierError::InvalidInput(format!(
+            "{} ciphertexts but {} values and {} proofs", request.ciphertexts.len(), values.len(), proofs.len())));
+    }
+    
+    let partials = values.iter()
+        .zip(&proofs)
+        .map(|(value, proof)| Ok(threshold::PartialDecryption {
+            share: threshold::DecryptionShare { index, value: BigUint::from_bytes_be(value) },
+            proof: threshold::PartialProof::from_bytes(&key.pk, proof)?,
+        }))
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(PaillierError::InvalidInput)?;
+    let ciphertexts: Vec<BigUint> = request.ciphertexts.iter().map(|c| BigUint::from_bytes_be(c)).collect();
+    
+    // A proof takes two modpows with exponents over twice |n|: about six encryptions
+    let per_token = STATE.with(|s| s.borrow().key_config.instructions_per_token());
+    let per_proof = 6 * per_token;
+    let mut verified = 0;
+    let (instructions_used, messages) = run_in_messages(partials.len(), per_proof, "partial decryption proof", |batch| {
+        for (c, partial) in ciphertexts[verified..verified + batch].iter().zip(&partials[verified..]) {
+            threshold::verify_partial(&key, c, partial).map_err(PaillierError::InvalidInput)?;
+        }
+        verified += batch;
+        Ok(())
+    })
+    .await?;
+    
+    ic_cdk::println!("Verified {} partial decryptions of party {} over {} messages", partials.len(), index, messages);
+    
+    // Re-read: the request may have been released, or this party's other call
+    // accepted, while we yielded
+    let mut request = storage::get_decryption(request_id)
+        .ok_or(PaillierError::DecryptionNotFound(request_id))?;
+    if request.plaintext.is_some() {
+        return Ok(decryption_status(request_id, &request, &config));
+    }
+    check_new_partial(&request, index)?;
+    
+    request.partials.push(PartialShare {
+        index: index as u32,
+        values: partials.iter().map(|p| p.share.value.to_bytes_be()).collect(),
+    });
+    storage::insert_decryption(request_id, request.clone());
+    
+    // Enough verified partials: combine them, keeping only the count. Each
+    // share is raised to a small exponent; budget an encryption to start with.
+    let mut instructions_used = instructions_used;
+    if request.partials.len() >= key.threshold {
+        let per_position = key.threshold as u64 * per_token;
+        let (mut matches, mut combined) = (0, 0);
+        instructions_used += run_in_messages(request.ciphertexts.len(), per_position, "combination", |batch| {
+            matches += count_released_matches(&key, &request.partials, combined..combined + batch)?;
+            combined += batch;
+            Ok(())
+        })
+        .await?
+        .0;
+        
+        // Another party's call may have released it first, with the same count
+        request = storage::get_decryption(request_id)
+            .ok_or(PaillierError::DecryptionNotFound(request_id))?;
+        if request.plaintext.is_none() {
+            request.plaintext = Some(matches.to_be_bytes().to_vec());
+            storage::insert_decryption(request_id, request.clone());
+            ic_cdk::println!("Decryption request {} released", request_id);
+        }
+    }
+    
+    let total_instructions = instructions_used + instruction_counter();
+    storage::update_metrics(|m| {
+        m.total_operations += 1;
+        m.total_instructions_used += total_instructions;
+    });
+    
+    Ok(decryption_status(request_id, &request, &config))
+}
+
+fn check_new_partial(request: &DecryptionRequest, index: usize) -> Result<(), PaillierError> {
+    if request.partials.iter().any(|p| p.index as usize == index) {
+        return Err(PaillierError::InvalidInput(format!("Party {} already submitted", index)));
+    }
+    Ok(())
+}
+
+// Combine the blinded differences at `positions` from the parties' shares and
+// count the zeros; the other values are random and dropped
+fn count_released_matches(
+    key: &ThresholdKey,
+    partials: &[PartialShare],
+    positions: std::ops::Range<usize>,
+) -> Result<u64, PaillierError> {
+    let mut matches = 0;
+    for i in positions {
+        let shares: Vec<threshold::DecryptionShare> = partials.iter()
+            .map(|p| threshold::DecryptionShare { index: p.index as usize, value: BigUint::from_bytes_be(&p.values[i]) })
+            .collect();
+        if threshold::combine(key, &shares).map_err(PaillierError::ComparisonFailed)?.is_zero() {
+            matches += 1;
+        }
+    }
+    Ok(matches)
+}
+
+// Visible to the requester and the parties
+#[query]
+fn get_decryption(request_id: u64) -> Result<DecryptionStatus, PaillierError> {
+    let config = storage::config().threshold_key
+        .ok_or_else(|| PaillierError::InvalidInput("No threshold key configured".to_string()))?;
+    let request = storage::get_decryption(request_id)
+        .ok_or(PaillierError::DecryptionNotFound(request_id))?;
+    
+    let caller = caller();
+    if request.requester != caller && !config.parties.contains(&caller) {
+        return Err(PaillierError::Unauthorized(format!(
+            "decryption request {} belongs to another principal", request_id)));
+    }
+    Ok(decryption_status(request_id, &request, &config))
+}
+
+fn parse_threshold_key(config: &ThresholdKeyConfig) -> Result<ThresholdKey, PaillierError> {
+    ThresholdKey::new(
+        BigUint::from_bytes_be(&config.n),
+        config.threshold as usize,
+        BigUint::from_bytes_be(&config.v),
+        config.verification_keys.iter().map(|vk| BigUint::from_bytes_be(vk)).collect(),
+    )
+    .map_err(PaillierError::InvalidInput)
+}
+
+fn threshold_key() -> Result<Option<ThresholdKey>, PaillierError> {
+    storage::config().threshold_key.as_ref().map(parse_threshold_key).transpose()
+}
+
+// For features that zero-test or decrypt under the canister key
+fn check_not_threshold(feature: &str) -> Result<(), PaillierError> {
+    if storage::config().threshold_key.is_some() {
+        return Err(PaillierError::InvalidInput(format!(
+            "{} are not available with threshold decryption", feature)));
+    }
+    Ok(())
+}
+
+// The key documents are encrypted under (see `encrypt_tokens`)
+fn document_key(state: &CanisterState) -> Result<simple_paillier::PublicKey, PaillierError> {
+    match threshold_key()? {
+        Some(key) => Ok(key.pk),
+        None => Ok(paillier_backend(state)?.public_key().clone()),
+    }
+}
+
+// Queue blinded differences under the threshold key for the parties
+fn open_decryption(ciphertexts: Vec<Vec<u8>>) -> Result<u64, PaillierError> {
+    let expired = storage::remove_decryptions_before(time().saturating_sub(DECRYPTION_RETENTION_NS));
+    if expired > 0 {
+        ic_cdk::println!("Dropped {} expired decryption requests", expired);
+    }
+    if storage::decryption_count() >= MAX_DECRYPTION_REQUESTS {
+        return Err(PaillierError::InvalidInput(format!(
+            "Too many decryption requests (max {}), try again later", MAX_DECRYPTION_REQUESTS)));
+    }
+    
+    let request_id = storage::allocate_decryption_id();
+    storage::insert_decryption(request_id, DecryptionRequest {
+        requester: caller(),
+        ciphertexts,
+        created_at: time(),
+        partials: Vec::new(),
+        plaintext: None,
+    });
+    Ok(request_id)
+}
+
+fn decryption_status(request_id: u64, request: &DecryptionRequest, config: &ThresholdKeyConfig) -> DecryptionStatus {
+    DecryptionStatus {
+        request_id,
+        ciphertexts: request.ciphertexts.clone(),
+        threshold: config.threshold,
+        partials_received: request.partials.len() as u32,
+        plaintext: request.plaintext.as_ref().map(|bytes| Nat(BigUint::from_bytes_be(bytes))),
+    }
+}
+
+// ===== VETKEYS METHODS =====
+// check_vetkd_support and get_vetkd_info are defined in vetkd_check.rs
+
+#[query]
+fn get_vetkd_metrics() -> VetKeyMetrics {
+    vetkd_utils::get_vetkd_metrics()
+}
+
+#[query]
+fn get_cache_stats() -> CacheStats {
+    VetKeyManager::get_cache_stats()
+}
+
+#[query]
+fn get_security_log() -> Result<Vec<SecurityEvent>, String> {
+    // Events name principals, so only the owner may read them
+    if !is_owner(caller()) {
+        return Err("Unauthorized: only owner can read the security log".to_string());
+    }
+    Ok(vetkd_utils::get_security_log())
+}
+
+#[update]
+fn clear_vetkd_cache() -> String {
+    if !is_owner(caller()) {
+        log_security_event(SecurityEventType::InvalidAccess, "clear_vetkd_cache".to_string());
+        return "Unauthorized: only owner can clear the key cache".to_string();
+    }
+    
+    VetKeyManager::clear_cache();
+    "Key cache cleared".to_string()
+}
+
+#[update]
+fn reset_vetkd_metrics() -> String {
+    if !is_owner(caller()) {
+        return "Unauthorized: only owner can reset metrics".to_string();
+    }
+    
+    vetkd_utils::reset_metrics();
+    "vetKeys metrics reset".to_string()
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primes::PrimeType;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn batches_follow_the_budget() {
+        assert_eq!(units_that_fit(0, INSTRUCTION_LIMIT_SAFETY / 40), 40);
+        assert_eq!(units_that_fit(INSTRUCTION_LIMIT_SAFETY / 2, INSTRUCTION_LIMIT_SAFETY / 40), 20);
+        assert_eq!(units_that_fit(INSTRUCTION_LIMIT_SAFETY, 1), 0);
+        assert_eq!(units_that_fit(INSTRUCTION_LIMIT_SAFETY + 1, 1), 0);
+        assert_eq!(units_that_fit(0, INSTRUCTION_LIMIT_SAFETY + 1), 0);
+    }
+
+    #[test]
+    fn larger_keys_fit_the_update_limit() {
+        for bits in [512, 1024, 2048] {
+            let config = KeyConfig::new(HeScheme::Paillier, bits).unwrap();
+            assert!(config.comparison_fits());
+            assert!(config.max_overlap_pairs() > 0);
+        }
+
+        // 3072 bits: encryption fits, a whole zero test doesn't
+        let config = KeyConfig::new(HeScheme::Paillier, 3072).unwrap();
+        assert!(!config.comparison_fits());
+        assert_eq!(config.max_overlap_pairs(), 0);
+        assert!(config.max_tokens() > 0);
+
+        assert!(KeyConfig::new(HeScheme::Paillier, 4096).is_err());
+        assert!(KeyConfig::new(HeScheme::ElGamal, 512).is_err());
+    }
+
+    #[test]
+    fn threshold_comparison_releases_only_the_count() {
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let (key, shares) = threshold::deal(simple_paillier::MIN_KEY_BITS, 2, 3, &mut rng).unwrap();
+        let mut encrypt = |tokens: &[u8]| -> Vec<BigUint> {
+            tokens.iter().map(|&t| key.encrypt_with_rng(&[t], &mut rng).unwrap()).collect()
+        };
+        let (doc1, doc2) = (encrypt(&[1, 2, 3, 4, 5]), encrypt(&[1, 9, 3, 7, 5]));
+
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let blinded = similarity::blinded_differences(&key, &doc1, &doc2, &mut rng, &mut |_| Ok(())).unwrap();
+        assert!(blinded.iter().all(|c| key.decrypt(c).is_err()));
+
+        let partials: Vec<PartialShare> = [&shares[0], &shares[2]].iter()
+            .map(|share| PartialShare {
+                index: share.index as u32,
+                values: blinded.iter()
+                    .map(|c| {
+                        let partial = threshold::partial_decrypt(&key, share, c, &mut rng).unwrap();
+                        assert!(threshold::verify_partial(&key, c, &partial).is_ok());
+                        partial.share.value.to_bytes_be()
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        // Batches add up to the whole; one party alone can't combine anything
+        assert_eq!(count_released_matches(&key, &partials, 0..5).unwrap(), 3);
+        let batched = count_released_matches(&key, &partials, 0..2).unwrap()
+            + count_released_matches(&key, &partials, 2..5).unwrap();
+        assert_eq!(batched, 3);
+        assert!(count_released_matches(&key, &partials[..1], 0..5).is_err());
+    }
+
+    #[test]
+    fn threshold_keys_must_fit_the_canister_key() {
+        // Only the modulus size matters here
+        let key = |bits: u32| {
+            let n = (BigUint::from(1u32) << (bits - 1)) + 1u32;
+            ThresholdKey::new(n, 1, BigUint::from(2u32), vec![BigUint::from(2u32)]).unwrap()
+        };
+        let (small, large) = (key(512), key(1024));
+
+        let paillier_512 = KeyConfig::new(HeScheme::Paillier, 512).unwrap();
+        assert!(check_threshold_key_fits(&small, &paillier_512).is_ok());
+        assert!(check_threshold_key_fits(&large, &paillier_512).is_err());
+        assert!(check_threshold_key_fits(&large, &KeyConfig::new(HeScheme::Paillier, 1024).unwrap()).is_ok());
+
+        // No whole comparison per message at 3072 bits, and no Paillier at all with ElGamal
+        assert!(check_threshold_key_fits(&small, &KeyConfig::new(HeScheme::Paillier, 3072).unwrap()).is_err());
+        assert!(check_threshold_key_fits(&small, &KeyConfig::new(HeScheme::ElGamal, 256).unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejected_chunks_store_nothing() {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let paillier = SimplePaillier::generate(512, PrimeType::Standard, &mut rng);
+        let pk = paillier.public_key();
+        let (c, proof) = proofs::encrypt_with_range_proof(pk, &[3u8; 32], &mut rng).unwrap();
+        let (ciphertexts, mut range_proofs) = (vec![c.to_bytes_be()], vec![proof.to_bytes(pk)]);
+
+        storage::begin_upload("doc".to_string(), Principal::anonymous(), 1);
+
+        // A bad bit proof: verification stops there and the check is never finished
+        let last = range_proofs[0].len() - 1;
+        range_proofs[0][last] ^= 0x01;
+        let mut check = proofs::RangeCheck::new(pk, &ciphertexts, &range_proofs).unwrap();
+        assert!(check.verify_bits(pk, proofs::RANGE_BITS).is_err());
+        assert!(store_proven_chunk("doc", check, 2).is_err());
+
+        let upload = storage::get_upload("doc").unwrap();
+        assert_eq!((upload.chunks, upload.tokens, upload.updated_at), (0, 0, 1));
+        assert_eq!(storage::pending_upload_bytes(), 0);
+
+        // The same chunk with its real proof goes in
+        range_proofs[0][last] ^= 0x01;
+        let mut check = proofs::RangeCheck::new(pk, &ciphertexts, &range_proofs).unwrap();
+        check.verify_bits(pk, proofs::RANGE_BITS).unwrap();
+        let upload = store_proven_chunk("doc", check, 3).unwrap();
+        assert_eq!((upload.chunks, upload.tokens), (1, 1));
+        assert_eq!(storage::take_upload("doc").unwrap().1, ciphertexts);
+    }
+}
@@ -0,0 +1,267 @@
+//! Packing of several small values into one Paillier plaintext.
+//!
+//! A plaintext is split into fixed-width slots, slot i holding x_i at bit
+//! offset i * slot_bits. Each slot is `value_bits` wide plus `guard_bits` of
+//! headroom, so slot-wise sums and small multiples can grow without carrying
+//! into the next slot. `PackedCiphertext` tracks how wide slot values may have
+//! grown and the operations refuse anything that could overflow a slot.
+//!
+//! With 16-bit values and one guard bit a 512-bit key holds 30 slots, so
+//! storage and encryption cost drop by the same factor.
+
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::simple_paillier::{PrivateKey, PublicKey};
+
+// Domain separator for token fingerprints
+const FINGERPRINT_DOMAIN: &[u8] = b"paillier-packing-fp-v1";
+
+/// Slot geometry for one key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotLayout {
+    pub value_bits: usize,
+    pub slot_bits: usize, // value_bits + guard_bits
+    pub slots: usize,     // Per plaintext
+}
+
+/// Ciphertext of up to `slots` packed values
+#[derive(Clone, Debug)]
+pub struct PackedCiphertext {
+    pub c: BigUint,
+    pub len: usize,  // Slots in use; the rest hold zero
+    pub bits: usize, // Every slot value is below 2^bits
+}
+
+impl SlotLayout {
+    /// Layout for plaintexts of `plaintext_bits` bits (n.bits() - 1 keeps
+    /// every packed value below n)
+    pub fn new(plaintext_bits: usize, value_bits: usize, guard_bits: usize) -> Result<Self, String> {
+        let slot_bits = value_bits + guard_bits;
+        if value_bits == 0 || slot_bits > 64 {
+            return Err(format!("Invalid slot: {} value bits + {} guard bits (max 64 in total)",
+                value_bits, guard_bits));
+        }
+        let slots = plaintext_bits / slot_bits;
+        if slots == 0 {
+            return Err(format!("A {}-bit slot does not fit a {}-bit plaintext", slot_bits, plaintext_bits));
+        }
+        Ok(SlotLayout { value_bits, slot_bits, slots })
+    }
+
+    pub fn for_key(pk: &PublicKey, value_bits: usize, guard_bits: usize) -> Result<Self, String> {
+        Self::new(pk.n.bits() as usize - 1, value_bits, guard_bits)
+    }
+
+    /// Ciphertexts needed for `values` packed values
+    pub fn ciphertexts_for(&self, values: usize) -> usize {
+        values.div_ceil(self.slots)
+    }
+
+    /// Slots used by ciphertext `index` of a packed sequence of `values`
+    pub fn len_of(&self, index: usize, values: usize) -> usize {
+        values.saturating_sub(index * self.slots).min(self.slots)
+    }
+
+    fn value_max(&self) -> u64 {
+        u64::MAX >> (64 - self.value_bits)
+    }
+
+    /// sum_i x_i * 2^(i * slot_bits)
+    pub fn encode(&self, values: &[u64]) -> Result<BigUint, String> {
+        if values.len() > self.slots {
+            return Err(format!("{} values do not fit {} slots", values.len(), self.slots));
+        }
+        if let Some(x) = values.iter().find(|&&x| x > self.value_max()) {
+            return Err(format!("Value {} does not fit {} bits", x, self.value_bits));
+        }
+
+        Ok(values.iter().rev().fold(BigUint::zero(), |acc, &x| (acc << self.slot_bits) + x))
+    }
+
+    /// The first `len` slots of a plaintext
+    pub fn decode(&self, plaintext: &BigUint, len: usize) -> Vec<u64> {
+        let mask = (BigUint::one() << self.slot_bits) - 1u32;
+        (0..len.min(self.slots))
+            .map(|i| {
+                let slot = (plaintext >> (i * self.slot_bits)) & &mask;
+                slot.iter_u64_digits().next().unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// A stored ciphertext of fresh (not yet operated on) values
+    pub fn wrap(&self, c: BigUint, len: usize) -> PackedCiphertext {
+        PackedCiphertext { c, len, bits: self.value_bits }
+    }
+
+    /// Encrypt with the canister RNG (and randomizer pool)
+    pub fn encrypt(&self, pk: &PublicKey, values: &[u64]) -> Result<PackedCiphertext, String> {
+        let c = pk.encrypt(&self.encode(values)?.to_bytes_be())?;
+        Ok(self.wrap(c, values.len()))
+    }
+
+    pub fn encrypt_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        pk: &PublicKey,
+        values: &[u64],
+        rng: &mut R,
+    ) -> Result<PackedCiphertext, String> {
+        let plaintext = self.encode(values)?;
+        let c = pk.encrypt_with_rng(&plaintext.to_bytes_be(), rng)?;
+        Ok(self.wrap(c, values.len()))
+    }
+
+    pub fn decrypt(&self, sk: &PrivateKey, c: &PackedCiphertext) -> Result<Vec<u64>, String> {
+        Ok(self.decode(&sk.decrypt(&c.c)?, c.len))
+    }
+
+    // Width after an operation, refused if it would spill into the next slot
+    fn check_bits(&self, bits: usize) -> Result<usize, String> {
+        if bits > self.slot_bits {
+            return Err(format!("Slot overflow: values may need {} bits, slots have {}", bits, self.slot_bits));
+        }
+        Ok(bits)
+    }
+
+    /// Slot-wise Enc(x_i + y_i)
+    pub fn add(&self, pk: &PublicKey, a: &PackedCiphertext, b: &PackedCiphertext) -> Result<PackedCiphertext, String> {
+        Ok(PackedCiphertext {
+            c: pk.add(&a.c, &b.c),
+            len: a.len.max(b.len),
+            bits: self.check_bits(a.bits.max(b.bits) + 1)?,
+        })
+    }
+
+    /// Slot-wise Enc(k * x_i)
+    pub fn mul_plain(&self, pk: &PublicKey, c: &PackedCiphertext, k: u64) -> Result<PackedCiphertext, String> {
+        // k * x < k * 2^bits <= 2^(bits + bit length of k - 1)
+        let k_bits = (64 - k.saturating_sub(1).leading_zeros()) as usize;
+        Ok(PackedCiphertext {
+            c: pk.mul_plain(&c.c, &BigUint::from(k)),
+            len: c.len,
+            bits: self.check_bits(c.bits + k_bits)?,
+        })
+    }
+
+    /// Slot-wise Enc(2^value_bits - 1 - x_i). No slot borrows, since every
+    /// x_i is at most 2^value_bits - 1; unused slots become the maximum too.
+    pub fn complement(&self, pk: &PublicKey, c: &PackedCiphertext) -> Result<PackedCiphertext, String> {
+        if c.bits > self.value_bits {
+            return Err(format!("Cannot complement {}-bit slot values", c.bits));
+        }
+        let all_max = self.encode(&vec![self.value_max(); self.slots])?;
+        Ok(PackedCiphertext {
+            c: pk.add_plain(&pk.neg(&c.c)?, &all_max),
+            len: c.len,
+            bits: self.value_bits,
+        })
+    }
+
+    // ===== EQUALITY =====
+    // x_i + (2^v - 1 - y_i) is 2^v - 1 exactly when x_i = y_i, so one
+    // decryption tests a whole ciphertext worth of positions. Unlike the
+    // blinded per-token protocol in similarity.rs the key holder sees every
+    // slot's difference, not just whether it is zero.
+
+    /// Evaluator side: slot-wise Enc(x_i - y_i + 2^value_bits - 1)
+    pub fn slot_differences(&self, pk: &PublicKey, a: &PackedCiphertext, b: &PackedCiphertext) -> Result<PackedCiphertext, String> {
+        if a.len != b.len {
+            return Err(format!("Slot count mismatch: {} vs {}", a.len, b.len));
+        }
+        self.add(pk, a, &self.complement(pk, b)?)
+    }
+
+    /// Key-holder side: number of equal positions behind `slot_differences`
+    pub fn count_equal(&self, sk: &PrivateKey, differences: &PackedCiphertext) -> Result<u64, String> {
+        let equal = self.value_max();
+        Ok(self.decrypt(sk, differences)?.iter().filter(|&&d| d == equal).count() as u64)
+    }
+}
+
+/// `bits`-bit fingerprint of a token for packed storage; distinct tokens
+/// collide with probability 2^-bits
+pub fn fingerprint(token: &[u8], bits: usize) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(FINGERPRINT_DOMAIN);
+    hasher.update(token);
+    let digest = hasher.finalize();
+
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix) >> (64 - bits.clamp(1, 64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primes::PrimeType;
+    use crate::simple_paillier::SimplePaillier;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    const MAX: u64 = 0xffff;
+
+    fn setup() -> (SimplePaillier, SlotLayout, ChaCha20Rng) {
+        let mut rng = ChaCha20Rng::seed_from_u64(4);
+        let paillier = SimplePaillier::generate(512, PrimeType::Standard, &mut rng);
+        let layout = SlotLayout::for_key(paillier.public_key(), 16, 1).unwrap();
+        (paillier, layout, rng)
+    }
+
+    #[test]
+    fn layout_and_encoding() {
+        let (paillier, layout, mut rng) = setup();
+        assert_eq!(layout.slots, 30);
+        assert_eq!(layout.ciphertexts_for(61), 3);
+        assert_eq!(layout.len_of(2, 61), 1);
+        assert!(layout.encode(&[MAX + 1]).is_err());
+        assert!(layout.encode(&vec![0; 31]).is_err());
+
+        let values: Vec<u64> = (0..30).map(|i| i * 2000 + 7).collect();
+        let c = layout.encrypt_with_rng(paillier.public_key(), &values, &mut rng).unwrap();
+        assert_eq!(layout.decrypt(paillier.private_key(), &c).unwrap(), values);
+    }
+
+    #[test]
+    fn add_and_mul_plain_refuse_to_overflow_a_slot() {
+        let (paillier, layout, mut rng) = setup();
+        let (pk, sk) = (paillier.public_key(), paillier.private_key());
+        let a = layout.encrypt_with_rng(pk, &[MAX, 0, 1], &mut rng).unwrap();
+        let b = layout.encrypt_with_rng(pk, &[MAX, MAX, 2], &mut rng).unwrap();
+
+        // The guard bit absorbs one addition without carrying into the next slot
+        let sum = layout.add(pk, &a, &b).unwrap();
+        assert_eq!(layout.decrypt(sk, &sum).unwrap(), vec![2 * MAX, MAX, 3]);
+        assert!(layout.add(pk, &sum, &a).is_err());
+
+        let doubled = layout.mul_plain(pk, &a, 2).unwrap();
+        assert_eq!(layout.decrypt(sk, &doubled).unwrap(), vec![2 * MAX, 0, 2]);
+        assert!(layout.mul_plain(pk, &a, 3).is_err());
+        assert!(layout.mul_plain(pk, &sum, 2).is_err());
+        assert_eq!(layout.mul_plain(pk, &sum, 1).unwrap().bits, 17);
+    }
+
+    #[test]
+    fn complement_and_count_equal() {
+        let (paillier, layout, mut rng) = setup();
+        let (pk, sk) = (paillier.public_key(), paillier.private_key());
+        let x = layout.encrypt_with_rng(pk, &[0, 5, MAX, 9], &mut rng).unwrap();
+        let y = layout.encrypt_with_rng(pk, &[0, 6, MAX, 8], &mut rng).unwrap();
+
+        let complement = layout.complement(pk, &x).unwrap();
+        assert_eq!(layout.decrypt(sk, &complement).unwrap(), vec![MAX, MAX - 5, 0, MAX - 9]);
+        // Unused slots are complemented too
+        assert_eq!(layout.decode(&sk.decrypt(&complement.c).unwrap(), 5)[4], MAX);
+        assert!(layout.complement(pk, &layout.add(pk, &x, &y).unwrap()).is_err());
+
+        let differences = layout.slot_differences(pk, &x, &y).unwrap();
+        assert_eq!(layout.count_equal(sk, &differences).unwrap(), 2);
+        assert_eq!(layout.count_equal(sk, &layout.slot_differences(pk, &x, &x).unwrap()).unwrap(), 4);
+
+        let shorter = layout.encrypt_with_rng(pk, &[0, 5, MAX], &mut rng).unwrap();
+        assert!(layout.slot_differences(pk, &x, &shorter).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Pool of precomputed Paillier randomizers r^n mod n^2.
+//!
+//! With g = n + 1 an encryption is (1 + m*n) * r^n mod n^2, so once r^n is
+//! known it costs two multiplications instead of a full-size modpow. A timer
+//! tops the pool up between calls; `PublicKey::encrypt` takes from it and
+//! computes r^n inline when it runs dry. Each randomizer is handed out once.
+//!
+//! The pool only lives on the heap: it belongs to one modulus, is discarded
+//! when another key refills it and starts empty after an upgrade.
+
+use num_bigint::BigUint;
+use std::cell::RefCell;
+
+use crate::rng;
+use crate::simple_paillier::PublicKey;
+
+/// Randomizers kept ready; a few maximal documents' worth at 512 bits
+pub const POOL_TARGET: usize = 256;
+
+#[derive(Default)]
+struct Pool {
+    n: Option<BigUint>, // Modulus the randomizers belong to
+    randomizers: Vec<BigUint>,
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool::default());
+}
+
+/// A fresh r^n mod n^2 for `pk`, if one is ready
+pub fn take(pk: &PublicKey) -> Option<BigUint> {
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.n.as_ref() != Some(&pk.n) {
+            return None;
+        }
+        pool.randomizers.pop()
+    })
+}
+
+/// Randomizers ready for `pk`
+pub fn available(pk: &PublicKey) -> usize {
+    POOL.with(|pool| {
+        let pool = pool.borrow();
+        if pool.n.as_ref() == Some(&pk.n) { pool.randomizers.len() } else { 0 }
+    })
+}
+
+/// Add randomizers for `pk` until the pool is full or `has_budget` says
+/// stop (checked before each one); returns how many were added
+pub fn refill(pk: &PublicKey, mut has_budget: impl FnMut() -> bool) -> Result<usize, String> {
+    let mut added = 0;
+    loop {
+        let missing = POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.n.as_ref() != Some(&pk.n) {
+                *pool = Pool { n: Some(pk.n.clone()), randomizers: Vec::new() };
+            }
+            POOL_TARGET.saturating_sub(pool.randomizers.len())
+        });
+        if missing == 0 || !has_budget() {
+            return Ok(added);
+        }
+
+        let randomizer = rng::with_rng(|rng| pk.randomizer_with_rng(rng))?;
+        POOL.with(|pool| pool.borrow_mut().randomizers.push(randomizer));
+        added += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primes::PrimeType;
+    use crate::simple_paillier::SimplePaillier;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use std::collections::HashSet;
+
+    fn keypair(seed: u64) -> SimplePaillier {
+        SimplePaillier::generate(512, PrimeType::Standard, &mut ChaCha20Rng::seed_from_u64(seed))
+    }
+
+    // Refill at most `count` randomizers
+    fn refill_some(pk: &PublicKey, count: usize) -> usize {
+        let mut left = count;
+        refill(pk, || {
+            if left == 0 {
+                return false;
+            }
+            left -= 1;
+            true
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn pooled_encryptions_decrypt_and_never_share_a_randomizer() {
+        rng::seed([1u8; 32]);
+        let paillier = keypair(1);
+        let pk = paillier.public_key();
+
+        assert_eq!(refill_some(pk, 16), 16);
+        assert_eq!(available(pk), 16);
+
+        // Same plaintext each time: equal ciphertexts would mean a reused r^n
+        let mut seen = HashSet::new();
+        for i in 0..16 {
+            let c = pk.encrypt(&[42]).unwrap();
+            assert_eq!(available(pk), 15 - i);
+            assert_eq!(paillier.decrypt(&c).unwrap(), BigUint::from(42u32));
+            assert!(seen.insert(c));
+        }
+
+        // Rerandomizing draws from the pool too
+        let c = pk.encrypt(&[42]).unwrap();
+        refill_some(pk, 8);
+        let copies: HashSet<BigUint> = (0..8).map(|_| pk.rerandomize(&c).unwrap()).collect();
+        assert_eq!(copies.len(), 8);
+        assert_eq!(available(pk), 0);
+        for copy in &copies {
+            assert_eq!(paillier.decrypt(copy).unwrap(), BigUint::from(42u32));
+        }
+    }
+
+    #[test]
+    fn pool_belongs_to_one_modulus() {
+        rng::seed([2u8; 32]);
+        let first = keypair(1);
+        let second = keypair(2);
+
+        refill_some(first.public_key(), 4);
+        assert_eq!(available(second.public_key()), 0);
+        assert!(take(second.public_key()).is_none());
+
+        // Refilling for another key discards the old randomizers
+        refill_some(second.public_key(), 4);
+        assert_eq!(available(first.public_key()), 0);
+        assert_eq!(available(second.public_key()), 4);
+
+        let randomizers: HashSet<BigUint> = std::iter::from_fn(|| take(second.public_key())).collect();
+        assert_eq!(randomizers.len(), 4);
+    }
+
+    #[test]
+    fn refill_stops_at_the_target() {
+        rng::seed([3u8; 32]);
+        let pk = keypair(1).public_key().clone();
+        assert_eq!(refill(&pk, || true).unwrap(), POOL_TARGET);
+        assert_eq!(refill(&pk, || true).unwrap(), 0);
+        assert_eq!(available(&pk), POOL_TARGET);
+    }
+}
@@ -0,0 +1,282 @@
+//! Probable-prime generation for Paillier key material.
+//!
+//! Candidates are filtered by trial division against a small-prime sieve and
+//! then confirmed with Miller-Rabin. Round counts follow FIPS 186-4 (C.3) for
+//! an error probability below 2^-100 on randomly chosen candidates.
+//!
+//! The caller's RNG only ever supplies candidate bytes. Miller-Rabin bases for
+//! generated candidates come from a generator seeded by the candidate itself,
+//! so a seeded DRBG yields the same primes whatever the test consumes.
+
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use rand::{CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+// Primes below this bound are used for trial division before Miller-Rabin
+const SIEVE_LIMIT: usize = 2048;
+
+// Domain separator for the per-candidate witness generator
+const WITNESS_DOMAIN: &[u8] = b"primes-miller-rabin-witness-v1";
+
+/// Kind of prime used for the factors of `n`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrimeType {
+    /// Random probable prime of the requested length
+    Standard,
+    /// Safe prime `p = 2p' + 1` with `p'` prime (required by threshold schemes)
+    Safe,
+}
+
+/// Generate a probable prime of exactly `bits` bits.
+///
+/// The two most significant bits are always set, so the product of two
+/// primes of `bits` bits has exactly `2 * bits` bits.
+pub fn gen_prime<R: RngCore + CryptoRng>(bits: usize, rng: &mut R) -> BigUint {
+    assert!(bits >= 16, "prime size too small: {} bits", bits);
+    let small_primes = small_primes();
+    let rounds = miller_rabin_rounds(bits);
+
+    loop {
+        let candidate = random_candidate(bits, rng);
+        if !passes_trial_division(&candidate, &small_primes) {
+            continue;
+        }
+        if miller_rabin(&candidate, rounds, &mut witness_rng(&candidate)) {
+            return candidate;
+        }
+    }
+}
+
+/// Generate a safe prime `p = 2p' + 1` of exactly `bits` bits.
+///
+/// Considerably slower than `gen_prime`; expect seconds for 512-bit primes
+/// and minutes for 1024-bit primes in native code.
+pub fn gen_safe_prime<R: RngCore + CryptoRng>(bits: usize, rng: &mut R) -> BigUint {
+    assert!(bits >= 16, "prime size too small: {} bits", bits);
+    let small_primes = small_primes();
+    let rounds = miller_rabin_rounds(bits);
+
+    loop {
+        // p = 3 mod 4 keeps p' = (p - 1) / 2 odd; its top bit is set because p's
+        // top two bits are
+        let mut p = random_candidate(bits, rng);
+        p.set_bit(1, true);
+        let sophie = &p >> 1;
+
+        // p and p' must both survive the sieve before paying for Miller-Rabin
+        if !passes_trial_division(&sophie, &small_primes)
+            || !passes_trial_division(&p, &small_primes)
+        {
+            continue;
+        }
+        let mut witnesses = witness_rng(&p);
+        if miller_rabin(&sophie, rounds, &mut witnesses) && miller_rabin(&p, rounds, &mut witnesses) {
+            return p;
+        }
+    }
+}
+
+/// Generate a prime of the requested type
+pub fn gen_prime_of_type<R: RngCore + CryptoRng>(
+    bits: usize,
+    prime_type: PrimeType,
+    rng: &mut R,
+) -> BigUint {
+    match prime_type {
+        PrimeType::Standard => gen_prime(bits, rng),
+        PrimeType::Safe => gen_safe_prime(bits, rng),
+    }
+}
+
+/// Probabilistic primality test (trial division followed by Miller-Rabin).
+/// Bases come from `rng`, so `n` may be chosen by an adversary.
+pub fn is_probable_prime<R: RngCore + CryptoRng>(n: &BigUint, rng: &mut R) -> bool {
+    let two = BigUint::from(2u32);
+    if n < &two {
+        return false;
+    }
+
+    for p in small_primes() {
+        if n == &BigUint::from(p) {
+            return true;
+        }
+        if (n % p).is_zero() {
+            return false;
+        }
+    }
+
+    miller_rabin(n, miller_rabin_rounds(n.bits() as usize), rng)
+}
+
+// ===== INTERNALS =====
+
+// Built from raw bytes rather than `gen_biguint` so a seeded RNG always yields
+// the same candidates, independent of num-bigint internals (see `from_seed`)
+fn random_candidate<R: RngCore + CryptoRng>(bits: usize, rng: &mut R) -> BigUint {
+    let mut candidate = random_bits(bits, rng);
+    candidate.set_bit(bits as u64 - 1, true);
+    candidate.set_bit(bits as u64 - 2, true);
+    candidate.set_bit(0, true);
+    candidate
+}
+
+fn random_bits<R: RngCore>(bits: usize, rng: &mut R) -> BigUint {
+    let mut bytes = vec![0u8; bits.div_ceil(8)];
+    rng.fill_bytes(&mut bytes);
+    BigUint::from_bytes_be(&bytes) >> (bytes.len() * 8 - bits)
+}
+
+// Bases for a generated candidate: random enough for a random candidate, and
+// a pure function of it
+fn witness_rng(candidate: &BigUint) -> ChaCha20Rng {
+    let mut hasher = Sha256::new();
+    hasher.update(WITNESS_DOMAIN);
+    hasher.update(candidate.to_bytes_be());
+    ChaCha20Rng::from_seed(hasher.finalize().into())
+}
+
+// Callers only pass candidates larger than SIEVE_LIMIT
+fn passes_trial_division(n: &BigUint, small_primes: &[u32]) -> bool {
+    small_primes.iter().all(|&p| !(n % p).is_zero())
+}
+
+/// Miller-Rabin with `rounds` uniformly random bases in [2, n - 2].
+/// `n` must be odd and greater than 3.
+fn miller_rabin<R: RngCore + CryptoRng>(n: &BigUint, rounds: usize, rng: &mut R) -> bool {
+    let one = BigUint::one();
+    let two = BigUint::from(2u32);
+    let n_minus_one = n - &one;
+    let bits = n.bits() as usize;
+
+    // n - 1 = d * 2^s with d odd
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+
+    'witness: for _ in 0..rounds {
+        // Rejection-sampled from raw bytes, like the candidates
+        let a = loop {
+            let a = random_bits(bits, rng);
+            if a >= two && a < n_minus_one {
+                break a;
+            }
+        };
+        let mut x = a.modpow(&d, n);
+
+        if x == one || x == n_minus_one {
+            continue;
+        }
+
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                continue 'witness;
+            }
+            if x == one {
+                return false;
+            }
+        }
+
+        return false;
+    }
+
+    true
+}
+
+/// Rounds for an error probability below 2^-100 (FIPS 186-4, table C.3),
+/// rounded up for the intermediate sizes.
+fn miller_rabin_rounds(bits: usize) -> usize {
+    match bits {
+        b if b >= 1536 => 4,
+        b if b >= 1024 => 5,
+        b if b >= 512 => 8,
+        b if b >= 256 => 16,
+        _ => 40,
+    }
+}
+
+fn small_primes() -> Vec<u32> {
+    let mut is_composite = vec![false; SIEVE_LIMIT];
+    let mut primes = Vec::new();
+
+    for i in 2..SIEVE_LIMIT {
+        if is_composite[i] {
+            continue;
+        }
+        primes.push(i as u32);
+        for multiple in (i * i..SIEVE_LIMIT).step_by(i) {
+            is_composite[multiple] = true;
+        }
+    }
+
+    primes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn test_rng() -> ChaCha20Rng {
+        ChaCha20Rng::seed_from_u64(1)
+    }
+
+    fn mersenne(e: u32) -> BigUint {
+        (BigUint::one() << e) - 1u32
+    }
+
+    #[test]
+    fn known_primes_pass() {
+        let mut rng = test_rng();
+        for e in [61, 89, 107, 127, 521] {
+            assert!(is_probable_prime(&mersenne(e), &mut rng), "2^{} - 1 is prime", e);
+        }
+        for p in [2u32, 3, 2039, 2053, 65537] {
+            assert!(is_probable_prime(&BigUint::from(p), &mut rng), "{} is prime", p);
+        }
+    }
+
+    #[test]
+    fn known_composites_fail() {
+        let mut rng = test_rng();
+        // Carmichael numbers, strong pseudoprimes to base 2 and a
+        // pseudoprime to every base up to 23 whose factors all exceed the sieve
+        for n in [0u64, 1, 4, 561, 41041, 825265, 2047, 3215031751, 3825123056546413051] {
+            assert!(!is_probable_prime(&BigUint::from(n), &mut rng), "{} is composite", n);
+        }
+        // 2^67 - 1 = 193707721 * 761838257287 and a product of two primes
+        assert!(!is_probable_prime(&mersenne(67), &mut rng));
+        assert!(!is_probable_prime(&(mersenne(61) * mersenne(89)), &mut rng));
+    }
+
+    #[test]
+    fn miller_rabin_without_the_sieve() {
+        let mut rng = test_rng();
+        assert!(miller_rabin(&mersenne(127), 40, &mut rng));
+        for n in [561u32, 41041, 2047] {
+            assert!(!miller_rabin(&BigUint::from(n), 40, &mut rng), "{} is composite", n);
+        }
+    }
+
+    #[test]
+    fn generated_primes_have_exact_length() {
+        let mut rng = test_rng();
+        for bits in [16, 64, 256] {
+            let p = gen_prime(bits, &mut rng);
+            assert_eq!(p.bits() as usize, bits);
+            assert!(p.bit(bits as u64 - 2), "top two bits are set");
+            assert!(is_probable_prime(&p, &mut rng));
+        }
+    }
+
+    #[test]
+    fn safe_primes_are_safe() {
+        let mut rng = test_rng();
+        let p = gen_safe_prime(64, &mut rng);
+        assert_eq!(p.bits(), 64);
+        assert!(is_probable_prime(&p, &mut rng));
+        assert!(is_probable_prime(&(&p >> 1), &mut rng), "(p - 1) / 2 is prime");
+    }
+}
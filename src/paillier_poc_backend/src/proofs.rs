@@ -0,0 +1,638 @@
+//! Non-interactive range proof that a Paillier ciphertext encrypts a value in
+//! [0, 2^RANGE_BITS), so client-encrypted tokens cannot fall outside the
+//! 32-byte token range.
+//!
+//! The prover encrypts every bit separately, c_i = Enc(b_i), and the token
+//! ciphertext is their recombination c = prod c_i^(2^i) = Enc(sum b_i * 2^i).
+//! Each c_i carries an OR-proof (Fiat–Shamir, one challenge per bit) that
+//! either c_i or c_i / g is an n-th residue, i.e. that it encrypts 0 or 1.
+//!
+//! The prover runs natively (clients use the crate as a library); the
+//! canister verifies. Verification costs a few modpows per bit, so
+//! `RangeCheck` lets the caller spread the bits over several messages.
+//!
+//! The same n-th root argument also proves a decryption correct: c encrypts m
+//! iff c * g^-m is an n-th residue, and the key holder can show it knows the
+//! root without revealing it or the key.
+
+use num_bigint::{BigUint, RandBigInt};
+use num_traits::{One, Zero};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::simple_paillier::{PrivateKey, PublicKey};
+
+/// Proven plaintext range: [0, 2^RANGE_BITS), one 32-byte token
+pub const RANGE_BITS: usize = 256;
+
+// Challenge size of each OR-proof
+const CHALLENGE_BITS: u64 = 128;
+const CHALLENGE_BYTES: usize = (CHALLENGE_BITS / 8) as usize;
+
+// Domain separators for the Fiat–Shamir hashes; bump if a transcript changes
+const PROOF_DOMAIN: &[u8] = b"paillier-range-v1";
+const DECRYPTION_DOMAIN: &[u8] = b"paillier-decryption-v1";
+
+/// OR-proof that `c` encrypts 0 or 1. The commitments are not sent: the
+/// verifier recomputes them from the challenges and responses.
+#[derive(Clone, Debug)]
+pub struct BitProof {
+    pub c: BigUint, // Enc(b)
+    pub e0: BigUint,
+    pub e1: BigUint,
+    pub z0: BigUint,
+    pub z1: BigUint,
+}
+
+#[derive(Clone, Debug)]
+pub struct RangeProof {
+    pub bits: Vec<BitProof>, // Least significant bit first
+}
+
+/// Prover side: encrypt a token (at most 32 bytes, big-endian) together with
+/// a proof that the ciphertext encrypts a value below 2^RANGE_BITS
+pub fn encrypt_with_range_proof<R: RngCore + CryptoRng>(
+    pk: &PublicKey,
+    m: &[u8],
+    rng: &mut R,
+) -> Result<(BigUint, RangeProof), String> {
+    let m = BigUint::from_bytes_be(m);
+    if m.bits() > RANGE_BITS as u64 {
+        return Err(format!("Plaintext exceeds {} bits", RANGE_BITS));
+    }
+    if pk.n.bits() <= RANGE_BITS as u64 {
+        return Err(format!("Modulus must be larger than {} bits", RANGE_BITS));
+    }
+
+    // Encrypt each bit with its own randomness
+    let mut bits = Vec::with_capacity(RANGE_BITS);
+    let mut randomness = Vec::with_capacity(RANGE_BITS);
+    for i in 0..RANGE_BITS {
+        let r = rng.gen_biguint_range(&BigUint::one(), &pk.n);
+        let rn = r.modpow(&pk.n, &pk.n_squared);
+        let c = if m.bit(i as u64) { (&pk.g * rn) % &pk.n_squared } else { rn };
+        bits.push(c);
+        randomness.push(r);
+    }
+
+    let c = recombine(pk, &bits);
+
+    let bits = bits.into_iter()
+        .zip(randomness)
+        .enumerate()
+        .map(|(i, (c_i, r))| prove_bit(pk, &c, i, c_i, m.bit(i as u64), &r, rng))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((c, RangeProof { bits }))
+}
+
+// OR-proof for one bit: the branch of the actual bit is proven honestly, the
+// other one is simulated with a challenge chosen in advance
+fn prove_bit<R: RngCore + CryptoRng>(
+    pk: &PublicKey,
+    c: &BigUint,
+    index: usize,
+    c_i: BigUint,
+    bit: bool,
+    r: &BigUint,
+    rng: &mut R,
+) -> Result<BitProof, String> {
+    let real = bit as usize;
+    let fake = 1 - real;
+    let u_inv = branch_inverses(pk, &c_i)?;
+
+    // Simulated branch: a = z^n * u^-e for random e, z
+    let e_fake = rng.gen_biguint(CHALLENGE_BITS);
+    let z_fake = rng.gen_biguint_range(&BigUint::one(), &pk.n);
+
+    // Honest branch: a = s^n
+    let s = rng.gen_biguint_range(&BigUint::one(), &pk.n);
+
+    let mut a = [BigUint::zero(), BigUint::zero()];
+    a[real] = s.modpow(&pk.n, &pk.n_squared);
+    a[fake] = commitment(pk, &z_fake, &u_inv[fake], &e_fake);
+
+    let e = challenge(pk, c, index, &c_i, &a[0], &a[1]);
+    let e_real = (e + challenge_modulus() - &e_fake) % challenge_modulus();
+
+    // u_real = r^n, so z = s * r^e satisfies z^n = a * u^e
+    let z_real = (s * r.modpow(&e_real, &pk.n)) % &pk.n;
+
+    let (e0, e1, z0, z1) = if real == 0 {
+        (e_real, e_fake, z_real, z_fake)
+    } else {
+        (e_fake, e_real, z_fake, z_real)
+    };
+    Ok(BitProof { c: c_i, e0, e1, z0, z1 })
+}
+
+impl RangeProof {
+    /// Fixed-width encoding: per bit c (|n^2| bytes), e0, e1 (16 bytes each),
+    /// z0, z1 (|n| bytes each)
+    pub fn to_bytes(&self, pk: &PublicKey) -> Vec<u8> {
+        let (n_bytes, n2_bytes) = widths(pk);
+        let mut out = Vec::with_capacity(self.bits.len() * bit_proof_bytes(pk));
+        for bit in &self.bits {
+            put(&mut out, &bit.c, n2_bytes);
+            put(&mut out, &bit.e0, CHALLENGE_BYTES);
+            put(&mut out, &bit.e1, CHALLENGE_BYTES);
+            put(&mut out, &bit.z0, n_bytes);
+            put(&mut out, &bit.z1, n_bytes);
+        }
+        out
+    }
+
+    pub fn from_bytes(pk: &PublicKey, bytes: &[u8]) -> Result<Self, String> {
+        let expected = range_proof_bytes(pk);
+        if bytes.len() != expected {
+            return Err(format!("Range proof must be {} bytes, got {}", expected, bytes.len()));
+        }
+
+        let (n_bytes, n2_bytes) = widths(pk);
+        let mut rest = bytes;
+        let mut take = |width: usize| {
+            let (head, tail) = rest.split_at(width);
+            rest = tail;
+            BigUint::from_bytes_be(head)
+        };
+
+        let bits = (0..RANGE_BITS)
+            .map(|_| BitProof {
+                c: take(n2_bytes),
+                e0: take(CHALLENGE_BYTES),
+                e1: take(CHALLENGE_BYTES),
+                z0: take(n_bytes),
+                z1: take(n_bytes),
+            })
+            .collect();
+        Ok(RangeProof { bits })
+    }
+
+    /// Cheap part of verification: the bit ciphertexts recombine to `c`
+    pub fn verify_binding(&self, pk: &PublicKey, c: &BigUint) -> Result<(), String> {
+        if self.bits.len() != RANGE_BITS {
+            return Err(format!("Range proof has {} bits (expected {})", self.bits.len(), RANGE_BITS));
+        }
+        let bits: Vec<BigUint> = self.bits.iter().map(|bit| bit.c.clone()).collect();
+        if &recombine(pk, &bits) != c {
+            return Err("Bit ciphertexts do not recombine to the ciphertext".to_string());
+        }
+        Ok(())
+    }
+
+    /// Expensive part of verification: bit `index` encrypts 0 or 1
+    pub fn verify_bit(&self, pk: &PublicKey, c: &BigUint, index: usize) -> Result<(), String> {
+        let bit = self.bits.get(index).ok_or_else(|| format!("No bit {} in range proof", index))?;
+
+        if !pk.is_valid_ciphertext(&bit.c) {
+            return Err(format!("Bit {}: ciphertext is not in Z*_{{n^2}}", index));
+        }
+        let in_range = |z: &BigUint| !z.is_zero() && z < &pk.n;
+        if !in_range(&bit.z0) || !in_range(&bit.z1) {
+            return Err(format!("Bit {}: response out of range", index));
+        }
+        if bit.e0.bits() > CHALLENGE_BITS || bit.e1.bits() > CHALLENGE_BITS {
+            return Err(format!("Bit {}: challenge out of range", index));
+        }
+
+        let u_inv = branch_inverses(pk, &bit.c)?;
+        let a0 = commitment(pk, &bit.z0, &u_inv[0], &bit.e0);
+        let a1 = commitment(pk, &bit.z1, &u_inv[1], &bit.e1);
+
+        let e = challenge(pk, c, index, &bit.c, &a0, &a1);
+        if (&bit.e0 + &bit.e1) % challenge_modulus() != e {
+            return Err(format!("Bit {}: challenge mismatch", index));
+        }
+        Ok(())
+    }
+}
+
+/// Verify a whole range proof in one go (native use; the canister goes
+/// through `RangeCheck` so it can spread the bits over messages)
+pub fn verify_range(pk: &PublicKey, c: &BigUint, proof: &RangeProof) -> Result<(), String> {
+    proof.verify_binding(pk, c)?;
+    (0..RANGE_BITS).try_for_each(|i| proof.verify_bit(pk, c, i))
+}
+
+/// Verification of a batch of ciphertexts with their range proofs, a given
+/// number of bit proofs at a time. The ciphertexts are only handed out once
+/// every bit has been checked.
+pub struct RangeCheck {
+    ciphertexts: Vec<BigUint>,
+    proofs: Vec<RangeProof>,
+    next_bit: usize, // Across all proofs, in order
+}
+
+impl RangeCheck {
+    /// Decode everything and run the cheap checks (Z*_{n^2}, binding) up front
+    pub fn new(pk: &PublicKey, ciphertexts: &[Vec<u8>], proofs: &[Vec<u8>]) -> Result<Self, String> {
+        if ciphertexts.len() != proofs.len() {
+            return Err(format!("{} ciphertexts but {} range proofs", ciphertexts.len(), proofs.len()));
+        }
+
+        let mut check = RangeCheck {
+            ciphertexts: Vec::with_capacity(ciphertexts.len()),
+            proofs: Vec::with_capacity(proofs.len()),
+            next_bit: 0,
+        };
+        for (i, (bytes, proof_bytes)) in ciphertexts.iter().zip(proofs).enumerate() {
+            // Values outside Z*_{n^2} are not ciphertexts and would break the zero tests
+            let c = BigUint::from_bytes_be(bytes);
+            if !pk.is_valid_ciphertext(&c) {
+                return Err(format!("ciphertext {}: not in Z*_{{n^2}}", i));
+            }
+            let proof = RangeProof::from_bytes(pk, proof_bytes)
+                .and_then(|proof| proof.verify_binding(pk, &c).map(|_| proof))
+                .map_err(|e| format!("ciphertext {}: {}", i, e))?;
+            check.ciphertexts.push(c);
+            check.proofs.push(proof);
+        }
+        Ok(check)
+    }
+
+    pub fn remaining_bits(&self) -> usize {
+        self.proofs.len() * RANGE_BITS - self.next_bit
+    }
+
+    /// Check the next `count` bit proofs (or as many as are left)
+    pub fn verify_bits(&mut self, pk: &PublicKey, count: usize) -> Result<(), String> {
+        let end = self.next_bit + count.min(self.remaining_bits());
+        while self.next_bit < end {
+            let (i, bit) = (self.next_bit / RANGE_BITS, self.next_bit % RANGE_BITS);
+            self.proofs[i].verify_bit(pk, &self.ciphertexts[i], bit)
+                .map_err(|e| format!("ciphertext {}: {}", i, e))?;
+            self.next_bit += 1;
+        }
+        Ok(())
+    }
+
+    /// The ciphertexts in canonical form (no leading zeros), once all verified
+    pub fn into_tokens(self) -> Result<Vec<Vec<u8>>, String> {
+        if self.remaining_bits() > 0 {
+            return Err(format!("{} bit proofs not verified yet", self.remaining_bits()));
+        }
+        Ok(self.ciphertexts.iter().map(BigUint::to_bytes_be).collect())
+    }
+}
+
+/// Encoded size of one bit's proof for this key
+pub fn bit_proof_bytes(pk: &PublicKey) -> usize {
+    let (n_bytes, n2_bytes) = widths(pk);
+    n2_bytes + 2 * CHALLENGE_BYTES + 2 * n_bytes
+}
+
+/// Encoded size of a whole range proof for this key
+pub fn range_proof_bytes(pk: &PublicKey) -> usize {
+    RANGE_BITS * bit_proof_bytes(pk)
+}
+
+// ===== DECRYPTION PROOF =====
+
+/// Proof that a ciphertext decrypts to a claimed value: knowledge of r with
+/// c * g^-m = r^n mod n^2 (Schnorr-style, made non-interactive)
+#[derive(Clone, Debug)]
+pub struct DecryptionProof {
+    pub a: BigUint, // s^n mod n^2
+    pub z: BigUint, // s * r^e mod n
+}
+
+/// Key-holder side: decrypt `c` and prove the result
+pub fn prove_decryption<R: RngCore + CryptoRng>(
+    pk: &PublicKey,
+    sk: &PrivateKey,
+    c: &BigUint,
+    rng: &mut R,
+) -> Result<(BigUint, DecryptionProof), String> {
+    if !pk.is_valid_ciphertext(c) {
+        return Err("Ciphertext is not in Z*_{n^2}".to_string());
+    }
+
+    let m = sk.decrypt(c)?;
+    let r = sk.nth_root(&zero_part(pk, c, &m))?;
+
+    let s = rng.gen_biguint_range(&BigUint::one(), &pk.n);
+    let a = s.modpow(&pk.n, &pk.n_squared);
+    let e = decryption_challenge(pk, c, &m, &a);
+    let z = (s * r.modpow(&e, &pk.n)) % &pk.n;
+
+    Ok((m, DecryptionProof { a, z }))
+}
+
+/// Anyone: check that `c` decrypts to `m` under `pk`
+pub fn verify_decryption(pk: &PublicKey, c: &BigUint, m: &BigUint, proof: &DecryptionProof) -> Result<(), String> {
+    if !pk.is_valid_ciphertext(c) {
+        return Err("Ciphertext is not in Z*_{n^2}".to_string());
+    }
+    if m >= &pk.n {
+        return Err("Claimed value is not below n".to_string());
+    }
+    if !pk.is_valid_ciphertext(&proof.a) || proof.z.is_zero() || proof.z >= pk.n {
+        return Err("Proof values out of range".to_string());
+    }
+
+    // z^n = s^n * r^(n e) = a * u^e
+    let u = zero_part(pk, c, m);
+    let e = decryption_challenge(pk, c, m, &proof.a);
+    let lhs = proof.z.modpow(&pk.n, &pk.n_squared);
+    let rhs = (&proof.a * u.modpow(&e, &pk.n_squared)) % &pk.n_squared;
+    if lhs != rhs {
+        return Err("Decryption proof does not verify".to_string());
+    }
+    Ok(())
+}
+
+impl DecryptionProof {
+    /// Fixed-width encoding: a (|n^2| bytes) then z (|n| bytes)
+    pub fn to_bytes(&self, pk: &PublicKey) -> Vec<u8> {
+        let (n_bytes, n2_bytes) = widths(pk);
+        let mut out = Vec::with_capacity(n2_bytes + n_bytes);
+        put(&mut out, &self.a, n2_bytes);
+        put(&mut out, &self.z, n_bytes);
+        out
+    }
+
+    pub fn from_bytes(pk: &PublicKey, bytes: &[u8]) -> Result<Self, String> {
+        let (n_bytes, n2_bytes) = widths(pk);
+        if bytes.len() != n2_bytes + n_bytes {
+            return Err(format!("Decryption proof must be {} bytes, got {}", n2_bytes + n_bytes, bytes.len()));
+        }
+        let (a, z) = bytes.split_at(n2_bytes);
+        Ok(DecryptionProof { a: BigUint::from_bytes_be(a), z: BigUint::from_bytes_be(z) })
+    }
+}
+
+// c * g^-m = Enc(plaintext - m): an n-th residue iff c encrypts m
+fn zero_part(pk: &PublicKey, c: &BigUint, m: &BigUint) -> BigUint {
+    let neg_m = (&pk.n - (m % &pk.n)) % &pk.n;
+    pk.add_plain(c, &neg_m)
+}
+
+fn decryption_challenge(pk: &PublicKey, c: &BigUint, m: &BigUint, a: &BigUint) -> BigUint {
+    let (n_bytes, n2_bytes) = widths(pk);
+    let mut transcript = Vec::with_capacity(2 * n_bytes + 2 * n2_bytes);
+    put(&mut transcript, &pk.n, n_bytes);
+    put(&mut transcript, c, n2_bytes);
+    put(&mut transcript, m, n_bytes);
+    put(&mut transcript, a, n2_bytes);
+
+    let mut hasher = Sha256::new();
+    hasher.update(DECRYPTION_DOMAIN);
+    hasher.update(&transcript);
+    BigUint::from_bytes_be(&hasher.finalize()[..CHALLENGE_BYTES])
+}
+
+// ===== HELPERS =====
+
+// prod c_i^(2^i), most significant bit first (Horner)
+fn recombine(pk: &PublicKey, bits: &[BigUint]) -> BigUint {
+    bits.iter()
+        .rev()
+        .fold(BigUint::one(), |acc, c_i| (&acc * &acc % &pk.n_squared) * c_i % &pk.n_squared)
+}
+
+// Inverses of the two branch statements u0 = c and u1 = c / g
+fn branch_inverses(pk: &PublicKey, c: &BigUint) -> Result<[BigUint; 2], String> {
+    let c_inv = c.modinv(&pk.n_squared)
+        .ok_or_else(|| "Ciphertext is not invertible mod n^2".to_string())?;
+    let u1_inv = (&c_inv * &pk.g) % &pk.n_squared;
+    Ok([c_inv, u1_inv])
+}
+
+// a = z^n * u^-e mod n^2
+fn commitment(pk: &PublicKey, z: &BigUint, u_inv: &BigUint, e: &BigUint) -> BigUint {
+    (z.modpow(&pk.n, &pk.n_squared) * u_inv.modpow(e, &pk.n_squared)) % &pk.n_squared
+}
+
+// Per-bit challenge, bound to the key, the token ciphertext and the bit index
+fn challenge(pk: &PublicKey, c: &BigUint, index: usize, c_i: &BigUint, a0: &BigUint, a1: &BigUint) -> BigUint {
+    let (n_bytes, n2_bytes) = widths(pk);
+    let mut transcript = Vec::with_capacity(n_bytes + 4 * n2_bytes + 4);
+    put(&mut transcript, &pk.n, n_bytes);
+    put(&mut transcript, c, n2_bytes);
+    transcript.extend_from_slice(&(index as u32).to_be_bytes());
+    put(&mut transcript, c_i, n2_bytes);
+    put(&mut transcript, a0, n2_bytes);
+    put(&mut transcript, a1, n2_bytes);
+
+    let mut hasher = Sha256::new();
+    hasher.update(PROOF_DOMAIN);
+    hasher.update(&transcript);
+    BigUint::from_bytes_be(&hasher.finalize()[..CHALLENGE_BYTES])
+}
+
+fn challenge_modulus() -> BigUint {
+    BigUint::one() << CHALLENGE_BITS
+}
+
+// Byte widths of values mod n and mod n^2
+pub(crate) fn widths(pk: &PublicKey) -> (usize, usize) {
+    ((pk.n.bits() as usize).div_ceil(8), (pk.n_squared.bits() as usize).div_ceil(8))
+}
+
+// Append `x` big-endian, left-padded to `width` bytes (values never exceed it)
+pub(crate) fn put(out: &mut Vec<u8>, x: &BigUint, width: usize) {
+    let bytes = x.to_bytes_be();
+    out.resize(out.len() + width.saturating_sub(bytes.len()), 0);
+    out.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primes::PrimeType;
+    use crate::simple_paillier::SimplePaillier;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn keypair(seed: u64) -> SimplePaillier {
+        SimplePaillier::generate(512, PrimeType::Standard, &mut ChaCha20Rng::seed_from_u64(seed))
+    }
+
+    #[test]
+    fn range_proof_verifies_and_binds() {
+        let paillier = keypair(1);
+        let pk = paillier.public_key();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let token = [0xa5u8; 32];
+
+        let (c, proof) = encrypt_with_range_proof(pk, &token, &mut rng).unwrap();
+        assert_eq!(paillier.decrypt(&c).unwrap(), BigUint::from_bytes_be(&token));
+        assert!(verify_range(pk, &c, &proof).is_ok());
+
+        // Survives encoding
+        let bytes = proof.to_bytes(pk);
+        assert_eq!(bytes.len(), range_proof_bytes(pk));
+        assert!(verify_range(pk, &c, &RangeProof::from_bytes(pk, &bytes).unwrap()).is_ok());
+
+        // A flipped bit anywhere in the encoding: bit ciphertext, challenge, response
+        let n2_bytes = widths(pk).1;
+        for offset in [0, n2_bytes + 3, bit_proof_bytes(pk) - 1, bytes.len() / 2] {
+            let mut tampered = bytes.clone();
+            tampered[offset] ^= 0x01;
+            let tampered = RangeProof::from_bytes(pk, &tampered).unwrap();
+            assert!(verify_range(pk, &c, &tampered).is_err(), "flip at byte {}", offset);
+        }
+
+        // The proof belongs to its own ciphertext
+        let (other_c, _) = encrypt_with_range_proof(pk, &token, &mut rng).unwrap();
+        assert!(verify_range(pk, &other_c, &proof).is_err());
+        assert!(verify_range(pk, &pk.rerandomize_with_rng(&c, &mut rng), &proof).is_err());
+    }
+
+    #[test]
+    fn range_proof_is_bound_to_the_modulus() {
+        let pk = keypair(1).public_key().clone();
+        let other = keypair(3).public_key().clone();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+
+        let (c, proof) = encrypt_with_range_proof(&pk, &[7u8; 32], &mut rng).unwrap();
+        assert!(verify_range(&other, &c, &proof).is_err());
+
+        // Same size, so the encoding itself still parses under the other key
+        let reparsed = RangeProof::from_bytes(&other, &proof.to_bytes(&pk)).unwrap();
+        assert!(verify_range(&other, &c, &reparsed).is_err());
+    }
+
+    #[test]
+    fn decryption_proof_verifies() {
+        let paillier = keypair(1);
+        let (pk, sk) = (paillier.public_key(), paillier.private_key());
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let c = pk.encrypt_with_rng(&[42], &mut rng).unwrap();
+
+        let (m, proof) = prove_decryption(pk, sk, &c, &mut rng).unwrap();
+        assert_eq!(m, BigUint::from(42u32));
+        assert!(verify_decryption(pk, &c, &m, &proof).is_ok());
+
+        // Survives encoding
+        let decoded = DecryptionProof::from_bytes(pk, &proof.to_bytes(pk)).unwrap();
+        assert!(verify_decryption(pk, &c, &m, &decoded).is_ok());
+        assert!(DecryptionProof::from_bytes(pk, &proof.to_bytes(pk)[1..]).is_err());
+
+        // The witness is an n-th root of the zero part
+        let r = sk.nth_root(&zero_part(pk, &c, &m)).unwrap();
+        assert_eq!(r.modpow(&pk.n, &pk.n_squared), zero_part(pk, &c, &m));
+    }
+
+    #[test]
+    fn decryption_proof_rejects_wrong_statements() {
+        let paillier = keypair(1);
+        let (pk, sk) = (paillier.public_key(), paillier.private_key());
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let c = pk.encrypt_with_rng(&[42], &mut rng).unwrap();
+        let (m, proof) = prove_decryption(pk, sk, &c, &mut rng).unwrap();
+
+        // Wrong plaintext, including one past the modulus
+        assert!(verify_decryption(pk, &c, &BigUint::from(43u32), &proof).is_err());
+        assert!(verify_decryption(pk, &c, &(&m + &pk.n), &proof).is_err());
+
+        // Wrong ciphertext: same plaintext re-randomized, and another value
+        let rerandomized = pk.rerandomize_with_rng(&c, &mut rng);
+        assert!(verify_decryption(pk, &rerandomized, &m, &proof).is_err());
+        let other = pk.encrypt_with_rng(&[7], &mut rng).unwrap();
+        assert!(verify_decryption(pk, &other, &m, &proof).is_err());
+    }
+
+    #[test]
+    fn tampered_decryption_proof_is_rejected() {
+        let paillier = keypair(1);
+        let (pk, sk) = (paillier.public_key(), paillier.private_key());
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let c = pk.encrypt_with_rng(&[42], &mut rng).unwrap();
+        let (m, proof) = prove_decryption(pk, sk, &c, &mut rng).unwrap();
+
+        // A flipped bit in the commitment or the response
+        let bytes = proof.to_bytes(pk);
+        for offset in [3, widths(pk).1 + 3, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[offset] ^= 0x01;
+            let tampered = DecryptionProof::from_bytes(pk, &tampered).unwrap();
+            assert!(verify_decryption(pk, &c, &m, &tampered).is_err(), "flip at byte {}", offset);
+        }
+
+        // Out-of-range values
+        let zero_z = DecryptionProof { a: proof.a.clone(), z: BigUint::zero() };
+        assert!(verify_decryption(pk, &c, &m, &zero_z).is_err());
+        let big_z = DecryptionProof { a: proof.a.clone(), z: &proof.z + &pk.n };
+        assert!(verify_decryption(pk, &c, &m, &big_z).is_err());
+
+        // A proof made under another key
+        let other = keypair(3);
+        let c_other = other.public_key().encrypt_with_rng(&[42], &mut rng).unwrap();
+        let (_, foreign) = prove_decryption(other.public_key(), other.private_key(), &c_other, &mut rng).unwrap();
+        assert!(verify_decryption(pk, &c, &m, &foreign).is_err());
+    }
+
+    fn proven(pk: &PublicKey, tokens: &[[u8; 32]], rng: &mut ChaCha20Rng) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        tokens.iter()
+            .map(|token| {
+                let (c, proof) = encrypt_with_range_proof(pk, token, rng).unwrap();
+                (c.to_bytes_be(), proof.to_bytes(pk))
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn range_check_runs_in_batches() {
+        let pk = keypair(1).public_key().clone();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let (mut ciphertexts, proofs) = proven(&pk, &[[1u8; 32], [2u8; 32]], &mut rng);
+        let canonical = ciphertexts.clone();
+        ciphertexts[0].insert(0, 0); // Leading zeros are accepted and dropped
+
+        let mut check = RangeCheck::new(&pk, &ciphertexts, &proofs).unwrap();
+        assert_eq!(check.remaining_bits(), 2 * RANGE_BITS);
+        check.verify_bits(&pk, 100).unwrap();
+        assert_eq!(check.remaining_bits(), 2 * RANGE_BITS - 100);
+
+        // Not handed out half-checked
+        let mut partial = RangeCheck::new(&pk, &ciphertexts, &proofs).unwrap();
+        partial.verify_bits(&pk, RANGE_BITS).unwrap();
+        assert!(partial.into_tokens().is_err());
+
+        // Batches straddle the boundary between proofs; the last one is cut short
+        while check.remaining_bits() > 0 {
+            check.verify_bits(&pk, 150).unwrap();
+        }
+        assert_eq!(check.into_tokens().unwrap(), canonical);
+    }
+
+    #[test]
+    fn range_check_rejects_bad_proofs() {
+        let pk = keypair(1).public_key().clone();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let (ciphertexts, proofs) = proven(&pk, &[[1u8; 32], [2u8; 32]], &mut rng);
+
+        // Caught up front: mismatched counts, a value outside Z*_{n^2},
+        // a truncated proof, a proof for another ciphertext
+        assert!(RangeCheck::new(&pk, &ciphertexts, &proofs[..1]).is_err());
+        let mut outside = ciphertexts.clone();
+        outside[1] = pk.n_squared.to_bytes_be();
+        assert!(RangeCheck::new(&pk, &outside, &proofs).is_err());
+        let mut truncated = proofs.clone();
+        truncated[1].pop();
+        assert!(RangeCheck::new(&pk, &ciphertexts, &truncated).is_err());
+        let swapped = vec![proofs[1].clone(), proofs[0].clone()];
+        assert!(RangeCheck::new(&pk, &ciphertexts, &swapped).is_err());
+
+        // A bad response in the second proof passes the cheap checks and
+        // fails in the batch that reaches it
+        let mut tampered = proofs.clone();
+        let bit = 200;
+        let z1_end = (bit + 1) * bit_proof_bytes(&pk);
+        tampered[1][z1_end - 1] ^= 0x01;
+        let mut check = RangeCheck::new(&pk, &ciphertexts, &tampered).unwrap();
+        check.verify_bits(&pk, RANGE_BITS + bit).unwrap();
+        let err = check.verify_bits(&pk, 10).unwrap_err();
+        assert!(err.starts_with("ciphertext 1: Bit 200"), "{}", err);
+        assert_eq!(check.remaining_bits(), RANGE_BITS - bit);
+        assert!(check.into_tokens().is_err());
+    }
+
+    #[test]
+    fn prover_rejects_oversized_tokens() {
+        let pk = keypair(1).public_key().clone();
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        assert!(encrypt_with_range_proof(&pk, &[1u8; 33], &mut rng).is_err());
+    }
+}
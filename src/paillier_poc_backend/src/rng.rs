@@ -0,0 +1,144 @@
+//! Canister CSPRNG: a ChaCha20 generator seeded from the management
+//! canister's `raw_rand` and reseeded periodically.
+//!
+//! Code that needs randomness goes through `with_rng`; `getrandom` is wired to
+//! `fill_bytes` so dependencies draw from the same generator. Native tests can
+//! call `seed` directly in place of `raw_rand`.
+
+use ic_cdk::api::management_canister::main::raw_rand;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::time::Duration;
+
+const RESEED_INTERVAL: Duration = Duration::from_secs(60 * 60); // 1 hour
+
+thread_local! {
+    static RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+
+/// Seed the generator, or mix a fresh seed into it if already seeded
+pub fn seed(seed: [u8; 32]) {
+    RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+
+        let seed = match rng.as_mut() {
+            Some(current) => {
+                // Keep the existing entropy: new state = H(current output || fresh seed)
+                let mut previous = [0u8; 32];
+                current.fill_bytes(&mut previous);
+
+                let mut hasher = Sha256::new();
+                hasher.update(previous);
+                hasher.update(seed);
+                hasher.finalize().into()
+            }
+            None => seed,
+        };
+
+        *rng = Some(ChaCha20Rng::from_seed(seed));
+    });
+}
+
+pub fn is_seeded() -> bool {
+    RNG.with(|rng| rng.borrow().is_some())
+}
+
+/// Run `f` with the canister RNG; fails if no seed has arrived yet
+pub fn with_rng<T>(f: impl FnOnce(&mut ChaCha20Rng) -> T) -> Result<T, String> {
+    RNG.with(|rng| {
+        rng.borrow_mut()
+            .as_mut()
+            .map(f)
+            .ok_or_else(|| "RNG not seeded yet (waiting for raw_rand)".to_string())
+    })
+}
+
+pub fn fill_bytes(dest: &mut [u8]) -> Result<(), String> {
+    with_rng(|rng| rng.fill_bytes(dest))
+}
+
+// ===== IC INTEGRATION =====
+
+/// Fetch 32 bytes from `raw_rand` and (re)seed the generator
+pub async fn reseed_from_raw_rand() -> Result<(), String> {
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("raw_rand failed: {:?} - {}", code, msg))?;
+
+    let fresh: [u8; 32] = bytes.get(..32)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| format!("raw_rand returned {} bytes (expected 32)", bytes.len()))?;
+
+    seed(fresh);
+    Ok(())
+}
+
+/// Seed on demand for calls that arrive before the init timer has fired
+pub async fn ensure_seeded() -> Result<(), String> {
+    if is_seeded() {
+        return Ok(());
+    }
+    reseed_from_raw_rand().await
+}
+
+/// Seed right after init/upgrade and reseed every `RESEED_INTERVAL`.
+/// Lifecycle hooks cannot await, so seeding happens from timers.
+pub fn schedule_seeding() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(reseed_logged()));
+    ic_cdk_timers::set_timer_interval(RESEED_INTERVAL, || ic_cdk::spawn(reseed_logged()));
+}
+
+async fn reseed_logged() {
+    match reseed_from_raw_rand().await {
+        Ok(()) => ic_cdk::println!("RNG reseeded from raw_rand"),
+        Err(e) => ic_cdk::println!("Error: RNG reseed failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seed a fresh generator (the RNG is thread-local) and read 32 bytes
+    fn output_after(seeds: &'static [[u8; 32]]) -> [u8; 32] {
+        std::thread::spawn(move || {
+            for s in seeds {
+                seed(*s);
+            }
+            let mut out = [0u8; 32];
+            fill_bytes(&mut out).unwrap();
+            out
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn unseeded_generator_refuses() {
+        std::thread::spawn(|| {
+            assert!(!is_seeded());
+            assert!(with_rng(|rng| rng.next_u64()).is_err());
+            assert!(fill_bytes(&mut [0u8; 8]).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn fixed_seed_is_deterministic() {
+        assert_eq!(output_after(&[[1u8; 32]]), output_after(&[[1u8; 32]]));
+        assert_ne!(output_after(&[[1u8; 32]]), output_after(&[[2u8; 32]]));
+    }
+
+    #[test]
+    fn reseeding_changes_the_stream() {
+        let once = output_after(&[[1u8; 32]]);
+        let reseeded = output_after(&[[1u8; 32], [2u8; 32]]);
+        assert_ne!(once, reseeded);
+        // The new state mixes in the old one rather than replacing it
+        assert_ne!(reseeded, output_after(&[[2u8; 32]]));
+        assert_eq!(reseeded, output_after(&[[1u8; 32], [2u8; 32]]));
+    }
+}
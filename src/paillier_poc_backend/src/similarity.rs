@@ -0,0 +1,289 @@
+//! Encrypted similarity between two token sequences: positional matches
+//! (Hamming) and order-independent set overlap (Jaccard).
+//!
+//! The evaluator turns each position into Enc(r_i * (a_i - b_i)) with a fresh
+//! non-zero blinding factor r_i, re-randomizes it and shuffles the batch. The
+//! key holder can then only learn how many of these decrypt to zero (matching
+//! positions), not the token values or which positions matched, and publishes
+//! that count encrypted.
+//!
+//! A zero test is exact up to the negligible chance that a non-zero difference
+//! shares a factor with the plaintext modulus, since tokens are smaller than it.
+//!
+//! Everything here works for any `AdditiveHomomorphic` scheme.
+
+use num_bigint::{BigUint, RandBigInt};
+use num_traits::One;
+use rand::seq::SliceRandom;
+use rand::{CryptoRng, RngCore};
+
+use crate::homomorphic::AdditiveHomomorphic;
+use crate::PaillierError;
+
+/// Evaluator side: Enc(r * (a - b)) for a random non-zero r.
+/// Decrypts to zero iff the two plaintexts are equal, and to a uniformly
+/// random value otherwise.
+pub fn blind_difference<H: AdditiveHomomorphic, R: RngCore + CryptoRng>(
+    he: &H,
+    c1: &H::Ciphertext,
+    c2: &H::Ciphertext,
+    rng: &mut R,
+) -> Result<H::Ciphertext, String> {
+    // Fresh randomness unlinks the result from c1 and c2
+    he.rerandomize_with_rng(&scale_difference(he, c1, c2, rng)?, rng)
+}
+
+/// First half of `blind_difference`, before rerandomization. Each half costs
+/// about one encryption, so callers short on budget can run them apart.
+pub fn scale_difference<H: AdditiveHomomorphic, R: RngCore + CryptoRng>(
+    he: &H,
+    c1: &H::Ciphertext,
+    c2: &H::Ciphertext,
+    rng: &mut R,
+) -> Result<H::Ciphertext, String> {
+    let diff = he.sub(c1, c2)?;
+    let r = rng.gen_biguint_range(&BigUint::one(), &he.plaintext_modulus());
+    Ok(he.mul_plain(&diff, &r))
+}
+
+/// Evaluator side: blinded differences for every position, in random order.
+/// `check(i)` runs before position i and can stop the protocol (the canister
+/// passes its instruction limit check).
+pub fn blinded_differences<H: AdditiveHomomorphic, R: RngCore + CryptoRng>(
+    he: &H,
+    cs1: &[H::Ciphertext],
+    cs2: &[H::Ciphertext],
+    rng: &mut R,
+    check: &mut impl FnMut(usize) -> Result<(), PaillierError>,
+) -> Result<Vec<H::Ciphertext>, PaillierError> {
+    if cs1.len() != cs2.len() {
+        return Err(PaillierError::TokenCountMismatch { doc1: cs1.len(), doc2: cs2.len() });
+    }
+
+    let mut blinded = Vec::with_capacity(cs1.len());
+    for (i, (c1, c2)) in cs1.iter().zip(cs2).enumerate() {
+        check(i)?;
+        blinded.push(blind_difference(he, c1, c2, rng)
+            .map_err(|e| PaillierError::ComparisonFailed(format!("token {}: {}", i, e)))?);
+    }
+
+    // Hide which positions matched from the key holder
+    blinded.shuffle(rng);
+    Ok(blinded)
+}
+
+/// Key-holder side: whether a blinded difference came from equal tokens
+pub fn is_match<H: AdditiveHomomorphic>(he: &H, blinded: &H::Ciphertext) -> Result<bool, String> {
+    he.is_zero(blinded)
+}
+
+/// Key-holder side: number of matching positions in a batch
+pub fn count_matches<H: AdditiveHomomorphic>(
+    he: &H,
+    blinded: &[H::Ciphertext],
+    check: &mut impl FnMut(usize) -> Result<(), PaillierError>,
+) -> Result<u64, PaillierError> {
+    let mut matches = 0;
+    for (i, c) in blinded.iter().enumerate() {
+        check(i)?;
+        if is_match(he, c).map_err(PaillierError::ComparisonFailed)? {
+            matches += 1;
+        }
+    }
+    Ok(matches)
+}
+
+// ===== SET OVERLAP =====
+// Order-independent comparison: every token of one document is tested against
+// every token of the other, so documents may differ in length. A token
+// repeated on one side would be counted once per copy, so the intersection is
+// counted in both directions and the smaller count is kept. That is exact when
+// at most one side repeats tokens and an upper bound otherwise.
+
+/// Evaluator side: Enc(r * (a - b)) given Enc(a) and the precomputed Enc(-b).
+/// The pairwise work is quadratic, so unlike `blind_difference` this skips
+/// rerandomization; the random factor already randomizes the plaintext.
+pub fn blind_pair<H: AdditiveHomomorphic, R: RngCore + CryptoRng>(
+    he: &H,
+    c1: &H::Ciphertext,
+    c2_neg: &H::Ciphertext,
+    rng: &mut R,
+) -> H::Ciphertext {
+    let r = rng.gen_biguint_range(&BigUint::one(), &he.plaintext_modulus());
+    he.mul_plain(&he.add(c1, c2_neg), &r)
+}
+
+/// Evaluator side: one group per token of `cs1` holding its blinded differences
+/// against all of `cs2`. Groups and their contents are shuffled, so the key
+/// holder learns how many groups contain a match but not which tokens.
+pub fn blinded_pairwise<H: AdditiveHomomorphic, R: RngCore + CryptoRng>(
+    he: &H,
+    cs1: &[H::Ciphertext],
+    cs2: &[H::Ciphertext],
+    rng: &mut R,
+    check: &mut impl FnMut(usize) -> Result<(), PaillierError>,
+) -> Result<Vec<Vec<H::Ciphertext>>, PaillierError> {
+    // Enc(a - b) = Enc(a) + Enc(-b), so negate the second set once
+    let negated = cs2.iter()
+        .map(|c| he.neg(c))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(PaillierError::ComparisonFailed)?;
+
+    let mut groups = Vec::with_capacity(cs1.len());
+    for (i, c1) in cs1.iter().enumerate() {
+        check(i)?;
+        let mut group: Vec<H::Ciphertext> = negated.iter()
+            .map(|c2_neg| blind_pair(he, c1, c2_neg, rng))
+            .collect();
+        group.shuffle(rng);
+        groups.push(group);
+    }
+
+    groups.shuffle(rng);
+    Ok(groups)
+}
+
+/// Key-holder side: whether any blinded difference in a group is zero
+pub fn contains_match<H: AdditiveHomomorphic>(he: &H, group: &[H::Ciphertext]) -> Result<bool, String> {
+    for c in group {
+        if is_match(he, c)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Key-holder side: tokens of the first set with a match, from the groups of
+/// `blinded_pairwise`
+pub fn intersection_size<H: AdditiveHomomorphic>(
+    he: &H,
+    groups: &[Vec<H::Ciphertext>],
+    check: &mut impl FnMut(usize) -> Result<(), PaillierError>,
+) -> Result<u64, PaillierError> {
+    let mut size = 0;
+    for (i, group) in groups.iter().enumerate() {
+        check(i)?;
+        if contains_match(he, group).map_err(PaillierError::ComparisonFailed)? {
+            size += 1;
+        }
+    }
+    Ok(size)
+}
+
+/// |A ∩ B|, counted from both sides so duplicate tokens can't inflate it:
+/// the result never exceeds the size of either document
+pub fn blinded_intersection_size<H: AdditiveHomomorphic, R: RngCore + CryptoRng>(
+    he: &H,
+    cs1: &[H::Ciphertext],
+    cs2: &[H::Ciphertext],
+    rng: &mut R,
+    check: &mut impl FnMut(usize) -> Result<(), PaillierError>,
+) -> Result<u64, PaillierError> {
+    let forward = blinded_pairwise(he, cs1, cs2, rng, check)?;
+    let forward = intersection_size(he, &forward, check)?;
+    let backward = blinded_pairwise(he, cs2, cs1, rng, check)?;
+    let backward = intersection_size(he, &backward, check)?;
+    Ok(forward.min(backward))
+}
+
+/// Jaccard index |A ∩ B| / |A ∪ B| from the intersection and set sizes,
+/// clamped to [0, 1]
+pub fn jaccard(intersection: u64, size1: u64, size2: u64) -> f64 {
+    let intersection = intersection.min(size1).min(size2);
+    let union = size1 + size2 - intersection;
+    if union == 0 {
+        return 0.0;
+    }
+    intersection as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elgamal::ExpElGamal;
+    use crate::simple_paillier::SimplePaillier;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn no_limit(_: usize) -> Result<(), PaillierError> {
+        Ok(())
+    }
+
+    fn encrypt_all<H: AdditiveHomomorphic>(he: &H, tokens: &[u8], rng: &mut ChaCha20Rng) -> Vec<H::Ciphertext> {
+        tokens.iter().map(|t| he.encrypt_with_rng(&[*t], rng).unwrap()).collect()
+    }
+
+    fn check_match_counts<H: AdditiveHomomorphic>(he: &H) {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let doc = [1, 2, 3, 4, 5, 6];
+        for (other, expected) in [([1, 2, 3, 4, 5, 6], 6), ([7, 8, 9, 10, 11, 12], 0), ([1, 9, 3, 9, 5, 1], 3)] {
+            let cs1 = encrypt_all(he, &doc, &mut rng);
+            let cs2 = encrypt_all(he, &other, &mut rng);
+            let plain = doc.iter().zip(&other).filter(|(a, b)| a == b).count() as u64;
+            assert_eq!(plain, expected);
+
+            let blinded = blinded_differences(he, &cs1, &cs2, &mut rng, &mut no_limit).unwrap();
+            assert_eq!(count_matches(he, &blinded, &mut no_limit).unwrap(), plain);
+        }
+    }
+
+    fn check_intersections<H: AdditiveHomomorphic>(he: &H) {
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let cases: [(&[u8], &[u8], u64); 5] = [
+            (&[1, 2, 3], &[3, 2, 1], 3),
+            (&[1, 2, 3], &[4, 5], 0),
+            (&[1, 2, 3, 4], &[4, 9, 2], 2),
+            (&[1, 1], &[1], 1),
+            // Repeats on both sides: each direction overcounts, the smaller by one
+            (&[1, 1, 2], &[1, 2, 2, 2], 3),
+        ];
+        for (a, b, expected) in cases {
+            let cs1 = encrypt_all(he, a, &mut rng);
+            let cs2 = encrypt_all(he, b, &mut rng);
+            let intersection = blinded_intersection_size(he, &cs1, &cs2, &mut rng, &mut no_limit).unwrap();
+            assert_eq!(intersection, expected, "{:?} and {:?}", a, b);
+            assert!(jaccard(intersection, a.len() as u64, b.len() as u64) <= 1.0);
+        }
+    }
+
+    #[test]
+    fn blinded_counts_match_plaintext_counts() {
+        check_match_counts(&ExpElGamal::from_seed(&[1u8; 32]).unwrap());
+        check_match_counts(&SimplePaillier::from_seed(&[1u8; 32], 512).unwrap());
+    }
+
+    #[test]
+    fn duplicates_do_not_inflate_the_intersection() {
+        check_intersections(&ExpElGamal::from_seed(&[1u8; 32]).unwrap());
+        check_intersections(&SimplePaillier::from_seed(&[1u8; 32], 512).unwrap());
+    }
+
+    #[test]
+    fn jaccard_stays_in_range() {
+        assert_eq!(jaccard(0, 0, 0), 0.0);
+        assert_eq!(jaccard(2, 2, 2), 1.0);
+        assert_eq!(jaccard(1, 2, 1), 0.5);
+        // An intersection larger than either side is clamped
+        assert_eq!(jaccard(2, 2, 1), 0.5);
+        assert_eq!(jaccard(5, 2, 2), 1.0);
+    }
+
+    #[test]
+    fn check_stops_the_protocol() {
+        let he = ExpElGamal::from_seed(&[1u8; 32]).unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let cs = encrypt_all(&he, &[1, 2, 3], &mut rng);
+        let mut stop_at_two = |i: usize| match i {
+            2 => Err(PaillierError::InstructionLimitExceeded { used: 2, limit: 1 }),
+            _ => Ok(()),
+        };
+        assert!(matches!(
+            blinded_differences(&he, &cs, &cs, &mut rng, &mut stop_at_two),
+            Err(PaillierError::InstructionLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            blinded_differences(&he, &cs, &cs[..2], &mut rng, &mut no_limit),
+            Err(PaillierError::TokenCountMismatch { .. })
+        ));
+    }
+}
@@ -1,50 +1,456 @@
-/// WARNING: This is a SIMPLIFIED implementation for performance testing only.
-/// DO NOT USE FOR ACTUAL ENCRYPTION - NOT CRYPTOGRAPHICALLY SECURE!
-/// Missing: proper prime generation, secure random, parameter validation
+//! Paillier encryption with g = n + 1 and CRT decryption.
+//!
+//! Moduli are products of two equal-length Miller-Rabin primes with
+//! gcd(pq, (p-1)(q-1)) = 1 enforced. The implementation is unaudited and its
+//! big-integer arithmetic is not constant time, so timing side channels on
+//! the private key are out of scope for this proof of concept.
 
 use num_bigint::{BigUint, RandBigInt};
-use num_traits::One;
-use rand::thread_rng;
+use num_integer::Integer;
+use num_traits::{One, Zero};
+use rand::{CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
 
-pub struct SimplePaillier {
+use crate::primes::{self, PrimeType};
+use crate::{pool, rng};
+
+/// Smallest modulus accepted by key generation
+pub const MIN_KEY_BITS: usize = 512;
+
+/// Minimum seed length for deterministic key derivation
+pub const MIN_SEED_BYTES: usize = 32;
+
+// Domain separator for the key-derivation DRBG; bump if derivation ever changes
+const SEED_DOMAIN: &[u8] = b"paillier-keygen-v2";
+
+/// Public half of a Paillier keypair (g = n + 1)
+#[derive(Clone, Debug)]
+pub struct PublicKey {
     pub n: BigUint,
     pub n_squared: BigUint,
     pub g: BigUint,
 }
 
-impl SimplePaillier {
-    pub fn new(bits: usize) -> Self {
-        // Generate two primes (INSECURE - just for POC)
-        let mut rng = thread_rng();
-        
-        // For testing, use smaller primes
-        let p = rng.gen_biguint((bits / 2) as u64);
-        let q = rng.gen_biguint((bits / 2) as u64);
-        let n = &p * &q;
+/// Private half of a Paillier keypair, with CRT precomputations
+#[derive(Clone)]
+pub struct PrivateKey {
+    p: BigUint,
+    q: BigUint,
+    n: BigUint,
+    n_squared: BigUint,
+    lambda: BigUint,
+    mu: BigUint,
+    // CRT: decrypt separately mod p^2 and q^2, then recombine mod n
+    p_squared: BigUint,
+    q_squared: BigUint,
+    p_minus_one: BigUint,
+    q_minus_one: BigUint,
+    hp: BigUint,
+    hq: BigUint,
+    p_inv_q: BigUint,
+}
+
+pub struct SimplePaillier {
+    public_key: PublicKey,
+    private_key: PrivateKey,
+}
+
+impl PublicKey {
+    pub fn new(n: BigUint) -> Self {
         let n_squared = &n * &n;
         let g = &n + BigUint::one();
-        
-        SimplePaillier { n, n_squared, g }
+        PublicKey { n, n_squared, g }
     }
-    
+
+    /// Encrypt with randomness from the canister RNG, taking a precomputed
+    /// r^n from the pool when one is ready
     pub fn encrypt(&self, m: &[u8]) -> Result<BigUint, String> {
-        // Convert message to BigUint
+        match pool::take(self) {
+            Some(rn) => self.encrypt_with_randomizer(m, &rn),
+            None => rng::with_rng(|rng| self.encrypt_with_rng(m, rng))?,
+        }
+    }
+
+    pub fn encrypt_with_rng<R: RngCore + CryptoRng>(&self, m: &[u8], rng: &mut R) -> Result<BigUint, String> {
+        self.encrypt_with_randomizer(m, &self.randomizer_with_rng(rng))
+    }
+
+    /// r^n mod n^2 for a random r: the expensive half of an encryption
+    pub fn randomizer_with_rng<R: RngCore + CryptoRng>(&self, rng: &mut R) -> BigUint {
+        let r = rng.gen_biguint_range(&BigUint::one(), &self.n);
+        r.modpow(&self.n, &self.n_squared)
+    }
+
+    /// c = g^m * r^n mod n^2, with g^m = 1 + m*n since g = n + 1. `rn` must
+    /// come from `randomizer_with_rng` and never be reused.
+    pub fn encrypt_with_randomizer(&self, m: &[u8], rn: &BigUint) -> Result<BigUint, String> {
         let m_big = BigUint::from_bytes_be(m);
         if m_big >= self.n {
             return Err("Message too large".into());
         }
-        
-        // Simple encryption (INSECURE)
-        let mut rng = thread_rng();
-        let r = rng.gen_biguint_range(&BigUint::one(), &self.n);
-        
-        // c = g^m * r^n mod n^2
-        let gm = self.g.modpow(&m_big, &self.n_squared);
-        let rn = r.modpow(&self.n, &self.n_squared);
+
+        // 1 + m*n <= n^2 - n + 1, already reduced
+        let gm = BigUint::one() + m_big * &self.n;
         Ok((gm * rn) % &self.n_squared)
     }
-    
+
+    /// Same plaintext under fresh randomness: c * r^n mod n^2, so the result
+    /// can't be linked to `c`. Takes r^n from the pool when one is ready.
+    pub fn rerandomize(&self, c: &BigUint) -> Result<BigUint, String> {
+        let rn = match pool::take(self) {
+            Some(rn) => rn,
+            None => rng::with_rng(|rng| self.randomizer_with_rng(rng))?,
+        };
+        Ok((c * rn) % &self.n_squared)
+    }
+
+    pub fn rerandomize_with_rng<R: RngCore + CryptoRng>(&self, c: &BigUint, rng: &mut R) -> BigUint {
+        (c * self.randomizer_with_rng(rng)) % &self.n_squared
+    }
+
+    /// Whether `c` lies in Z*_{n^2}, i.e. 0 < c < n^2 and gcd(c, n) = 1.
+    /// Anything else cannot come from `encrypt` and would break `neg`/`sub`.
+    pub fn is_valid_ciphertext(&self, c: &BigUint) -> bool {
+        !c.is_zero() && c < &self.n_squared && c.gcd(&self.n).is_one()
+    }
+
     pub fn add(&self, c1: &BigUint, c2: &BigUint) -> BigUint {
         (c1 * c2) % &self.n_squared
     }
-}
\ No newline at end of file
+
+    /// Enc(m + k) = c * g^k, using g^k = 1 + k*n mod n^2
+    pub fn add_plain(&self, c: &BigUint, k: &BigUint) -> BigUint {
+        let gk = (BigUint::one() + (k % &self.n) * &self.n) % &self.n_squared;
+        (c * gk) % &self.n_squared
+    }
+
+    /// Enc(k * m) = c^k
+    pub fn mul_plain(&self, c: &BigUint, k: &BigUint) -> BigUint {
+        c.modpow(k, &self.n_squared)
+    }
+
+    /// Enc(-m mod n) = c^-1 mod n^2
+    pub fn neg(&self, c: &BigUint) -> Result<BigUint, String> {
+        c.modinv(&self.n_squared)
+            .ok_or_else(|| "Ciphertext is not invertible mod n^2".to_string())
+    }
+
+    /// Enc(m1 - m2 mod n) = c1 * c2^-1
+    pub fn sub(&self, c1: &BigUint, c2: &BigUint) -> Result<BigUint, String> {
+        Ok(self.add(c1, &self.neg(c2)?))
+    }
+
+    /// Enc(sum_i k_i * m_i), i.e. a dot product with a plaintext vector
+    pub fn weighted_sum(&self, cs: &[BigUint], weights: &[BigUint]) -> Result<BigUint, String> {
+        if cs.len() != weights.len() {
+            return Err(format!("Length mismatch: {} ciphertexts vs {} weights",
+                cs.len(), weights.len()));
+        }
+
+        // Enc(0) with r = 1 is the identity for `add`
+        Ok(cs.iter()
+            .zip(weights)
+            .fold(BigUint::one(), |acc, (c, k)| self.add(&acc, &self.mul_plain(c, k))))
+    }
+}
+
+impl PrivateKey {
+    /// Derive the private key from the prime factors of `n`
+    pub fn from_primes(p: BigUint, q: BigUint) -> Result<Self, String> {
+        if p == q {
+            return Err("p and q must be distinct".into());
+        }
+
+        let n = &p * &q;
+        let p_minus_one = &p - 1u32;
+        let q_minus_one = &q - 1u32;
+        if !n.gcd(&(&p_minus_one * &q_minus_one)).is_one() {
+            return Err("gcd(pq, (p-1)(q-1)) != 1".into());
+        }
+
+        let n_squared = &n * &n;
+        let g = &n + BigUint::one();
+
+        // With g = n + 1, L(g^lambda mod n^2) = lambda mod n
+        let lambda = p_minus_one.lcm(&q_minus_one);
+        let mu = (&lambda % &n).modinv(&n)
+            .ok_or("lambda is not invertible mod n")?;
+
+        let p_squared = &p * &p;
+        let q_squared = &q * &q;
+        let hp = h_function(&g, &p, &p_squared, &p_minus_one)?;
+        let hq = h_function(&g, &q, &q_squared, &q_minus_one)?;
+        let p_inv_q = p.modinv(&q).ok_or("p is not invertible mod q")?;
+
+        Ok(PrivateKey {
+            p, q, n, n_squared, lambda, mu,
+            p_squared, q_squared, p_minus_one, q_minus_one, hp, hq, p_inv_q,
+        })
+    }
+
+    pub fn p(&self) -> &BigUint {
+        &self.p
+    }
+
+    pub fn q(&self) -> &BigUint {
+        &self.q
+    }
+
+    /// Decrypt using the CRT (roughly 3-4x faster than `decrypt_standard`)
+    pub fn decrypt(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.check_ciphertext(c)?;
+
+        // m_p = L_p(c^(p-1) mod p^2) * h_p mod p, likewise for q
+        let cp = c.modpow(&self.p_minus_one, &self.p_squared);
+        let mp = (l_function(&cp, &self.p) * &self.hp) % &self.p;
+        let cq = c.modpow(&self.q_minus_one, &self.q_squared);
+        let mq = (l_function(&cq, &self.q) * &self.hq) % &self.q;
+
+        // m = m_p + p * ((m_q - m_p) * p^-1 mod q)
+        let diff = (&mq + &self.q - (&mp % &self.q)) % &self.q;
+        let k = (diff * &self.p_inv_q) % &self.q;
+        Ok(mp + &self.p * k)
+    }
+
+    /// Textbook decryption m = L(c^lambda mod n^2) * mu mod n
+    pub fn decrypt_standard(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.check_ciphertext(c)?;
+
+        let u = c.modpow(&self.lambda, &self.n_squared);
+        Ok((l_function(&u, &self.n) * &self.mu) % &self.n)
+    }
+
+    /// Recover the randomness r of an encryption of zero, u = r^n mod n^2.
+    /// x -> x^n is a bijection on Z*_n, so r = u^(n^-1 mod phi(n)) mod n.
+    pub fn nth_root(&self, u: &BigUint) -> Result<BigUint, String> {
+        self.check_ciphertext(u)?;
+
+        let phi = &self.p_minus_one * &self.q_minus_one;
+        let n_inv = self.n.modinv(&phi).ok_or("n is not invertible mod phi(n)")?;
+        Ok((u % &self.n).modpow(&n_inv, &self.n))
+    }
+
+    fn check_ciphertext(&self, c: &BigUint) -> Result<(), String> {
+        if c.is_zero() || c >= &self.n_squared {
+            return Err("Ciphertext out of range".into());
+        }
+        Ok(())
+    }
+}
+
+impl SimplePaillier {
+    /// Generate a keypair from safe primes using the canister RNG.
+    ///
+    /// Safe primes take minutes at 2048 bits, far beyond one message's
+    /// budget, so the canister itself derives its key with `from_seed`.
+    pub fn new(bits: usize) -> Result<Self, String> {
+        rng::with_rng(|rng| Self::generate(bits, PrimeType::Safe, rng))
+    }
+
+    /// Generate a keypair whose modulus `n = p * q` has exactly `bits` bits.
+    ///
+    /// `p` and `q` are distinct probable primes of `bits / 2` bits each and
+    /// `gcd(pq, (p-1)(q-1)) = 1` is enforced.
+    pub fn generate<R: RngCore + CryptoRng>(bits: usize, prime_type: PrimeType, rng: &mut R) -> Self {
+        assert!(
+            bits >= MIN_KEY_BITS && bits.is_multiple_of(2),
+            "Invalid key size: {} (must be even and at least {})", bits, MIN_KEY_BITS
+        );
+
+        loop {
+            let p = primes::gen_prime_of_type(bits / 2, prime_type, rng);
+            let q = primes::gen_prime_of_type(bits / 2, prime_type, rng);
+
+            // Retry on the (negligible) chance of a degenerate pair
+            if let Ok(paillier) = Self::from_primes(p, q) {
+                return paillier;
+            }
+        }
+    }
+
+    /// Deterministically derive a keypair from seed material (e.g. vetKD output).
+    ///
+    /// The seed is hashed into a ChaCha20 DRBG that drives the prime search, so
+    /// the same seed and size always give the same key and it can be re-derived
+    /// on demand instead of being stored.
+    ///
+    /// Uses standard primes so derivation fits in one message. For random
+    /// equal-length primes that does not make n easier to factor; safe primes
+    /// are only needed by the threshold scheme (see threshold.rs).
+    pub fn from_seed(seed: &[u8], bits: usize) -> Result<Self, String> {
+        if seed.len() < MIN_SEED_BYTES {
+            return Err(format!("Seed too short: {} bytes (min {})", seed.len(), MIN_SEED_BYTES));
+        }
+        if bits < MIN_KEY_BITS || !bits.is_multiple_of(2) {
+            return Err(format!("Invalid key size: {} (must be even and at least {})", bits, MIN_KEY_BITS));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(SEED_DOMAIN);
+        hasher.update((bits as u64).to_be_bytes());
+        hasher.update(seed);
+        let mut drbg = ChaCha20Rng::from_seed(hasher.finalize().into());
+
+        Ok(Self::generate(bits, PrimeType::Standard, &mut drbg))
+    }
+
+    /// Build a keypair from two known primes
+    pub fn from_primes(p: BigUint, q: BigUint) -> Result<Self, String> {
+        let private_key = PrivateKey::from_primes(p, q)?;
+        let public_key = PublicKey::new(private_key.n.clone());
+        Ok(SimplePaillier { public_key, private_key })
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    pub fn encrypt(&self, m: &[u8]) -> Result<BigUint, String> {
+        self.public_key.encrypt(m)
+    }
+
+    pub fn decrypt(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.private_key.decrypt(c)
+    }
+
+    pub fn add(&self, c1: &BigUint, c2: &BigUint) -> BigUint {
+        self.public_key.add(c1, c2)
+    }
+
+    pub fn add_plain(&self, c: &BigUint, k: &BigUint) -> BigUint {
+        self.public_key.add_plain(c, k)
+    }
+
+    pub fn mul_plain(&self, c: &BigUint, k: &BigUint) -> BigUint {
+        self.public_key.mul_plain(c, k)
+    }
+
+    pub fn neg(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.public_key.neg(c)
+    }
+
+    pub fn sub(&self, c1: &BigUint, c2: &BigUint) -> Result<BigUint, String> {
+        self.public_key.sub(c1, c2)
+    }
+
+    pub fn rerandomize(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.public_key.rerandomize(c)
+    }
+}
+
+// L(x) = (x - 1) / d
+fn l_function(x: &BigUint, d: &BigUint) -> BigUint {
+    (x - 1u32) / d
+}
+
+// h_p = L_p(g^(p-1) mod p^2)^-1 mod p
+fn h_function(
+    g: &BigUint,
+    p: &BigUint,
+    p_squared: &BigUint,
+    p_minus_one: &BigUint,
+) -> Result<BigUint, String> {
+    let gp = g.modpow(p_minus_one, p_squared);
+    (l_function(&gp, p) % p)
+        .modinv(p)
+        .ok_or_else(|| "h function is not invertible".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> SimplePaillier {
+        SimplePaillier::generate(512, PrimeType::Standard, &mut ChaCha20Rng::seed_from_u64(7))
+    }
+
+    fn plaintext(m: u64) -> Vec<u8> {
+        m.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn keygen_produces_balanced_primes() {
+        let paillier = keypair();
+        let sk = paillier.private_key();
+        let mut rng = ChaCha20Rng::seed_from_u64(8);
+
+        assert_eq!(paillier.public_key().n.bits(), 512);
+        assert_eq!((sk.p().bits(), sk.q().bits()), (256, 256));
+        assert!(primes::is_probable_prime(sk.p(), &mut rng));
+        assert!(primes::is_probable_prime(sk.q(), &mut rng));
+        assert_eq!(&(sk.p() * sk.q()), &paillier.public_key().n);
+    }
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let paillier = keypair();
+        let pk = paillier.public_key();
+        let mut rng = ChaCha20Rng::seed_from_u64(8);
+
+        let largest = &pk.n - 1u32;
+        let messages = [BigUint::zero(), BigUint::one(), BigUint::from(u64::MAX), largest];
+        for m in &messages {
+            let c = pk.encrypt_with_rng(&m.to_bytes_be(), &mut rng).unwrap();
+            assert_eq!(&paillier.decrypt(&c).unwrap(), m);
+            assert_eq!(&paillier.private_key().decrypt_standard(&c).unwrap(), m);
+        }
+
+        assert!(pk.encrypt_with_rng(&pk.n.to_bytes_be(), &mut rng).is_err());
+    }
+
+    #[test]
+    fn encryption_is_randomized() {
+        let paillier = keypair();
+        let mut rng = ChaCha20Rng::seed_from_u64(8);
+        let a = paillier.public_key().encrypt_with_rng(&plaintext(5), &mut rng).unwrap();
+        let b = paillier.public_key().encrypt_with_rng(&plaintext(5), &mut rng).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn homomorphic_operations() {
+        let paillier = keypair();
+        let pk = paillier.public_key();
+        let mut rng = ChaCha20Rng::seed_from_u64(8);
+        let a = pk.encrypt_with_rng(&plaintext(30), &mut rng).unwrap();
+        let b = pk.encrypt_with_rng(&plaintext(12), &mut rng).unwrap();
+
+        assert_eq!(paillier.decrypt(&pk.add(&a, &b)).unwrap(), BigUint::from(42u32));
+        assert_eq!(paillier.decrypt(&pk.sub(&a, &b).unwrap()).unwrap(), BigUint::from(18u32));
+        assert_eq!(paillier.decrypt(&pk.mul_plain(&a, &BigUint::from(3u32))).unwrap(), BigUint::from(90u32));
+        assert_eq!(paillier.decrypt(&pk.add_plain(&a, &BigUint::from(1u32))).unwrap(), BigUint::from(31u32));
+    }
+
+    #[test]
+    fn from_seed_known_answer() {
+        // Pins the derivation: a change here makes existing canisters derive a
+        // different key after upgrade, so bump SEED_DOMAIN along with it
+        let paillier = SimplePaillier::from_seed(&[0x42; 32], 512).unwrap();
+        assert_eq!(
+            paillier.public_key().n.to_str_radix(16),
+            concat!(
+                "cb3bd656515994048099f7e83dc83002a2b5dc6c599ae9c252b175ad0ad01ad0",
+                "faf4a0d08b49d4768f9753be3c901b12c3b04d675d0f3722c399905045d5d261",
+            )
+        );
+
+        let again = SimplePaillier::from_seed(&[0x42; 32], 512).unwrap();
+        assert_eq!(again.private_key().p(), paillier.private_key().p());
+        let other = SimplePaillier::from_seed(&[0x43; 32], 512).unwrap();
+        assert_ne!(other.public_key().n, paillier.public_key().n);
+        assert!(SimplePaillier::from_seed(&[0x42; 16], 512).is_err());
+    }
+
+    #[test]
+    fn rejects_degenerate_primes() {
+        let p = BigUint::from(2039u32);
+        assert!(SimplePaillier::from_primes(p.clone(), p).is_err());
+        // gcd(3 * 7, 2 * 6) = 3
+        assert!(SimplePaillier::from_primes(BigUint::from(3u32), BigUint::from(7u32)).is_err());
+    }
+}
@@ -0,0 +1,668 @@
+//! Stable-memory layout. Documents, key configuration, owner and metrics live
+//! in stable structures so they survive upgrades without pre/post-upgrade
+//! serialization. The Paillier key itself is never stored: it is re-derived
+//! from vetKD and only cached on the heap.
+//!
+//! Values are Candid-encoded. New fields must be `Option` (or have a serde
+//! default) so records written by older versions still decode.
+//!
+//! Every document has an entry in a separate index holding everything but its
+//! ciphertexts, so listings and searches don't decode tokens. The document
+//! functions below keep both maps in step.
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::jobs::Job;
+use crate::PerformanceMetrics;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Never reuse or reorder memory ids: they identify regions of stable memory
+const DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(1);
+const METRICS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const JOBS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const DECRYPTIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const DOCUMENT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+// 7 held issued score hashes for the former request_decryption
+const UPLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(8);
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StoredDocument {
+    pub tokens: Vec<Vec<u8>>, // encrypted tokens, big-endian (packed: several fingerprints each)
+    pub owner: Principal, // Who stored it
+    pub acl: Option<Vec<AccessGrant>>,
+    pub packing: Option<PackedLayout>, // Set for documents from encrypt_document_packed
+}
+
+/// Index entry for a document: owner, grants and sizes without the tokens
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DocumentMeta {
+    pub owner: Principal,
+    pub acl: Option<Vec<AccessGrant>>,
+    pub ciphertexts: u64,
+    pub packing: Option<PackedLayout>,
+}
+
+/// How a packed document's fingerprints are laid out (see packing.rs)
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PackedLayout {
+    pub tokens: u64, // Fingerprints across all ciphertexts
+    pub value_bits: u32,
+    pub guard_bits: u32,
+}
+
+/// What a non-owner may do with a document
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    Read,    // See it in list_documents, fetch rerandomized ciphertexts
+    Compare, // Use it in comparisons
+    Delete,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AccessGrant {
+    pub principal: Principal,
+    pub permission: Permission,
+}
+
+/// Ownership and grants, readable from a document or its index entry
+pub trait Access {
+    fn owner(&self) -> Principal;
+
+    fn grants(&self) -> &[AccessGrant];
+
+    /// Whether `principal` holds `permission` through the ACL
+    fn is_granted(&self, principal: Principal, permission: Permission) -> bool {
+        self.grants().iter().any(|g| g.principal == principal && g.permission == permission)
+    }
+
+    /// Owners may do anything; everyone else needs a grant
+    fn allows(&self, principal: Principal, permission: Permission) -> bool {
+        self.owner() == principal || self.is_granted(principal, permission)
+    }
+}
+
+impl Access for StoredDocument {
+    fn owner(&self) -> Principal {
+        self.owner
+    }
+
+    fn grants(&self) -> &[AccessGrant] {
+        self.acl.as_deref().unwrap_or_default()
+    }
+}
+
+impl Access for DocumentMeta {
+    fn owner(&self) -> Principal {
+        self.owner
+    }
+
+    fn grants(&self) -> &[AccessGrant] {
+        self.acl.as_deref().unwrap_or_default()
+    }
+}
+
+impl DocumentMeta {
+    /// Tokens in the document; packed documents hold several per ciphertext
+    pub fn token_count(&self) -> usize {
+        self.packing.map_or(self.ciphertexts as usize, |p| p.tokens as usize)
+    }
+}
+
+impl StoredDocument {
+    pub fn meta(&self) -> DocumentMeta {
+        DocumentMeta {
+            owner: self.owner,
+            acl: self.acl.clone(),
+            ciphertexts: self.tokens.len() as u64,
+            packing: self.packing,
+        }
+    }
+
+    /// Tokens in the document; packed documents hold several per ciphertext
+    pub fn token_count(&self) -> usize {
+        self.packing.map_or(self.tokens.len(), |p| p.tokens as usize)
+    }
+
+    /// Add a grant, returning false if it was already present
+    pub fn grant(&mut self, grant: AccessGrant) -> bool {
+        let acl = self.acl.get_or_insert_with(Vec::new);
+        if acl.contains(&grant) {
+            return false;
+        }
+        acl.push(grant);
+        true
+    }
+
+    /// Remove a grant, returning false if it wasn't present
+    pub fn revoke(&mut self, grant: &AccessGrant) -> bool {
+        let acl = self.acl.get_or_insert_with(Vec::new);
+        let before = acl.len();
+        acl.retain(|g| g != grant);
+        acl.len() != before
+    }
+}
+
+/// A document being built from several `append_tokens` calls. Kept apart from
+/// the documents map, so nothing can compare it until it is finalized. The
+/// tokens live in their own map, one entry per chunk, so appending a chunk
+/// doesn't rewrite the ones before it.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingUpload {
+    pub owner: Principal,
+    pub chunks: u32,     // Stored under (doc_id, 0..chunks)
+    pub tokens: u64,     // Across all chunks
+    pub bytes: u64,      // Ciphertext bytes across all chunks
+    pub updated_at: u64, // Expiry runs from the last chunk
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    doc_id: String,
+    index: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct UploadChunk {
+    tokens: Vec<Vec<u8>>, // encrypted tokens, big-endian
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct StableConfig {
+    pub owner: Option<Principal>,
+    pub key_bits: Option<u32>, // Set once initialize_paillier succeeds
+    pub scheme: Option<HeScheme>, // None for deployments from before ElGamal (Paillier)
+    pub key_derivation: Option<KeyDerivation>,
+    pub next_job_id: Option<u64>,
+    pub threshold_key: Option<ThresholdKeyConfig>, // Once set, documents are encrypted under it
+    pub next_decryption_id: Option<u64>,
+    pub token_cost: Option<u64>, // Instructions per encryption, measured by initialize_paillier
+    pub comparison_cost: Option<u64>, // Instructions per blinded zero test, likewise
+}
+
+/// Public parameters of a threshold decryption key (see threshold.rs). The
+/// shares themselves are held by the parties and never reach the canister.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ThresholdKeyConfig {
+    pub n: Vec<u8>,
+    pub threshold: u32,
+    pub parties: Vec<Principal>, // parties[i - 1] holds share i
+    pub v: Vec<u8>,
+    pub verification_keys: Vec<Vec<u8>>, // One per party, same order
+}
+
+/// A comparison waiting for (or released by) threshold decryption
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DecryptionRequest {
+    pub requester: Principal,
+    pub ciphertexts: Vec<Vec<u8>>, // Shuffled blinded differences, under the threshold key
+    pub created_at: u64,
+    pub partials: Vec<PartialShare>, // Verified partial decryptions, one per party
+    pub plaintext: Option<Vec<u8>>, // How many decrypt to zero, set once `threshold` parties are in
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PartialShare {
+    pub index: u32,
+    pub values: Vec<Vec<u8>>, // One per ciphertext, same order
+}
+
+/// Homomorphic scheme the canister was initialized with (see homomorphic.rs)
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum HeScheme {
+    #[default]
+    Paillier,
+    ElGamal, // Exponential ElGamal on secp256k1
+}
+
+/// Where the key seed comes from
+#[derive(CandidType, Deserialize, Clone)]
+pub enum KeyDerivation {
+    /// Re-derived from vetKD on demand; nothing secret is stored
+    VetKd,
+    /// Subnet without vetKD (testing only): the random seed has to be kept
+    Fallback { seed: Vec<u8> },
+}
+
+macro_rules! candid_storable {
+    ($t:ty) => {
+        impl Storable for $t {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).expect(concat!("failed to encode ", stringify!($t))))
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                Decode!(bytes.as_ref(), Self).expect(concat!("failed to decode ", stringify!($t)))
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    };
+}
+
+candid_storable!(StoredDocument);
+candid_storable!(DocumentMeta);
+candid_storable!(StableConfig);
+candid_storable!(PerformanceMetrics);
+candid_storable!(Job);
+candid_storable!(PendingUpload);
+candid_storable!(ChunkKey);
+candid_storable!(UploadChunk);
+candid_storable!(DecryptionRequest);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static DOCUMENTS: RefCell<StableBTreeMap<String, StoredDocument, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(DOCUMENTS_MEMORY_ID)))
+    );
+
+    static DOCUMENT_INDEX: RefCell<StableBTreeMap<String, DocumentMeta, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(DOCUMENT_INDEX_MEMORY_ID)))
+    );
+
+    static CONFIG: RefCell<StableCell<StableConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CONFIG_MEMORY_ID)),
+            StableConfig::default(),
+        ).expect("failed to initialize config cell")
+    );
+
+    static METRICS: RefCell<StableCell<PerformanceMetrics, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(METRICS_MEMORY_ID)),
+            PerformanceMetrics::default(),
+        ).expect("failed to initialize metrics cell")
+    );
+
+    static JOBS: RefCell<StableBTreeMap<u64, Job, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(JOBS_MEMORY_ID)))
+    );
+
+    static UPLOADS: RefCell<StableBTreeMap<String, PendingUpload, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(UPLOADS_MEMORY_ID)))
+    );
+
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<ChunkKey, UploadChunk, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(UPLOAD_CHUNKS_MEMORY_ID)))
+    );
+
+    static DECRYPTIONS: RefCell<StableBTreeMap<u64, DecryptionRequest, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(DECRYPTIONS_MEMORY_ID)))
+    );
+}
+
+// ===== DOCUMENTS =====
+
+pub fn get_document(doc_id: &str) -> Option<StoredDocument> {
+    DOCUMENTS.with(|docs| docs.borrow().get(&doc_id.to_string()))
+}
+
+/// Insert or replace a document, returning the previous version
+pub fn insert_document(doc_id: String, doc: StoredDocument) -> Option<StoredDocument> {
+    DOCUMENT_INDEX.with(|index| index.borrow_mut().insert(doc_id.clone(), doc.meta()));
+    DOCUMENTS.with(|docs| docs.borrow_mut().insert(doc_id, doc))
+}
+
+pub fn remove_document(doc_id: &str) -> Option<StoredDocument> {
+    DOCUMENT_INDEX.with(|index| index.borrow_mut().remove(&doc_id.to_string()));
+    DOCUMENTS.with(|docs| docs.borrow_mut().remove(&doc_id.to_string()))
+}
+
+pub fn document_count() -> usize {
+    DOCUMENTS.with(|docs| docs.borrow().len() as usize)
+}
+
+/// Visit every document's index entry in doc_id order
+pub fn for_each_document_meta(mut f: impl FnMut(&str, &DocumentMeta)) {
+    DOCUMENT_INDEX.with(|index| {
+        for (doc_id, meta) in index.borrow().iter() {
+            f(&doc_id, &meta);
+        }
+    });
+}
+
+/// Remove all documents, returning how many were removed
+pub fn clear_documents() -> usize {
+    DOCUMENT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let ids: Vec<String> = index.iter().map(|(doc_id, _)| doc_id).collect();
+        for doc_id in &ids {
+            index.remove(doc_id);
+        }
+    });
+    DOCUMENTS.with(|docs| {
+        let mut docs = docs.borrow_mut();
+        let ids: Vec<String> = docs.iter().map(|(doc_id, _)| doc_id).collect();
+        for doc_id in &ids {
+            docs.remove(doc_id);
+        }
+        ids.len()
+    })
+}
+
+// ===== JOBS =====
+
+pub fn get_job(job_id: u64) -> Option<Job> {
+    JOBS.with(|jobs| jobs.borrow().get(&job_id))
+}
+
+pub fn insert_job(job_id: u64, job: Job) {
+    JOBS.with(|jobs| jobs.borrow_mut().insert(job_id, job));
+}
+
+pub fn remove_job(job_id: u64) -> Option<Job> {
+    JOBS.with(|jobs| jobs.borrow_mut().remove(&job_id))
+}
+
+pub fn job_count() -> usize {
+    JOBS.with(|jobs| jobs.borrow().len() as usize)
+}
+
+/// Allocate a job id; ids are never reused, even after jobs are pruned
+pub fn allocate_job_id() -> u64 {
+    let mut job_id = 1;
+    update_config(|config| {
+        job_id = config.next_job_id.unwrap_or(1);
+        config.next_job_id = Some(job_id + 1);
+    });
+    job_id
+}
+
+/// Visit every job, oldest first
+pub fn for_each_job(mut f: impl FnMut(u64, &Job)) {
+    JOBS.with(|jobs| {
+        for (job_id, job) in jobs.borrow().iter() {
+            f(job_id, &job);
+        }
+    });
+}
+
+// ===== UPLOADS =====
+
+pub fn get_upload(doc_id: &str) -> Option<PendingUpload> {
+    UPLOADS.with(|uploads| uploads.borrow().get(&doc_id.to_string()))
+}
+
+/// Start an empty upload, discarding any earlier one of the same doc_id
+pub fn begin_upload(doc_id: String, owner: Principal, now: u64) -> PendingUpload {
+    remove_upload(&doc_id);
+    let upload = PendingUpload { owner, chunks: 0, tokens: 0, bytes: 0, updated_at: now };
+    UPLOADS.with(|uploads| uploads.borrow_mut().insert(doc_id, upload.clone()));
+    upload
+}
+
+/// Store the next chunk of an upload, returning the updated upload
+pub fn append_upload_chunk(doc_id: &str, tokens: Vec<Vec<u8>>, now: u64) -> Option<PendingUpload> {
+    let mut upload = get_upload(doc_id)?;
+    let key = ChunkKey { doc_id: doc_id.to_string(), index: upload.chunks };
+
+    upload.chunks += 1;
+    upload.tokens += tokens.len() as u64;
+    upload.bytes += tokens.iter().map(|t| t.len() as u64).sum::<u64>();
+    upload.updated_at = now;
+
+    UPLOAD_CHUNKS.with(|chunks| chunks.borrow_mut().insert(key, UploadChunk { tokens }));
+    UPLOADS.with(|uploads| uploads.borrow_mut().insert(doc_id.to_string(), upload.clone()));
+    Some(upload)
+}
+
+/// Remove an upload and return its tokens in the order they were appended
+pub fn take_upload(doc_id: &str) -> Option<(PendingUpload, Vec<Vec<u8>>)> {
+    let upload = get_upload(doc_id)?;
+    let mut tokens = Vec::with_capacity(upload.tokens as usize);
+    UPLOAD_CHUNKS.with(|chunks| {
+        for (_, chunk) in chunks.borrow().range(chunk_range(doc_id)) {
+            tokens.extend(chunk.tokens);
+        }
+    });
+    remove_upload(doc_id);
+    Some((upload, tokens))
+}
+
+/// Remove an upload and its chunks
+pub fn remove_upload(doc_id: &str) -> Option<PendingUpload> {
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let keys: Vec<ChunkKey> = chunks.range(chunk_range(doc_id)).map(|(key, _)| key).collect();
+        for key in &keys {
+            chunks.remove(key);
+        }
+    });
+    UPLOADS.with(|uploads| uploads.borrow_mut().remove(&doc_id.to_string()))
+}
+
+pub fn upload_count() -> usize {
+    UPLOADS.with(|uploads| uploads.borrow().len() as usize)
+}
+
+/// Ciphertext bytes held by uploads in progress (at most MAX_PENDING_UPLOADS
+/// headers are read, never the chunks)
+pub fn pending_upload_bytes() -> u64 {
+    UPLOADS.with(|uploads| uploads.borrow().iter().map(|(_, upload)| upload.bytes).sum())
+}
+
+/// Remove uploads last touched before `cutoff`, returning how many were removed
+pub fn remove_uploads_before(cutoff: u64) -> usize {
+    let expired: Vec<String> = UPLOADS.with(|uploads| {
+        uploads.borrow().iter()
+            .filter(|(_, upload)| upload.updated_at < cutoff)
+            .map(|(doc_id, _)| doc_id)
+            .collect()
+    });
+    for doc_id in &expired {
+        remove_upload(doc_id);
+    }
+    expired.len()
+}
+
+fn chunk_range(doc_id: &str) -> std::ops::RangeInclusive<ChunkKey> {
+    ChunkKey { doc_id: doc_id.to_string(), index: 0 }..=ChunkKey { doc_id: doc_id.to_string(), index: u32::MAX }
+}
+
+// ===== DECRYPTIONS =====
+
+pub fn get_decryption(request_id: u64) -> Option<DecryptionRequest> {
+    DECRYPTIONS.with(|requests| requests.borrow().get(&request_id))
+}
+
+pub fn insert_decryption(request_id: u64, request: DecryptionRequest) {
+    DECRYPTIONS.with(|requests| requests.borrow_mut().insert(request_id, request));
+}
+
+pub fn decryption_count() -> usize {
+    DECRYPTIONS.with(|requests| requests.borrow().len() as usize)
+}
+
+/// Remove requests created before `cutoff`, returning how many were removed
+pub fn remove_decryptions_before(cutoff: u64) -> usize {
+    DECRYPTIONS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        let expired: Vec<u64> = requests.iter()
+            .filter(|(_, request)| request.created_at < cutoff)
+            .map(|(request_id, _)| request_id)
+            .collect();
+        for request_id in &expired {
+            requests.remove(request_id);
+        }
+        expired.len()
+    })
+}
+
+/// Allocate a decryption request id; never reused
+pub fn allocate_decryption_id() -> u64 {
+    let mut request_id = 1;
+    update_config(|config| {
+        request_id = config.next_decryption_id.unwrap_or(1);
+        config.next_decryption_id = Some(request_id + 1);
+    });
+    request_id
+}
+
+// ===== CONFIG =====
+
+pub fn config() -> StableConfig {
+    CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn update_config(f: impl FnOnce(&mut StableConfig)) {
+    CONFIG.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut config = cell.get().clone();
+        f(&mut config);
+        cell.set(config).expect("failed to write config cell");
+    });
+}
+
+// ===== METRICS =====
+
+pub fn metrics() -> PerformanceMetrics {
+    METRICS.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn update_metrics(f: impl FnOnce(&mut PerformanceMetrics)) {
+    METRICS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut metrics = cell.get().clone();
+        f(&mut metrics);
+        cell.set(metrics).expect("failed to write metrics cell");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexed() -> Vec<(String, u64)> {
+        let mut entries = Vec::new();
+        for_each_document_meta(|doc_id, meta| entries.push((doc_id.to_string(), meta.ciphertexts)));
+        entries
+    }
+
+    #[test]
+    fn index_follows_documents() {
+        let owner = Principal::anonymous();
+        let doc = |tokens: usize| StoredDocument { tokens: vec![vec![1]; tokens], owner, acl: None, packing: None };
+
+        insert_document("a".to_string(), doc(3));
+        insert_document("b".to_string(), doc(2));
+        insert_document("a".to_string(), doc(5));
+        assert_eq!(indexed(), vec![("a".to_string(), 5), ("b".to_string(), 2)]);
+
+        remove_document("a");
+        assert_eq!(indexed(), vec![("b".to_string(), 2)]);
+
+        assert_eq!(clear_documents(), 1);
+        assert!(indexed().is_empty());
+    }
+
+    fn stored_chunks() -> u64 {
+        UPLOAD_CHUNKS.with(|chunks| chunks.borrow().len())
+    }
+
+    #[test]
+    fn chunks_come_back_in_order() {
+        let owner = Principal::anonymous();
+        begin_upload("a".to_string(), owner, 1);
+        begin_upload("b".to_string(), owner, 1);
+        append_upload_chunk("b", vec![vec![9]], 2).unwrap();
+
+        let mut expected = Vec::new();
+        for i in 0..12u8 {
+            let chunk = vec![vec![i, i]; i as usize % 3 + 1];
+            expected.extend(chunk.clone());
+            append_upload_chunk("a", chunk, 10 + i as u64).unwrap();
+        }
+        assert!(append_upload_chunk("c", vec![vec![1]], 3).is_none());
+
+        let upload = get_upload("a").unwrap();
+        assert_eq!((upload.chunks, upload.tokens, upload.bytes), (12, 24, 48));
+        assert_eq!(upload.updated_at, 21);
+        assert_eq!(pending_upload_bytes(), 49);
+
+        // Finishing hands back every token and frees the chunks
+        let (upload, tokens) = take_upload("a").unwrap();
+        assert_eq!(upload.tokens, 24);
+        assert_eq!(tokens, expected);
+        assert!(get_upload("a").is_none());
+        assert!(take_upload("a").is_none());
+        assert_eq!(stored_chunks(), 1);
+        assert_eq!(pending_upload_bytes(), 1);
+
+        // The other upload is untouched
+        assert_eq!(take_upload("b").unwrap().1, vec![vec![9]]);
+    }
+
+    #[test]
+    fn abort_restart_and_expiry_free_the_chunks() {
+        let owner = Principal::anonymous();
+        for doc_id in ["a", "b", "c"] {
+            begin_upload(doc_id.to_string(), owner, 1);
+            append_upload_chunk(doc_id, vec![vec![1, 2]; 2], 5).unwrap();
+            append_upload_chunk(doc_id, vec![vec![3]], 5).unwrap();
+        }
+        assert_eq!(stored_chunks(), 6);
+
+        // Abort
+        assert_eq!(remove_upload("a").unwrap().tokens, 3);
+        assert!(remove_upload("a").is_none());
+        assert_eq!(stored_chunks(), 4);
+
+        // Restart starts from nothing
+        let restarted = begin_upload("b".to_string(), owner, 7);
+        assert_eq!((restarted.chunks, restarted.tokens), (0, 0));
+        assert_eq!(stored_chunks(), 2);
+        assert!(take_upload("b").unwrap().1.is_empty());
+
+        // Expiry
+        begin_upload("d".to_string(), owner, 20);
+        assert_eq!(remove_uploads_before(10), 1);
+        assert!(get_upload("c").is_none());
+        assert_eq!(stored_chunks(), 0);
+        assert_eq!(upload_count(), 1);
+        assert_eq!(pending_upload_bytes(), 0);
+    }
+
+    #[test]
+    fn acl_decides_access() {
+        let owner = Principal::from_slice(&[1]);
+        let reader = Principal::from_slice(&[2]);
+        let stranger = Principal::from_slice(&[3]);
+        let mut doc = StoredDocument { tokens: vec![vec![1]], owner, acl: None, packing: None };
+
+        for permission in [Permission::Read, Permission::Compare, Permission::Delete] {
+            assert!(doc.allows(owner, permission));
+            assert!(!doc.allows(reader, permission));
+        }
+
+        let read = AccessGrant { principal: reader, permission: Permission::Read };
+        assert!(doc.grant(read.clone()));
+        assert!(!doc.grant(read.clone()));
+        assert_eq!(doc.grants().len(), 1);
+
+        // A grant covers exactly one permission for one principal
+        assert!(doc.allows(reader, Permission::Read));
+        assert!(!doc.allows(reader, Permission::Compare));
+        assert!(!doc.allows(reader, Permission::Delete));
+        assert!(!doc.allows(stranger, Permission::Read));
+
+        // The index answers the same as the document
+        let meta = doc.meta();
+        assert_eq!(meta.owner(), owner);
+        assert!(meta.allows(reader, Permission::Read));
+        assert!(!meta.allows(reader, Permission::Compare));
+
+        assert!(doc.revoke(&read));
+        assert!(!doc.revoke(&read));
+        assert!(!doc.allows(reader, Permission::Read));
+        assert!(doc.allows(owner, Permission::Read));
+    }
+}
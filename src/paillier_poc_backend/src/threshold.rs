@@ -0,0 +1,450 @@
+//! Threshold Paillier decryption (Shoup, as generalized by Damgård–Jurik, with
+//! s = 1): the decryption exponent is Shamir-shared among `parties` holders and
+//! any `threshold` of them can decrypt together, while fewer learn nothing.
+//!
+//! A trusted dealer runs `deal` natively, publishes the `ThresholdKey` and
+//! hands each holder their `KeyShare` privately. Holders compute partial
+//! decryptions with a proof of correctness; anyone can check the proofs and
+//! `combine` enough of the verified `DecryptionShare`s into the plaintext.
+//!
+//! The canister evaluates on ciphertexts under the threshold key through
+//! `AdditiveHomomorphic` like under its own key, but holds no share, so its
+//! `decrypt` always fails.
+//!
+//! With n = pq for safe primes p = 2p' + 1, q = 2q' + 1 and m = p'q', the
+//! shared secret is d with d = 0 mod m and d = 1 mod n. For a qualified set S
+//! and Delta = parties!, prod c_i^(2 mu_i) = c^(4 Delta^2 d), whose L-value is
+//! 4 Delta^2 * plaintext mod n.
+
+use num_bigint::{BigInt, BigUint, RandBigInt, Sign};
+use num_traits::{One, Signed, Zero};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::homomorphic::AdditiveHomomorphic;
+use crate::primes;
+use crate::proofs::{put, widths};
+use crate::simple_paillier::{PublicKey, MIN_KEY_BITS};
+
+// Challenge size of the partial decryption proofs
+const CHALLENGE_BITS: u64 = 128;
+const CHALLENGE_BYTES: usize = (CHALLENGE_BITS / 8) as usize;
+
+// Extra nonce bits over |Delta * s_i| + |e|, so z hides the share statistically
+const HIDING_BITS: u64 = 128;
+
+// Domain separator for the Fiat–Shamir hash; bump if the transcript changes
+const PARTIAL_DOMAIN: &[u8] = b"paillier-threshold-partial-v1";
+
+/// Public parameters of a threshold key
+#[derive(Clone, Debug)]
+pub struct ThresholdKey {
+    pub pk: PublicKey,
+    pub threshold: usize,
+    pub v: BigUint, // Random square in Z*_{n^2}
+    pub verification_keys: Vec<BigUint>, // v^(Delta * s_i), one per holder
+}
+
+/// Secret share of holder `index` (1-based)
+#[derive(Clone)]
+pub struct KeyShare {
+    pub index: usize,
+    pub secret: BigUint, // f(index) mod n*m
+}
+
+/// Holder `index`'s contribution c^(2 Delta s_i), as `combine` takes it once
+/// its proof has been checked
+#[derive(Clone, Debug)]
+pub struct DecryptionShare {
+    pub index: usize,
+    pub value: BigUint,
+}
+
+/// A share as submitted, with a proof that it used the same secret as the
+/// holder's verification key
+#[derive(Clone, Debug)]
+pub struct PartialDecryption {
+    pub share: DecryptionShare,
+    pub proof: PartialProof,
+}
+
+/// Equality of discrete logs: log_{c^4}(value^2) = log_v(v_i) = Delta * s_i
+#[derive(Clone, Debug)]
+pub struct PartialProof {
+    pub a: BigUint, // (c^4)^r
+    pub b: BigUint, // v^r
+    pub z: BigUint, // r + e * Delta * s_i, over the integers
+}
+
+impl ThresholdKey {
+    pub fn new(n: BigUint, threshold: usize, v: BigUint, verification_keys: Vec<BigUint>) -> Result<Self, String> {
+        if n.bits() < MIN_KEY_BITS as u64 {
+            return Err(format!("Modulus must have at least {} bits", MIN_KEY_BITS));
+        }
+        if threshold == 0 || threshold > verification_keys.len() {
+            return Err(format!("Threshold must be between 1 and {} (the number of holders)",
+                verification_keys.len()));
+        }
+
+        let pk = PublicKey::new(n);
+        if !pk.is_valid_ciphertext(&v) || verification_keys.iter().any(|vk| !pk.is_valid_ciphertext(vk)) {
+            return Err("Verification values must lie in Z*_{n^2}".to_string());
+        }
+        Ok(ThresholdKey { pk, threshold, v, verification_keys })
+    }
+
+    pub fn parties(&self) -> usize {
+        self.verification_keys.len()
+    }
+
+    /// Delta = parties!
+    pub fn delta(&self) -> BigUint {
+        (1..=self.parties()).fold(BigUint::one(), |acc, i| acc * i)
+    }
+}
+
+/// Dealer side: generate a key with safe primes and split it into `parties`
+/// shares, any `threshold` of which can decrypt. Slow: safe-prime search.
+pub fn deal<R: RngCore + CryptoRng>(
+    bits: usize,
+    threshold: usize,
+    parties: usize,
+    rng: &mut R,
+) -> Result<(ThresholdKey, Vec<KeyShare>), String> {
+    if bits < MIN_KEY_BITS || !bits.is_multiple_of(2) {
+        return Err(format!("Invalid key size: {} (must be even and at least {})", bits, MIN_KEY_BITS));
+    }
+    if threshold == 0 || threshold > parties {
+        return Err(format!("Threshold must be between 1 and {}", parties));
+    }
+
+    let (p, q) = loop {
+        let p = primes::gen_safe_prime(bits / 2, rng);
+        let q = primes::gen_safe_prime(bits / 2, rng);
+        if p != q {
+            break (p, q);
+        }
+    };
+
+    let n = &p * &q;
+    let m: BigUint = (&p >> 1usize) * (&q >> 1usize);
+    let nm = &n * &m;
+
+    // d = 0 mod m, d = 1 mod n
+    let d = &m * m.modinv(&n).ok_or("m is not invertible mod n")?;
+
+    // f(X) = d + a_1 X + ... + a_{t-1} X^{t-1} mod nm
+    let coefficients: Vec<BigUint> = std::iter::once(d)
+        .chain((1..threshold).map(|_| rng.gen_biguint_below(&nm)))
+        .collect();
+    let shares: Vec<KeyShare> = (1..=parties)
+        .map(|index| {
+            let x = BigUint::from(index);
+            let secret = coefficients.iter()
+                .rev()
+                .fold(BigUint::zero(), |acc, a| (acc * &x + a) % &nm);
+            KeyShare { index, secret }
+        })
+        .collect();
+
+    let pk = PublicKey::new(n);
+    let r = rng.gen_biguint_range(&BigUint::one(), &pk.n_squared);
+    let v = r.modpow(&BigUint::from(2u32), &pk.n_squared);
+
+    let delta = (1..=parties).fold(BigUint::one(), |acc, i| acc * i);
+    let verification_keys = shares.iter()
+        .map(|share| v.modpow(&(&delta * &share.secret), &pk.n_squared))
+        .collect();
+
+    Ok((ThresholdKey { pk, threshold, v, verification_keys }, shares))
+}
+
+/// Holder side: partial decryption of `c` with a proof of correctness
+pub fn partial_decrypt<R: RngCore + CryptoRng>(
+    key: &ThresholdKey,
+    share: &KeyShare,
+    c: &BigUint,
+    rng: &mut R,
+) -> Result<PartialDecryption, String> {
+    let pk = &key.pk;
+    if !pk.is_valid_ciphertext(c) {
+        return Err("Ciphertext is not in Z*_{n^2}".to_string());
+    }
+    let vk = share.index.checked_sub(1)
+        .and_then(|i| key.verification_keys.get(i))
+        .ok_or_else(|| format!("No holder {}", share.index))?;
+
+    let exponent = key.delta() * &share.secret;
+    let value = c.modpow(&(&exponent * 2u32), &pk.n_squared);
+
+    let c4 = c.modpow(&BigUint::from(4u32), &pk.n_squared);
+    // s_i < n^2, so Delta * n^2 bounds the exponent without depending on it
+    let r = rng.gen_biguint(pk.n_squared.bits() + key.delta().bits() + CHALLENGE_BITS + HIDING_BITS);
+    let a = c4.modpow(&r, &pk.n_squared);
+    let b = key.v.modpow(&r, &pk.n_squared);
+
+    let value_squared = (&value * &value) % &pk.n_squared;
+    let e = partial_challenge(key, c, &value_squared, vk, &a, &b);
+    let z = r + e * exponent;
+
+    Ok(PartialDecryption {
+        share: DecryptionShare { index: share.index, value },
+        proof: PartialProof { a, b, z },
+    })
+}
+
+/// Anyone: check a partial decryption of `c` against the holder's verification key
+pub fn verify_partial(key: &ThresholdKey, c: &BigUint, partial: &PartialDecryption) -> Result<(), String> {
+    let pk = &key.pk;
+    let share = &partial.share;
+    let vk = share.index.checked_sub(1)
+        .and_then(|i| key.verification_keys.get(i))
+        .ok_or_else(|| format!("No holder {}", share.index))?;
+
+    let proof = &partial.proof;
+    if !pk.is_valid_ciphertext(c) || !pk.is_valid_ciphertext(&share.value)
+        || !pk.is_valid_ciphertext(&proof.a) || !pk.is_valid_ciphertext(&proof.b)
+    {
+        return Err("Values must lie in Z*_{n^2}".to_string());
+    }
+
+    let c4 = c.modpow(&BigUint::from(4u32), &pk.n_squared);
+    let value_squared = (&share.value * &share.value) % &pk.n_squared;
+    let e = partial_challenge(key, c, &value_squared, vk, &proof.a, &proof.b);
+
+    // (c^4)^z = a * (value^2)^e and v^z = b * v_i^e
+    let lhs1 = c4.modpow(&proof.z, &pk.n_squared);
+    let rhs1 = (&proof.a * value_squared.modpow(&e, &pk.n_squared)) % &pk.n_squared;
+    let lhs2 = key.v.modpow(&proof.z, &pk.n_squared);
+    let rhs2 = (&proof.b * vk.modpow(&e, &pk.n_squared)) % &pk.n_squared;
+    if lhs1 != rhs1 || lhs2 != rhs2 {
+        return Err(format!("Partial decryption of holder {} does not verify", share.index));
+    }
+    Ok(())
+}
+
+/// Combine `threshold` shares from distinct holders into the plaintext. Only
+/// pass shares whose `PartialDecryption` passed `verify_partial`.
+pub fn combine(key: &ThresholdKey, shares: &[DecryptionShare]) -> Result<BigUint, String> {
+    let pk = &key.pk;
+
+    let mut chosen: Vec<&DecryptionShare> = Vec::with_capacity(key.threshold);
+    for share in shares {
+        if chosen.len() == key.threshold {
+            break;
+        }
+        if !chosen.iter().any(|s| s.index == share.index) {
+            chosen.push(share);
+        }
+    }
+    if chosen.len() < key.threshold {
+        return Err(format!("Need {} partial decryptions from distinct holders, got {}",
+            key.threshold, chosen.len()));
+    }
+
+    let delta = BigInt::from_biguint(Sign::Plus, key.delta());
+    let indices: Vec<BigInt> = chosen.iter().map(|p| BigInt::from(p.index)).collect();
+
+    // prod c_i^(2 mu_i), mu_i = Delta * prod_{j != i} j / (j - i) is an integer
+    let mut combined = BigUint::one();
+    for (share, i) in chosen.iter().zip(&indices) {
+        let (numerator, denominator) = indices.iter()
+            .filter(|j| *j != i)
+            .fold((delta.clone(), BigInt::one()), |(num, den), j| (num * j, den * (j - i)));
+        let mu = numerator / denominator;
+
+        let base = if mu.is_negative() {
+            share.value.modinv(&pk.n_squared).ok_or("Partial decryption is not invertible")?
+        } else {
+            share.value.clone()
+        };
+        let exponent = mu.magnitude() * 2u32;
+        combined = (combined * base.modpow(&exponent, &pk.n_squared)) % &pk.n_squared;
+    }
+
+    // L(combined) = 4 Delta^2 * plaintext mod n
+    let l = (combined - 1u32) / &pk.n;
+    let scale = (key.delta().pow(2) * 4u32) % &pk.n;
+    let scale_inv = scale.modinv(&pk.n).ok_or("4 Delta^2 is not invertible mod n")?;
+    Ok((l * scale_inv) % &pk.n)
+}
+
+// ===== EVALUATION =====
+// The canister computes on ciphertexts under the threshold key like under its
+// own, but holds no share: `decrypt` always fails, and only `combine` over
+// `threshold` verified partial decryptions recovers a plaintext.
+
+impl AdditiveHomomorphic for ThresholdKey {
+    type Ciphertext = BigUint;
+
+    fn keygen(_seed: &[u8], _bits: usize) -> Result<Self, String> {
+        Err("Threshold keys are dealt (see `deal`), not derived from a seed".to_string())
+    }
+
+    fn encrypt_with_rng<R: RngCore + CryptoRng>(&self, m: &[u8], rng: &mut R) -> Result<BigUint, String> {
+        self.pk.encrypt_with_rng(m, rng)
+    }
+
+    // Draws on the randomizer pool (see pool.rs)
+    fn encrypt(&self, m: &[u8]) -> Result<BigUint, String> {
+        self.pk.encrypt(m)
+    }
+
+    fn add(&self, c1: &BigUint, c2: &BigUint) -> BigUint {
+        self.pk.add(c1, c2)
+    }
+
+    fn neg(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.pk.neg(c)
+    }
+
+    fn mul_plain(&self, c: &BigUint, k: &BigUint) -> BigUint {
+        self.pk.mul_plain(c, k)
+    }
+
+    fn rerandomize_with_rng<R: RngCore + CryptoRng>(&self, c: &BigUint, rng: &mut R) -> Result<BigUint, String> {
+        Ok(self.pk.rerandomize_with_rng(c, rng))
+    }
+
+    // Draws on the randomizer pool
+    fn rerandomize(&self, c: &BigUint) -> Result<BigUint, String> {
+        self.pk.rerandomize(c)
+    }
+
+    fn plaintext_modulus(&self) -> BigUint {
+        self.pk.n.clone()
+    }
+
+    fn decrypt(&self, _c: &BigUint) -> Result<BigUint, String> {
+        Err("Threshold ciphertexts are only decrypted by combining partial decryptions".to_string())
+    }
+
+    fn serialize(&self, c: &BigUint) -> Vec<u8> {
+        c.to_bytes_be()
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<BigUint, String> {
+        let c = BigUint::from_bytes_be(bytes);
+        if !self.pk.is_valid_ciphertext(&c) {
+            return Err("Ciphertext is not in Z*_{n^2}".to_string());
+        }
+        Ok(c)
+    }
+}
+
+impl PartialProof {
+    /// a and b (|n^2| bytes each) followed by z (big-endian, variable length)
+    pub fn to_bytes(&self, pk: &PublicKey) -> Vec<u8> {
+        let (_, n2_bytes) = widths(pk);
+        let mut out = Vec::with_capacity(2 * n2_bytes + (self.z.bits() as usize).div_ceil(8));
+        put(&mut out, &self.a, n2_bytes);
+        put(&mut out, &self.b, n2_bytes);
+        out.extend_from_slice(&self.z.to_bytes_be());
+        out
+    }
+
+    pub fn from_bytes(pk: &PublicKey, bytes: &[u8]) -> Result<Self, String> {
+        let (_, n2_bytes) = widths(pk);
+        if bytes.len() <= 2 * n2_bytes {
+            return Err(format!("Partial decryption proof must be longer than {} bytes", 2 * n2_bytes));
+        }
+        let (a, rest) = bytes.split_at(n2_bytes);
+        let (b, z) = rest.split_at(n2_bytes);
+        Ok(PartialProof {
+            a: BigUint::from_bytes_be(a),
+            b: BigUint::from_bytes_be(b),
+            z: BigUint::from_bytes_be(z),
+        })
+    }
+}
+
+// Challenge bound to the key, the ciphertext, the holder and their commitments
+fn partial_challenge(
+    key: &ThresholdKey,
+    c: &BigUint,
+    value_squared: &BigUint,
+    vk: &BigUint,
+    a: &BigUint,
+    b: &BigUint,
+) -> BigUint {
+    let (n_bytes, n2_bytes) = widths(&key.pk);
+    let mut transcript = Vec::with_capacity(n_bytes + 6 * n2_bytes);
+    put(&mut transcript, &key.pk.n, n_bytes);
+    for x in [&key.v, c, value_squared, vk, a, b] {
+        put(&mut transcript, x, n2_bytes);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(PARTIAL_DOMAIN);
+    hasher.update(&transcript);
+    BigUint::from_bytes_be(&hasher.finalize()[..CHALLENGE_BYTES])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn any_qualified_set_decrypts() {
+        let mut rng = ChaCha20Rng::seed_from_u64(5);
+        let (key, shares) = deal(MIN_KEY_BITS, 2, 3, &mut rng).unwrap();
+        let c = key.pk.encrypt_with_rng(&[42], &mut rng).unwrap();
+
+        let partials: Vec<PartialDecryption> = shares.iter()
+            .map(|share| partial_decrypt(&key, share, &c, &mut rng).unwrap())
+            .collect();
+        for partial in &partials {
+            assert!(verify_partial(&key, &c, partial).is_ok());
+        }
+
+        for pair in [[0, 1], [0, 2], [2, 1]] {
+            let chosen: Vec<DecryptionShare> = pair.iter().map(|&i| partials[i].share.clone()).collect();
+            assert_eq!(combine(&key, &chosen).unwrap(), BigUint::from(42u32));
+        }
+        assert!(combine(&key, &[partials[0].share.clone(), partials[0].share.clone()]).is_err());
+    }
+
+    #[test]
+    fn evaluation_decrypts_only_through_the_parties() {
+        let mut rng = ChaCha20Rng::seed_from_u64(7);
+        let (key, shares) = deal(MIN_KEY_BITS, 2, 2, &mut rng).unwrap();
+        let (a, b) = (key.encrypt_with_rng(&[9], &mut rng).unwrap(), key.encrypt_with_rng(&[4], &mut rng).unwrap());
+
+        // 3 * (9 - 4), rerandomized
+        let c = key.mul_plain(&key.sub(&a, &b).unwrap(), &BigUint::from(3u32));
+        let c = key.rerandomize_with_rng(&c, &mut rng).unwrap();
+        assert!(key.decrypt(&c).is_err());
+        assert!(key.is_zero(&c).is_err());
+
+        let partials: Vec<DecryptionShare> = shares.iter()
+            .map(|share| partial_decrypt(&key, share, &c, &mut rng).unwrap().share)
+            .collect();
+        assert_eq!(combine(&key, &partials).unwrap(), BigUint::from(15u32));
+
+        assert!(key.deserialize(&[]).is_err());
+        assert!(key.deserialize(&key.pk.n.to_bytes_be()).is_err());
+        assert_eq!(key.deserialize(&key.serialize(&c)).unwrap(), c);
+    }
+
+    #[test]
+    fn tampered_partials_are_rejected() {
+        let mut rng = ChaCha20Rng::seed_from_u64(6);
+        let (key, shares) = deal(MIN_KEY_BITS, 2, 3, &mut rng).unwrap();
+        let c = key.pk.encrypt_with_rng(&[7], &mut rng).unwrap();
+        let partial = partial_decrypt(&key, &shares[0], &c, &mut rng).unwrap();
+
+        let mut wrong_value = partial.clone();
+        wrong_value.share.value = (&wrong_value.share.value * &c) % &key.pk.n_squared;
+        assert!(verify_partial(&key, &c, &wrong_value).is_err());
+
+        // Claiming another holder's index
+        let mut wrong_index = partial.clone();
+        wrong_index.share.index = 2;
+        assert!(verify_partial(&key, &c, &wrong_index).is_err());
+
+        let other = key.pk.encrypt_with_rng(&[7], &mut rng).unwrap();
+        assert!(verify_partial(&key, &other, &partial).is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use ic_cdk::api::management_canister::main::{
-    VetKdPublicKeyRequest, VetKdPublicKeyResponse,
+use crate::vetkd_utils::{
+    vetkd_context, vetkd_key_id, VetKdPublicKeyRequest, VetKdPublicKeyResponse,
 };
 use ic_cdk_macros::update;
 use candid::Principal;
@@ -13,8 +13,8 @@ pub async fn check_vetkd_support() -> Result<bool, String> {
     // Try to get public key to verify vetKD is available
     let request = VetKdPublicKeyRequest {
         canister_id: None,
-        derivation_path: vec![b"test".to_vec()],
-        key_id: vec![0; 32], // Default key ID
+        context: vetkd_context(),
+        key_id: vetkd_key_id(),
     };
     
     match ic_cdk::call::<_, (VetKdPublicKeyResponse,)>(
@@ -39,10 +39,8 @@ pub async fn check_vetkd_support() -> Result<bool, String> {
 pub async fn get_vetkd_info() -> Result<String, String> {
     match check_vetkd_support().await {
         Ok(true) => {
-            Ok(format!(
-                "vetKeys is available on this subnet. \
-                Key derivation and threshold operations are supported."
-            ))
+            Ok("vetKeys is available on this subnet. \
+                Key derivation and threshold operations are supported.".to_string())
         },
         Err(e) => {
             Ok(format!(
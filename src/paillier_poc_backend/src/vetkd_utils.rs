@@ -1,4 +1,5 @@
-use ic_cdk::api::{time, management_canister::main::*, caller};
+use ic_cdk::api::{time, caller};
+use bls12_381::{pairing, G1Affine, G1Projective, G2Affine, Scalar};
 use candid::{CandidType, Deserialize, Principal};
 use lru::LruCache;
 use std::cell::RefCell;
@@ -8,6 +9,121 @@ use serde::Serialize;
 const KEY_CACHE_TTL: u64 = 5 * 60 * 1_000_000_000; // 5 minutes in nanoseconds
 const CACHE_SIZE: usize = 100; // Max cached keys
 
+// Master key on the subnet: dfx_test_key locally, test_key_1 / key_1 on mainnet
+pub const VETKD_KEY_NAME: &str = "test_key_1";
+// Domain separator for everything this canister derives
+const VETKD_CONTEXT: &[u8] = b"paillier_poc_backend";
+// Fee for vetkd_derive_key with key_1; unused cycles are refunded
+const VETKD_DERIVE_CYCLES: u128 = 26_153_846_153;
+
+// ===== MANAGEMENT CANISTER TYPES =====
+// ic-cdk 0.12 predates the vetKD API; these mirror the management canister's candid.
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VetKdKeyId {
+    pub curve: VetKdCurve,
+    pub name: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VetKdPublicKeyRequest {
+    pub canister_id: Option<Principal>,
+    pub context: Vec<u8>,
+    pub key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VetKdPublicKeyResponse {
+    pub public_key: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VetKdDeriveKeyRequest {
+    pub input: Vec<u8>,
+    pub context: Vec<u8>,
+    pub transport_public_key: Vec<u8>, // Compressed G1 point
+    pub key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VetKdDeriveKeyResponse {
+    pub encrypted_key: Vec<u8>, // c1 (G1) || c2 (G2) || c3 (G1)
+}
+
+pub fn vetkd_key_id() -> VetKdKeyId {
+    VetKdKeyId { curve: VetKdCurve::Bls12381G2, name: VETKD_KEY_NAME.to_string() }
+}
+
+/// Context sent with every request, also used by `vetkd_public_key`
+pub fn vetkd_context() -> Vec<u8> {
+    VETKD_CONTEXT.to_vec()
+}
+
+// ===== TRANSPORT KEY =====
+// The subnet returns the vetKey encrypted to a one-off transport key:
+// c1 = g1^r, c2 = g2^r, c3 = k * tpk^r. The key k itself is deterministic, so
+// the keypair derived from it survives upgrades even though c1..c3 do not.
+
+struct TransportSecretKey {
+    secret: Scalar,
+}
+
+impl TransportSecretKey {
+    fn random() -> Result<Self, String> {
+        let mut wide = [0u8; 64];
+        crate::rng::fill_bytes(&mut wide)?;
+        Ok(TransportSecretKey { secret: Scalar::from_bytes_wide(&wide) })
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        G1Affine::from(G1Affine::generator() * self.secret).to_compressed().to_vec()
+    }
+
+    /// The compressed vetKey (a BLS signature in G1)
+    fn decrypt(&self, encrypted_key: &[u8]) -> Result<Vec<u8>, String> {
+        if encrypted_key.len() != 192 {
+            return Err(format!("Encrypted vetKey has {} bytes (expected 192)", encrypted_key.len()));
+        }
+        let c1 = g1_from_bytes(&encrypted_key[..48])?;
+        let c2 = g2_from_bytes(&encrypted_key[48..144])?;
+        let c3 = g1_from_bytes(&encrypted_key[144..])?;
+
+        // c1 and c2 must share the same r, otherwise c3 - c1^tsk is not the key
+        if pairing(&c1, &G2Affine::generator()) != pairing(&G1Affine::generator(), &c2) {
+            return Err("Malformed encrypted vetKey".to_string());
+        }
+
+        let key = G1Projective::from(c3) - c1 * self.secret;
+        Ok(G1Affine::from(key).to_compressed().to_vec())
+    }
+}
+
+fn g1_from_bytes(bytes: &[u8]) -> Result<G1Affine, String> {
+    let bytes: [u8; 48] = bytes.try_into().map_err(|_| "Bad G1 point length".to_string())?;
+    Option::from(G1Affine::from_compressed(&bytes)).ok_or_else(|| "Invalid G1 point".to_string())
+}
+
+fn g2_from_bytes(bytes: &[u8]) -> Result<G2Affine, String> {
+    let bytes: [u8; 96] = bytes.try_into().map_err(|_| "Bad G2 point length".to_string())?;
+    Option::from(G2Affine::from_compressed(&bytes)).ok_or_else(|| "Invalid G2 point".to_string())
+}
+
+// Length-prefixed path components, so ["ab", "c"] and ["a", "bc"] differ
+fn encode_input(derivation_path: &[Vec<u8>]) -> Vec<u8> {
+    let mut input = Vec::new();
+    for part in derivation_path {
+        input.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        input.extend_from_slice(part);
+    }
+    input
+}
+
 thread_local! {
     static KEY_CACHE: RefCell<LruCache<String, (u64, Vec<u8>)>> = 
         RefCell::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap()));
@@ -106,24 +222,44 @@ impl VetKeyManager {
         })
     }
     
-    async fn derive_from_vetkd(&self, doc_id: &str) -> Result<Vec<u8>, String> {
-        let start_time = time();
+    /// Derive the canister-wide seed for the Paillier keypair.
+    /// Not cached here: the keypair derived from it is cached on the heap.
+    pub async fn derive_paillier_seed(&self, key_bits: usize) -> Result<Vec<u8>, String> {
+        let derivation_path = vec![
+            b"paillier".to_vec(),
+            self.key_name.as_bytes().to_vec(),
+            (key_bits as u32).to_be_bytes().to_vec(),
+        ];
         
+        self.derive_from_path(derivation_path).await
+    }
+    
+    async fn derive_from_vetkd(&self, doc_id: &str) -> Result<Vec<u8>, String> {
         let derivation_path = vec![
             b"document".to_vec(),
             doc_id.as_bytes().to_vec(),
         ];
         
+        self.derive_from_path(derivation_path).await
+    }
+    
+    async fn derive_from_path(&self, derivation_path: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+        let start_time = time();
+        
+        crate::rng::ensure_seeded().await?;
+        let transport_key = TransportSecretKey::random()?;
         let request = VetKdDeriveKeyRequest {
-            key_id: vec![0; 32], // Default key ID
-            derivation_path,
-            encryption_public_key: None, // For future IBE support
+            input: encode_input(&derivation_path),
+            context: vetkd_context(),
+            transport_public_key: transport_key.public_key(),
+            key_id: vetkd_key_id(),
         };
         
-        let result = ic_cdk::call::<_, (VetKdDeriveKeyResponse,)>(
+        let result = ic_cdk::api::call::call_with_payment128::<_, (VetKdDeriveKeyResponse,)>(
             Principal::management_canister(),
             "vetkd_derive_key",
-            (request,)
+            (request,),
+            VETKD_DERIVE_CYCLES,
         ).await;
         
         match result {
@@ -131,7 +267,7 @@ impl VetKeyManager {
                 let duration = (time() - start_time) / 1_000_000; // Convert to ms
                 update_derivation_time_metrics(duration);
                 ic_cdk::println!("Key derivation took {}ms", duration);
-                Ok(response.derived_key)
+                transport_key.decrypt(&response.encrypted_key)
             }
             Err((code, msg)) => {
                 Err(format!("vetKD derivation failed: {:?} - {}", code, msg))
@@ -145,15 +281,15 @@ impl VetKeyManager {
         let mut hasher = Sha256::new();
         hasher.update(b"fallback:");
         hasher.update(doc_id.as_bytes());
-        hasher.update(&time().to_be_bytes());
-        hasher.update(&caller().as_slice());
+        hasher.update(time().to_be_bytes());
+        hasher.update(caller().as_slice());
         
         let hash = hasher.finalize();
         
         // Extend to 64 bytes for key material
         let mut key = hash.to_vec();
         let mut hasher2 = Sha256::new();
-        hasher2.update(&hash);
+        hasher2.update(hash);
         hasher2.update(b"extended");
         key.extend_from_slice(&hasher2.finalize());
         
@@ -163,7 +299,7 @@ impl VetKeyManager {
 
 // ===== METRICS TRACKING =====
 
-#[derive(Default, CandidType, Deserialize, Serialize)]
+#[derive(Default, Clone, CandidType, Deserialize, Serialize)]
 pub struct VetKeyMetrics {
     pub key_derivations: u64,
     pub cache_hits: u64,
@@ -227,7 +363,7 @@ pub struct CacheStats {
 
 // ===== SECURITY LOGGING =====
 
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(Clone, CandidType, Deserialize, Serialize)]
 pub struct SecurityEvent {
     pub timestamp: u64,
     pub event_type: SecurityEventType,
@@ -235,7 +371,7 @@ pub struct SecurityEvent {
     pub details: String,
 }
 
-#[derive(CandidType, Deserialize, Serialize)]
+#[derive(Clone, CandidType, Deserialize, Serialize)]
 pub enum SecurityEventType {
     KeyDerivation,
     CacheAccess,
@@ -245,7 +381,7 @@ pub enum SecurityEventType {
 }
 
 thread_local! {
-    static SECURITY_LOG: RefCell<Vec<SecurityEvent>> = RefCell::new(Vec::new());
+    static SECURITY_LOG: RefCell<Vec<SecurityEvent>> = const { RefCell::new(Vec::new()) };
 }
 
 pub fn log_security_event(event_type: SecurityEventType, details: String) {